@@ -5,25 +5,178 @@ import (
 	"strings"
 )
 
+// A backslash in a string escapes the character after it, even a quote.
 var tokenizer = *p.NewTokenizer(`\s+`, strings.Split(
-	`** + - * / [ ] . ( ) { } : , >= <= < > == != ! && || true false in null number identifier string`, " ",
+	`** // ?. + - * / % [ ] . ( ) { } : , ? >= <= < > == != ! && || true false in null number identifier string`, " ",
 ), map[string]string{
 	"number":     `[0-9]+(?:\.[0-9]+)?`,
 	"identifier": `[a-zA-Z_][a-zA-Z_0-9]*`,
-	"string":     `'[^']*'|"[^"]*"`,
+	"string":     `'[^'\\]*(?:\\[\s\S][^'\\]*)*'|"[^"\\]*(?:\\[\s\S][^"\\]*)*"`,
 	"true":       `true\b`,
 	"false":      `false\b`,
 	"in":         `in\b`,
 	"null":       `null\b`,
 })
 
+// Cache holds the trees of parsed expressions, so that an expression that is
+// evaluated repeatedly, for example in a $map, is only parsed once. The zero
+// value is ready to use.
+type Cache struct {
+	expressions map[string]p.IASTNode
+	templates   map[string]*TemplateCache
+
+	// frozen is whether trees parsed later are not kept, see Freeze
+	frozen bool
+}
+
+// TemplateCache holds the trees of the parsed ${..} expressions of a single
+// template, by their offset in the template.
+type TemplateCache struct {
+	length         int
+	interpolations map[interpolationKey]interpolation
+	frozen         bool
+}
+
+type interpolationKey struct {
+	offset     int
+	terminator string
+}
+
+type interpolation struct {
+	tree   p.IASTNode
+	length int // of the expression, including the terminator
+}
+
+// Parse parses and evaluates source in the given context
 func Parse(source string, context interface{}) (interface{}, error) {
+	var cache Cache
+	return cache.Parse(source, context, nil)
+}
+
+// ParseUntilTerminator parses and evaluates the expression in source starting
+// at offset and ending before terminator, returning the offset of its end
+func ParseUntilTerminator(source string, offset int, terminator string, context interface{}) (interface{}, int, error) {
+	t := TemplateCache{length: len(source)}
+	return t.ParseUntilTerminator(source, offset, terminator, context, nil)
+}
+
+// Freeze stops c from keeping the trees of expressions parsed from now on,
+// so that c is only read and can be shared by renders running at once
+func (c *Cache) Freeze() {
+	c.frozen = true
+	for _, t := range c.templates {
+		t.frozen = true
+	}
+}
+
+// Tree returns the tree of source, reusing it when source has been parsed
+// before
+func (c *Cache) Tree(source string) (p.IASTNode, error) {
+	if tree, ok := c.expressions[source]; ok {
+		return tree, nil
+	}
+	tree, err := parseExpression(source)
+	if err != nil || c.frozen {
+		return tree, err
+	}
+	if c.expressions == nil {
+		c.expressions = make(map[string]p.IASTNode)
+	}
+	c.expressions[source] = tree
+	return tree, nil
+}
+
+// Parse is like the package-level Parse, reusing the tree when source has
+// been parsed before. step, if not nil, is called before each node of the
+// expression is evaluated; an error from it stops the evaluation.
+func (c *Cache) Parse(source string, context interface{}, step func() error) (interface{}, error) {
+	tree, err := c.Tree(source)
+	if err != nil {
+		return nil, err
+	}
+	newInterpreter := NewInterpreter{step: step}
+	newInterpreter.AddContext(context.(map[string]interface{}))
+	return newInterpreter.Interpret(tree)
+}
+
+// Template returns the cache for the ${..} expressions of template. Looking
+// it up once per template avoids hashing the template for each expression.
+func (c *Cache) Template(template string) *TemplateCache {
+	t, ok := c.templates[template]
+	if !ok {
+		t = &TemplateCache{length: len(template), frozen: c.frozen}
+		if c.frozen {
+			return t
+		}
+		if c.templates == nil {
+			c.templates = make(map[string]*TemplateCache)
+		}
+		c.templates[template] = t
+	}
+	return t
+}
+
+// Tree returns the tree of the expression in source starting at offset and
+// ending before terminator, and the offset of its end, reusing the tree when
+// the expression has been parsed before. The source must be a suffix of the
+// template.
+func (t *TemplateCache) Tree(source string, offset int, terminator string) (p.IASTNode, int, error) {
+	// the expression is keyed by its offset in the template
+	key := interpolationKey{t.length - len(source) + offset, terminator}
+	entry, ok := t.interpolations[key]
+	if !ok {
+		tree, end, err := parseUntilTerminator(source, offset, terminator)
+		if err != nil {
+			return nil, 0, err
+		}
+		entry = interpolation{tree, end - offset}
+		if !t.frozen {
+			if t.interpolations == nil {
+				t.interpolations = make(map[interpolationKey]interpolation)
+			}
+			t.interpolations[key] = entry
+		}
+	}
+	return entry.tree, offset + entry.length, nil
+}
+
+// ParseUntilTerminator is like the package-level ParseUntilTerminator,
+// reusing the tree when the expression has been parsed before, and calling
+// step as Cache.Parse does. The source must be a suffix of the template.
+func (t *TemplateCache) ParseUntilTerminator(source string, offset int, terminator string, context interface{}, step func() error) (interface{}, int, error) {
+	tree, end, err := t.Tree(source, offset, terminator)
+	if err != nil {
+		return nil, 0, err
+	}
+	newInterpreter := NewInterpreter{step: step}
+	newInterpreter.AddContext(context.(map[string]interface{}))
+	result, err := newInterpreter.Interpret(tree)
+	if err != nil {
+		return nil, 0, err
+	}
+
+	return result, end, nil
+}
+
+// Tokenize returns the tokens of an expression, each with its kind, value and
+// start and end offsets, for debugging; expressions are not evaluated from
+// these.
+func Tokenize(expression string) ([]p.Token, error) {
+	return tokenizer.Tokenize(expression, 0)
+}
+
+func parseExpression(source string) (p.IASTNode, error) {
 	var parser p.Parser
-	var newInterpreter NewInterpreter
 	err := parser.NewParser(source, tokenizer, 0)
 	if err != nil {
 		return nil, err
 	}
+	if parser.CurrentToken.IsEmpty() {
+		return nil, p.SyntaxError{
+			Message: "empty expression",
+			Source:  source,
+		}
+	}
 	tree, err := parser.Parse(0)
 	if err != nil {
 		return nil, err
@@ -36,19 +189,24 @@ func Parse(source string, context interface{}) (interface{}, error) {
 			End:     parser.CurrentToken.End,
 		}
 	}
-	newInterpreter.AddContext(context.(map[string]interface{}))
-	return newInterpreter.Interpret(tree)
-
+	return tree, nil
 }
 
-func ParseUntilTerminator(source string, offset int, terminator string, context interface{}) (interface{}, int, error) {
+func parseUntilTerminator(source string, offset int, terminator string) (p.IASTNode, int, error) {
 	var parser p.Parser
-	var newInterpreter NewInterpreter
 
 	err := parser.NewParser(source, tokenizer, offset)
 	if err != nil {
 		return nil, 0, err
 	}
+	if parser.CurrentToken.Kind == terminator {
+		return nil, 0, p.SyntaxError{
+			Message: "empty expression",
+			Source:  source,
+			Start:   parser.CurrentToken.Start,
+			End:     parser.CurrentToken.End,
+		}
+	}
 	tree, err := parser.Parse(0)
 	if err != nil {
 		return nil, 0, err
@@ -61,11 +219,5 @@ func ParseUntilTerminator(source string, offset int, terminator string, context
 			Expected: []string{terminator},
 		}
 	}
-	newInterpreter.AddContext(context.(map[string]interface{}))
-	result, err := newInterpreter.Interpret(tree)
-	if err != nil {
-		return nil, 0, err
-	}
-
-	return result, parser.CurrentToken.End, nil
+	return tree, parser.CurrentToken.End, nil
 }
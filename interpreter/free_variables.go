@@ -0,0 +1,66 @@
+package interpreter
+
+import (
+	"sort"
+
+	p "github.com/taskcluster/json-e/interpreter/parser"
+)
+
+// ParseExpression parses expression into its tree, for analyzing it without
+// evaluating it.
+func ParseExpression(expression string) (p.IASTNode, error) {
+	return parseExpression(expression)
+}
+
+// FreeVariables returns the names of the context values expression refers
+// to, in sorted order, including the builtins it calls. The right side of `.`
+// and the keys of an object literal are not variables.
+func FreeVariables(expression string) ([]string, error) {
+	tree, err := parseExpression(expression)
+	if err != nil {
+		return nil, err
+	}
+	names := map[string]bool{}
+	freeVariables(tree, names)
+	result := make([]string, 0, len(names))
+	for name := range names {
+		result = append(result, name)
+	}
+	sort.Strings(result)
+	return result, nil
+}
+
+func freeVariables(node p.IASTNode, names map[string]bool) {
+	switch n := node.(type) {
+	case p.ContextValue:
+		names[n.Token.Value] = true
+	case p.BinOp:
+		freeVariables(n.Left, names)
+		if n.Token.Kind != "." && n.Token.Kind != "?." {
+			freeVariables(n.Right, names)
+		}
+	case p.UnaryOp:
+		freeVariables(n.Expr, names)
+	case p.TernaryOp:
+		freeVariables(n.Condition, names)
+		freeVariables(n.ThenExpr, names)
+		freeVariables(n.ElseExpr, names)
+	case p.FunctionCall:
+		freeVariables(n.Name, names)
+		for _, arg := range n.Args {
+			freeVariables(arg, names)
+		}
+	case p.List:
+		for _, item := range n.List {
+			freeVariables(item, names)
+		}
+	case p.ValueAccess:
+		freeVariables(n.Arr, names)
+		freeVariables(n.Left, names)
+		freeVariables(n.Right, names)
+	case p.Object:
+		for _, value := range n.Obj {
+			freeVariables(value, names)
+		}
+	}
+}
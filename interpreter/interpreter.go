@@ -11,13 +11,33 @@ import (
 
 type NewInterpreter struct {
 	context map[string]interface{}
+	step    func() error
 }
 
 func (i *NewInterpreter) AddContext(context map[string]interface{}) {
 	i.context = context
 }
 
+// skipped is the value of a link in an optional chain whose object was null,
+// so that the rest of the chain is skipped, as in `x?.a.b` for a null x
+type skipped struct{}
+
 func (i NewInterpreter) visit(node parser.IASTNode) (interface{}, error) {
+	val, err := i.visitLink(node)
+	if _, ok := val.(skipped); ok {
+		return nil, err
+	}
+	return val, err
+}
+
+// visitLink visits the object of a property access, index or call, which may
+// be skipped
+func (i NewInterpreter) visitLink(node parser.IASTNode) (interface{}, error) {
+	if i.step != nil {
+		if err := i.step(); err != nil {
+			return nil, err
+		}
+	}
 	var err error
 	nodeType := strings.Split(fmt.Sprintf("%T", node), ".")[1]
 	funcName := "Visit_" + nodeType
@@ -44,7 +64,7 @@ func (i NewInterpreter) Visit_ASTNode(node parser.ASTNode) (interface{}, error)
 	case "null":
 		return nil, nil
 	case "string":
-		return token.Value[1 : len(token.Value)-1], nil
+		return parser.ParseString(token.Value), nil
 	case "true":
 		return true, nil
 	case "false":
@@ -86,31 +106,56 @@ func (i NewInterpreter) Visit_UnaryOp(node parser.UnaryOp) (interface{}, error)
 	panic(fmt.Sprintf("unknown unary operator: '%s'", node.Token.Kind))
 }
 
+func (i NewInterpreter) Visit_TernaryOp(node parser.TernaryOp) (interface{}, error) {
+	condition, err := i.visit(node.Condition)
+	if err != nil {
+		return nil, err
+	}
+	if IsTruthy(condition) {
+		return i.visit(node.ThenExpr)
+	}
+	return i.visit(node.ElseExpr)
+}
+
 func (i NewInterpreter) Visit_BinOp(node parser.BinOp) (interface{}, error) {
 	var right interface{}
-	mathOperators := []string{"-", "*", "/", "**"}
+	mathOperators := []string{"-", "*", "/", "//", "%", "**"}
 	compareOperators := []string{"<=", ">=", "<", ">"}
 	tokenKind := node.Token.Kind
-	left, err := i.visit(node.Left)
+	var left interface{}
+	var err error
+	if tokenKind == "." || tokenKind == "?." {
+		left, err = i.visitLink(node.Left)
+	} else {
+		left, err = i.visit(node.Left)
+	}
 	if err != nil {
 		return nil, err
 	}
+	if _, ok := left.(skipped); ok {
+		return left, nil
+	}
 
 	switch tokenKind {
-	case "||":
-		if IsTruthy(left) {
-			return true, nil
-		} else {
-			right, err = i.visit(node.Right)
-			return IsTruthy(right), err
-		}
-	case "&&":
-		if !IsTruthy(left) {
-			return false, nil
-		} else {
-			right, err = i.visit(node.Right)
-			return IsTruthy(right), err
+	case "||", "&&":
+		// && and || only take booleans, so that a value is not mistaken for
+		// its truthiness
+		l, ok := left.(bool)
+		if !ok {
+			return nil, logicalOperandError(tokenKind)
+		}
+		if l == (tokenKind == "||") {
+			return l, nil
+		}
+		right, err = i.visit(node.Right)
+		if err != nil {
+			return nil, err
+		}
+		r, ok := right.(bool)
+		if !ok {
+			return nil, logicalOperandError(tokenKind)
 		}
+		return r, nil
 	default:
 		right, err = i.visit(node.Right)
 		if err != nil {
@@ -120,9 +165,9 @@ func (i NewInterpreter) Visit_BinOp(node parser.BinOp) (interface{}, error) {
 
 	switch tokenKind {
 	case "==":
-		return deepEquals(left, right), nil
+		return DeepEquals(left, right), nil
 	case "!=":
-		return !deepEquals(left, right), nil
+		return !DeepEquals(left, right), nil
 	case ".":
 		obj := left
 		key := right.(string)
@@ -131,18 +176,30 @@ func (i NewInterpreter) Visit_BinOp(node parser.BinOp) (interface{}, error) {
 				return value, nil
 			}
 			return nil, parser.SyntaxError{
-				Message: "object has no such property",
+				Message: fmt.Sprintf("object has no property \"%s\"", key),
 			}
 		}
 		return nil, parser.SyntaxError{
 			Message: "cannot access properties of non-object",
 		}
+	case "?.":
+		// like ".", but nil for a missing property; a null object skips the
+		// rest of the chain
+		if left == nil {
+			return skipped{}, nil
+		}
+		if target, ok := left.(map[string]interface{}); ok {
+			return target[right.(string)], nil
+		}
+		return nil, parser.SyntaxError{
+			Message: "cannot access properties of non-object",
+		}
 	case "in":
 		// A in B, where B is a string
 		if s, ok := right.(string); ok {
 			if !isString(left) {
 				return nil, parser.SyntaxError{
-					Message: "in operator expected a string when querying on a string",
+					Message: "infix: in-string expects string on left side",
 				}
 			}
 			return strings.Contains(s, left.(string)), nil
@@ -152,7 +209,7 @@ func (i NewInterpreter) Visit_BinOp(node parser.BinOp) (interface{}, error) {
 		if o, ok := right.(map[string]interface{}); ok {
 			if !isString(left) {
 				return nil, parser.SyntaxError{
-					Message: "in operator expected a string when querying on an object",
+					Message: "infix: in-object expects string on left side",
 				}
 			}
 			_, result := o[left.(string)]
@@ -162,7 +219,7 @@ func (i NewInterpreter) Visit_BinOp(node parser.BinOp) (interface{}, error) {
 		// A in B; where B is an array
 		if a, ok := right.([]interface{}); ok {
 			for _, val := range a {
-				if deepEquals(left, val) {
+				if DeepEquals(left, val) {
 					return true, nil
 				}
 			}
@@ -170,7 +227,7 @@ func (i NewInterpreter) Visit_BinOp(node parser.BinOp) (interface{}, error) {
 		}
 
 		return nil, parser.SyntaxError{
-			Message: "in operator expected string, array or object",
+			Message: "infix: in expects array, string, or object on right side",
 		}
 	case "+":
 		if isNumber(left) && isNumber(right) {
@@ -211,10 +268,13 @@ func (i NewInterpreter) Visit_List(node parser.List) (interface{}, error) {
 }
 
 func (i NewInterpreter) Visit_ValueAccess(node parser.ValueAccess) (interface{}, error) {
-	arr, err := i.visit(node.Arr)
+	arr, err := i.visitLink(node.Arr)
 	if err != nil {
 		return nil, err
 	}
+	if _, ok := arr.(skipped); ok {
+		return arr, nil
+	}
 	var right, left interface{}
 	if node.Left != nil {
 		left, err = i.visit(node.Left)
@@ -237,7 +297,9 @@ func (i NewInterpreter) Visit_ValueAccess(node parser.ValueAccess) (interface{},
 				if value, ok := target[k]; ok {
 					return value, nil
 				}
-				return nil, nil
+				return nil, parser.SyntaxError{
+					Message: fmt.Sprintf("object has no property \"%s\"", k),
+				}
 			}
 			return nil, parser.SyntaxError{
 				Message: "object properties must be accessed with strings",
@@ -246,9 +308,9 @@ func (i NewInterpreter) Visit_ValueAccess(node parser.ValueAccess) (interface{},
 	}
 
 	// Check that we have integer arguments
-	A, aok := left.(float64)
-	B, bok := right.(float64)
-	if !aok || A != float64(int(A)) || (right != nil && !(bok && B == float64(int(B)))) {
+	start, aok := asIndex(left)
+	end, bok := asIndex(right)
+	if !aok || (right != nil && !bok) {
 		return nil, parser.SyntaxError{
 			Message: "slicing can only be used with integer arguments",
 		}
@@ -256,13 +318,14 @@ func (i NewInterpreter) Visit_ValueAccess(node parser.ValueAccess) (interface{},
 
 	// Handle slicing of arrays
 	if target, ok := arr.([]interface{}); ok {
-		start := int(A)
-		end := int(B)
 		if right == nil {
 			end = len(target)
 		}
 		if start < 0 {
 			start = len(target) + start
+			if start < 0 && node.IsInterval {
+				start = 0
+			}
 		}
 		if end < 0 {
 			end = len(target) + end
@@ -277,7 +340,7 @@ func (i NewInterpreter) Visit_ValueAccess(node parser.ValueAccess) (interface{},
 			start = end
 		}
 		if !node.IsInterval {
-			if start >= len(target) {
+			if start < 0 || start >= len(target) {
 				return nil, parser.SyntaxError{
 					Message: "string index out of bounds",
 				}
@@ -287,15 +350,17 @@ func (i NewInterpreter) Visit_ValueAccess(node parser.ValueAccess) (interface{},
 		return target[start:end], nil
 	}
 	// Handle slicing of strings
-	if target, ok := arr.(string); ok {
-		// TODO: Handle utf-8 encoding...
-		start := int(A)
-		end := int(B)
+	if str, ok := arr.(string); ok {
+		// Index by rune, so that multi-byte characters are never split
+		target := []rune(str)
 		if right == nil {
 			end = len(target)
 		}
 		if start < 0 {
 			start = len(target) + start
+			if start < 0 && node.IsInterval {
+				start = 0
+			}
 		}
 		if end < 0 {
 			end = len(target) + end
@@ -310,14 +375,14 @@ func (i NewInterpreter) Visit_ValueAccess(node parser.ValueAccess) (interface{},
 			start = end
 		}
 		if !node.IsInterval {
-			if start >= len(target) {
+			if start < 0 || start >= len(target) {
 				return nil, parser.SyntaxError{
 					Message: "string index out of bounds",
 				}
 			}
 			return string(target[start]), nil
 		}
-		return target[start:end], nil
+		return string(target[start:end]), nil
 	}
 
 	return nil, parser.SyntaxError{
@@ -325,22 +390,46 @@ func (i NewInterpreter) Visit_ValueAccess(node parser.ValueAccess) (interface{},
 	}
 }
 
+// asIndex converts an integral number to an int for indexing or slicing,
+// rejecting fractional numbers and numbers too large to index with.
+func asIndex(value interface{}) (int, bool) {
+	f, ok := value.(float64)
+	if !ok || f != math.Trunc(f) || math.Abs(f) > 1<<53 {
+		return 0, false
+	}
+	return int(f), true
+}
+
 func (i NewInterpreter) Visit_ContextValue(node parser.ContextValue) (interface{}, error) {
 	if contextValue, ok := i.context[node.Token.Value]; ok {
 		return contextValue, nil
 	}
 	return nil, parser.SyntaxError{
-		Message: fmt.Sprintf("undefined variable %s", node.Token.Value),
+		Message:  fmt.Sprintf("undefined variable %s", node.Token.Value),
+		Variable: node.Token.Value,
 	}
 }
 func (i NewInterpreter) Visit_FunctionCall(node parser.FunctionCall) (interface{}, error) {
 
 	var args []interface{}
-	funcName, err := i.visit(node.Name)
+	funcName, err := i.visitLink(node.Name)
 	if err != nil {
 		return nil, err
 	}
+	if _, ok := funcName.(skipped); ok {
+		return funcName, nil
+	}
 	f, ok := funcName.(*function)
+	if ok && f.Lazy {
+		lazyArgs := make([]LazyArgument, len(node.Args))
+		for idx, element := range node.Args {
+			element := element
+			lazyArgs[idx] = func() (interface{}, error) {
+				return i.visit(element)
+			}
+		}
+		return f.Function.(func([]LazyArgument) (interface{}, error))(lazyArgs)
+	}
 	if ok {
 		var result interface{}
 
@@ -380,6 +469,12 @@ func (i NewInterpreter) Interpret(node parser.IASTNode) (result interface{}, err
 	return
 }
 
+func logicalOperandError(tokenKind string) error {
+	return parser.SyntaxError{
+		Message: fmt.Sprintf("infix: %s expects boolean %s boolean; compare other values, as in x != null", tokenKind, tokenKind),
+	}
+}
+
 func mathOp(left, right interface{}, tokenKind string) (interface{}, error) {
 
 	if isNumber(left) && isNumber(right) {
@@ -392,8 +487,30 @@ func mathOp(left, right interface{}, tokenKind string) (interface{}, error) {
 			return l * r, nil
 		case "/":
 			return l / r, nil
+		case "//":
+			if r == 0 {
+				return nil, parser.SyntaxError{
+					Message: "infix: // expects a non-zero divisor",
+				}
+			}
+			// floor division, avoiding a negative zero result
+			return math.Floor(l/r) + 0, nil
+		case "%":
+			if r == 0 {
+				return nil, parser.SyntaxError{
+					Message: "infix: % expects a non-zero divisor",
+				}
+			}
+			// truncated remainder, taking the sign of the dividend as JS does
+			return math.Mod(l, r), nil
 		case "**":
-			return math.Pow(r, l), nil
+			result := math.Pow(r, l)
+			if math.IsNaN(result) {
+				return nil, parser.SyntaxError{
+					Message: "infix: ** result is not a number",
+				}
+			}
+			return result, nil
 		default:
 			panic("unknown operator")
 		}
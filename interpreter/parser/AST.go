@@ -32,6 +32,19 @@ func (u *UnaryOp) NewNode(token Token, expr IASTNode) {
 	u.Expr = expr
 }
 
+type TernaryOp struct {
+	Token              Token
+	Condition          IASTNode
+	ThenExpr, ElseExpr IASTNode
+}
+
+func (t *TernaryOp) NewNode(token Token, condition, thenExpr, elseExpr IASTNode) {
+	t.Token = token
+	t.Condition = condition
+	t.ThenExpr = thenExpr
+	t.ElseExpr = elseExpr
+}
+
 type FunctionCall struct {
 	Token Token
 	Name  IASTNode
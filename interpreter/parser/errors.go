@@ -3,8 +3,25 @@ package parser
 import (
 	"fmt"
 	"strings"
+	"unicode/utf8"
 )
 
+// maxSnippetLength is the length beyond which snippets of source are elided in
+// error messages, so that a very long expression does not produce an even
+// longer message.
+const maxSnippetLength = 50
+
+func snippet(s string) string {
+	if len(s) <= maxSnippetLength {
+		return s
+	}
+	end := maxSnippetLength
+	for end > 0 && !utf8.RuneStart(s[end]) {
+		end--
+	}
+	return s[:end] + "..."
+}
+
 // SyntaxError holds a snippet of source that couldn't be handled.
 type SyntaxError struct {
 	Message  string
@@ -12,6 +29,9 @@ type SyntaxError struct {
 	Start    int
 	End      int
 	Expected []string
+	// Variable is the name of the variable that is not in the context, for
+	// an error referring to one
+	Variable string
 }
 
 func (s SyntaxError) Error() string {
@@ -21,9 +41,39 @@ func (s SyntaxError) Error() string {
 	}
 	if s.Expected != nil {
 		return fmt.Sprintf("%s expected %s at %d -> '%s' in '%s'",
-			m, strings.Join(s.Expected, ", "), s.Start, s.Source[s.Start:s.End], s.Source,
+			m, strings.Join(s.Expected, ", "), s.Start, snippet(s.Source[s.Start:s.End]), snippet(s.Source),
 		)
 	}
 	return fmt.Sprintf("%s at %d -> '%s' in '%s'",
-		m, s.Start, s.Source[s.Start:s.End], s.Source)
+		m, s.Start, snippet(s.Source[s.Start:s.End]), snippet(s.Source))
+}
+
+// UserMessage returns the message and the expected tokens, without the
+// position in the source that Error includes for debugging.
+func (s SyntaxError) UserMessage() string {
+	m := s.Message
+	if m == "" {
+		m = "syntax error"
+	}
+	if s.Expected != nil {
+		return fmt.Sprintf("%s, expected %s", m, strings.Join(s.Expected, ", "))
+	}
+	return m
+}
+
+// NestingError is returned for an expression nested more than 64 levels deep,
+// counting parentheses, brackets, braces, unary operators and each operand of
+// `**`
+type NestingError struct {
+	Source string
+}
+
+func (e NestingError) Error() string {
+	return fmt.Sprintf("expression is nested more than %d levels deep in '%s'", maxNesting, snippet(e.Source))
+}
+
+// UserMessage returns the message without the source that Error includes for
+// debugging.
+func (e NestingError) UserMessage() string {
+	return fmt.Sprintf("expression is nested more than %d levels deep", maxNesting)
 }
@@ -2,8 +2,16 @@ package parser
 
 import (
 	"fmt"
+	"regexp"
+	"strconv"
+	"unicode/utf16"
 )
 
+// maxNesting is the deepest an expression may nest, counting parentheses,
+// brackets, braces, unary operators and the right-associative `**`; expressions
+// are parsed and evaluated recursively, so this keeps that within the stack
+const maxNesting = 64
+
 type Parser struct {
 	source              string
 	tokenizer           Tokenizer
@@ -12,6 +20,7 @@ type Parser struct {
 	primitivesTokens    []string
 	operatorsByPriority [][]string
 	expectedTokens      []string
+	depth               int
 }
 
 func (p *Parser) NewParser(source string, tokenizer Tokenizer, offset int) (err error) {
@@ -20,11 +29,21 @@ func (p *Parser) NewParser(source string, tokenizer Tokenizer, offset int) (err
 	p.CurrentToken, err = p.tokenizer.Next(p.source, offset)
 	p.unaryOpTokens = []string{"-", "+", "!"}
 	p.primitivesTokens = []string{"number", "null", "true", "false", "string"}
-	p.operatorsByPriority = [][]string{{"||"}, {"&&"}, {"in"}, {"==", "!="}, {">", "<", "<=", ">="}, {"+", "-"}, {"*", "/"}, {"**"}}
+	p.operatorsByPriority = [][]string{{"||"}, {"&&"}, {"in"}, {"==", "!="}, {">", "<", "<=", ">="}, {"+", "-"}, {"*", "/", "//", "%"}, {"**"}}
 	p.expectedTokens = []string{"!", "(", "+", "-", "[", "false", "identifier", "null", "number", "string", "true", "{"}
+	p.depth = 0
 	return
 }
 
+// nest goes count levels deeper into the expression, or back when negative
+func (p *Parser) nest(count int) error {
+	p.depth += count
+	if p.depth > maxNesting {
+		return NestingError{Source: p.source}
+	}
+	return nil
+}
+
 func (p *Parser) takeToken(kinds ...string) error {
 	var err error
 	if p.CurrentToken.IsEmpty() {
@@ -49,16 +68,26 @@ func (p *Parser) takeToken(kinds ...string) error {
 }
 
 func (p *Parser) Parse(level int) (node IASTNode, err error) {
-	//expr : logicalAnd (OR logicalAnd)*
+	//expr : logicalOr (QUESTION expr COLON expr)?
+	//logicalOr : logicalAnd (OR logicalAnd)*
 	//logicalAnd : inStatement (AND inStatement)*
 	//inStatement : equality (IN equality)*
 	//equality : comparison (EQUALITY | INEQUALITY  comparison)*
 	//comparison : addition (LESS | GREATER | LESSEQUAL | GREATEREQUAL addition)*
 	//addition : multiplication (PLUS | MINUS multiplication)* "
-	//multiplication : exponentiation (MUL | DIV exponentiation)*
+	//multiplication : exponentiation (MUL | DIV | FLOORDIV | MOD exponentiation)*
 	//exponentiation : propertyAccessOrFunc (EXP exponentiation)*
 	var binaryNode BinOp
 	var next IASTNode
+	// a whole expression, as within parentheses, is a level deeper, as is
+	// each operand of `**`, which nests to the right
+	chain := 0
+	if level == 0 {
+		chain = 1
+	}
+	if err = p.nest(chain); err != nil {
+		return nil, err
+	}
 	if level == len(p.operatorsByPriority)-1 {
 		node, err = p.parsePropertyAccessOrFunc()
 		if err != nil {
@@ -67,14 +96,24 @@ func (p *Parser) Parse(level int) (node IASTNode, err error) {
 		token := p.CurrentToken
 
 		for ; token != (Token{}) && StringsContains(token.Kind, p.operatorsByPriority[level]); token = p.CurrentToken {
+			if err = p.checkOperand(node, token); err != nil {
+				return nil, err
+			}
 			err = p.takeToken(token.Kind)
 			if err != nil {
 				return nil, err
 			}
+			if err = p.nest(1); err != nil {
+				return nil, err
+			}
+			chain++
 			next, err = p.Parse(level)
 			if err != nil {
 				return nil, err
 			}
+			if err = p.checkOperand(next, p.CurrentToken); err != nil {
+				return nil, err
+			}
 			binaryNode.NewNode(token, next, node)
 			node = binaryNode
 		}
@@ -86,6 +125,9 @@ func (p *Parser) Parse(level int) (node IASTNode, err error) {
 		token := p.CurrentToken
 
 		for ; token != (Token{}) && StringsContains(token.Kind, p.operatorsByPriority[level]); token = p.CurrentToken {
+			if err = p.checkOperand(node, token); err != nil {
+				return nil, err
+			}
 			err = p.takeToken(token.Kind)
 			if err != nil {
 				return nil, err
@@ -94,23 +136,93 @@ func (p *Parser) Parse(level int) (node IASTNode, err error) {
 			if err != nil {
 				return nil, err
 			}
+			if err = p.checkOperand(next, p.CurrentToken); err != nil {
+				return nil, err
+			}
 			binaryNode.NewNode(token, node, next)
 			node = binaryNode
 		}
 	}
 
+	if level == 0 && p.CurrentToken.Kind == "?" {
+		if err = p.checkOperand(node, p.CurrentToken); err != nil {
+			return nil, err
+		}
+		node, err = p.parseTernary(node)
+	}
+
+	p.depth -= chain
+	return
+}
+
+// checkOperand returns an error if an operand of a binary operator is missing,
+// so `2 * *3` is not parsed as a multiplication with a missing operand
+func (p *Parser) checkOperand(node IASTNode, token Token) error {
+	if node == nil {
+		return SyntaxError{
+			Message:  fmt.Sprintf("Found '%s'", token.Kind),
+			Source:   p.source,
+			Start:    token.Start,
+			End:      token.End,
+			Expected: p.expectedTokens,
+		}
+	}
+	return nil
+}
+
+func (p *Parser) parseTernary(condition IASTNode) (node IASTNode, err error) {
+	//ternary : logicalOr QUESTION expr COLON expr
+	var ternaryNode TernaryOp
+	var thenExpr, elseExpr IASTNode
+	token := p.CurrentToken
+	err = p.takeToken("?")
+	if err != nil {
+		return nil, err
+	}
+	thenExpr, err = p.Parse(0)
+	if err != nil {
+		return nil, err
+	}
+	if thenExpr == nil {
+		return nil, SyntaxError{
+			Message:  fmt.Sprintf("Found '%s'", p.CurrentToken.Kind),
+			Source:   p.source,
+			Start:    p.CurrentToken.Start,
+			End:      p.CurrentToken.End,
+			Expected: p.expectedTokens,
+		}
+	}
+	err = p.takeToken(":")
+	if err != nil {
+		return nil, err
+	}
+	elseExpr, err = p.Parse(0)
+	if err != nil {
+		return nil, err
+	}
+	if elseExpr == nil {
+		return nil, SyntaxError{
+			Message:  fmt.Sprintf("Found '%s'", p.CurrentToken.Kind),
+			Source:   p.source,
+			Start:    p.CurrentToken.Start,
+			End:      p.CurrentToken.End,
+			Expected: p.expectedTokens,
+		}
+	}
+	ternaryNode.NewNode(token, condition, thenExpr, elseExpr)
+	node = ternaryNode
 	return
 }
 
 func (p *Parser) parsePropertyAccessOrFunc() (node IASTNode, err error) {
-	//propertyAccessOrFunc : unit (accessWithBrackets | DOT id | functionCall)*
+	//propertyAccessOrFunc : unit (accessWithBrackets | (DOT | QUESTIONDOT) id | functionCall)*
 	var primitiveNode ASTNode
 	var binaryNode BinOp
 	node, err = p.parseUnit()
 	if err != nil {
 		return nil, err
 	}
-	operators := []string{"[", "(", "."}
+	operators := []string{"[", "(", ".", "?."}
 
 	for token := p.CurrentToken; p.CurrentToken != (Token{}) && StringsContains(token.Kind, operators); token = p.CurrentToken {
 		if token.Kind == "[" {
@@ -118,9 +230,9 @@ func (p *Parser) parsePropertyAccessOrFunc() (node IASTNode, err error) {
 			if err != nil {
 				return nil, err
 			}
-		} else if token.Kind == "." {
+		} else if token.Kind == "." || token.Kind == "?." {
 			token = p.CurrentToken
-			err = p.takeToken(".")
+			err = p.takeToken(token.Kind)
 			if err != nil {
 				return nil, err
 			}
@@ -144,7 +256,7 @@ func (p *Parser) parsePropertyAccessOrFunc() (node IASTNode, err error) {
 }
 
 func (p *Parser) parseUnit() (node IASTNode, err error) {
-	// unit : unaryOp unit | primitives | contextValue | LPAREN expr RPAREN | list | object
+	// unit : unaryOp exponentiation | primitives | contextValue | LPAREN expr RPAREN | list | object
 	var unaryNode UnaryOp
 	var primitiveNode ASTNode
 	var contextValueNode ContextValue
@@ -166,13 +278,26 @@ func (p *Parser) parseUnit() (node IASTNode, err error) {
 		if err != nil {
 			return nil, err
 		}
-		next, err = p.parseUnit()
+		// the operand is an exponentiation, so `-2 ** 2` is `-(2 ** 2)`
+		if err = p.nest(1); err != nil {
+			return nil, err
+		}
+		next, err = p.Parse(len(p.operatorsByPriority) - 1)
 		if err != nil {
 			return nil, err
 		}
+		if err = p.checkOperand(next, p.CurrentToken); err != nil {
+			return nil, err
+		}
+		p.depth--
 		unaryNode.NewNode(token, next)
 		node = unaryNode
 	} else if isPrimitivesToken {
+		if token.Kind == "string" {
+			if err = p.checkString(token); err != nil {
+				return nil, err
+			}
+		}
 		err = p.takeToken(token.Kind)
 		if err != nil {
 			return nil, err
@@ -271,7 +396,7 @@ func (p *Parser) parseFunctionCall(name IASTNode) (node IASTNode, err error) {
 }
 
 func (p *Parser) parseList() (node IASTNode, err error) {
-	//    list : LSQAREBRAKET (expr ( COMMA expr)*)? RSQAREBRAKET)
+	//    list : LSQAREBRAKET (expr ( COMMA expr)* COMMA?)? RSQAREBRAKET)
 	var list []IASTNode
 	var listNode List
 	var token = p.CurrentToken
@@ -301,6 +426,9 @@ func (p *Parser) parseList() (node IASTNode, err error) {
 			if err != nil {
 				return nil, err
 			}
+			if p.CurrentToken.Kind == "]" {
+				break
+			}
 			node, err = p.Parse(0)
 			if err != nil {
 				return nil, err
@@ -391,7 +519,10 @@ func (p *Parser) parseObject() (node IASTNode, err error) {
 	for p.CurrentToken.Kind == "string" || p.CurrentToken.Kind == "identifier" {
 		key := p.CurrentToken.Value
 		if p.CurrentToken.Kind == "string" {
-			key = parseString(key)
+			if err = p.checkString(p.CurrentToken); err != nil {
+				return nil, err
+			}
+			key = ParseString(key)
 		}
 		err = p.takeToken(p.CurrentToken.Kind)
 		if err != nil {
@@ -434,6 +565,50 @@ func (p *Parser) parseObject() (node IASTNode, err error) {
 	return
 }
 
-func parseString(s string) string {
-	return s[1 : len(s)-1]
+// escapes gives the characters of a string literal's escapes, other than
+// \uXXXX
+var escapes = map[string]string{"n": "\n", "t": "\t", "\\": "\\", "'": "'", "\"": "\""}
+
+// escapeRegexp matches an escape; a surrogate pair is a single escape, so that
+// it gives one character
+var escapeRegexp = regexp.MustCompile(`\\(u[dD][89abAB][0-9a-fA-F]{2}\\u[dD][c-fC-F][0-9a-fA-F]{2}|u[0-9a-fA-F]{4}|[\s\S])`)
+
+// checkString returns a SyntaxError if a string literal has a \uXXXX escape
+// for a surrogate that is not part of a pair, as not every language can
+// represent one in a string
+func (p *Parser) checkString(token Token) error {
+	for _, escape := range escapeRegexp.FindAllString(token.Value[1:len(token.Value)-1], -1) {
+		if len(escape) != 6 {
+			continue
+		}
+		if code, _ := strconv.ParseUint(escape[2:], 16, 32); code >= 0xD800 && code <= 0xDFFF {
+			return SyntaxError{
+				Message: fmt.Sprintf("string contains a lone surrogate %s", escape),
+				Source:  p.source,
+				Start:   token.Start,
+				End:     token.End,
+			}
+		}
+	}
+	return nil
+}
+
+// ParseString removes the quotes of a string literal and expands its escapes;
+// a backslash that does not begin an escape is kept as it is
+func ParseString(s string) string {
+	return escapeRegexp.ReplaceAllStringFunc(s[1:len(s)-1], func(escape string) string {
+		switch len(escape) {
+		case 12:
+			high, _ := strconv.ParseUint(escape[2:6], 16, 32)
+			low, _ := strconv.ParseUint(escape[8:], 16, 32)
+			return string(utf16.DecodeRune(rune(high), rune(low)))
+		case 6:
+			code, _ := strconv.ParseUint(escape[2:], 16, 32)
+			return string(rune(code))
+		}
+		if c, ok := escapes[escape[1:]]; ok {
+			return c
+		}
+		return escape
+	})
 }
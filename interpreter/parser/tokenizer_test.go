@@ -40,3 +40,41 @@ func TestTokenizer(t *testing.T) {
 	}
 	require.EqualValues(t, expected, kinds)
 }
+
+func TestTokenizerPrefersLongestToken(t *testing.T) {
+	tok := NewTokenizer(`\s+`, []string{`**`, `*`, `number`}, map[string]string{
+		"number": `[0-9]+(?:\.[0-9]+)?`,
+	})
+	tokens, err := tok.Tokenize(`2**3`, 0)
+	require.NoError(t, err)
+	require.EqualValues(t, []Token{
+		{Kind: "number", Value: "2", Start: 0, End: 1},
+		{Kind: "**", Value: "**", Start: 1, End: 3},
+		{Kind: "number", Value: "3", Start: 3, End: 4},
+	}, tokens)
+
+	tokens, err = tok.Tokenize(`2 * *3`, 0)
+	require.NoError(t, err)
+	var kinds []string
+	for _, token := range tokens {
+		kinds = append(kinds, token.Kind)
+	}
+	require.EqualValues(t, []string{"number", "*", "*", "number"}, kinds)
+}
+
+func TestTokenizerLongTokens(t *testing.T) {
+	tok := NewTokenizer(`\s+`, []string{`+`, `identifier`, `string`}, map[string]string{
+		"identifier": `[a-zA-Z_][a-zA-Z_0-9]*`,
+		"string":     `'[^']*'|"[^"]*"`,
+	})
+	name := strings.Repeat("x", 1000000)
+	tokens, err := tok.Tokenize(name+" + "+name, 0)
+	require.NoError(t, err)
+	require.Len(t, tokens, 3)
+	require.Equal(t, name, tokens[2].Value)
+
+	_, err = tok.Tokenize("'"+name, 0)
+	require.Error(t, err)
+	require.Contains(t, err.Error(), "'"+strings.Repeat("x", 49)+"...'")
+	require.True(t, len(err.Error()) < 200, "error message is not elided")
+}
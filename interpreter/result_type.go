@@ -0,0 +1,72 @@
+package interpreter
+
+import (
+	p "github.com/taskcluster/json-e/interpreter/parser"
+)
+
+// operatorTypes gives the operators whose result, if any, always has the same
+// type
+var operatorTypes = map[string]string{
+	"-": "number", "*": "number", "/": "number", "//": "number", "%": "number", "**": "number",
+	"<": "boolean", ">": "boolean", "<=": "boolean", ">=": "boolean", "==": "boolean", "!=": "boolean",
+	"&&": "boolean", "||": "boolean", "in": "boolean",
+}
+
+var literalTypes = map[string]string{
+	"number": "number", "string": "string", "true": "boolean", "false": "boolean", "null": "null",
+}
+
+// StaticResultType returns the type of the value expression evaluates to, as
+// named by the typeof builtin, when that does not depend on the context;
+// otherwise it returns an empty string.
+func StaticResultType(expression string) (string, error) {
+	tree, err := parseExpression(expression)
+	if err != nil {
+		return "", err
+	}
+	return resultType(tree), nil
+}
+
+// resultType returns the type of the value the tree evaluates to, or an empty
+// string if that depends on the context. An expression that fails when it is
+// evaluated has no value, so it is not considered here.
+func resultType(node p.IASTNode) string {
+	switch n := node.(type) {
+	case p.ASTNode:
+		return literalTypes[n.Token.Kind]
+	case p.UnaryOp:
+		if n.Token.Kind == "!" {
+			return "boolean"
+		}
+		return "number"
+	case p.BinOp:
+		if n.Token.Kind == "+" {
+			// + adds numbers or concatenates strings, so one side is enough
+			left, right := resultType(n.Left), resultType(n.Right)
+			if left == "number" || right == "number" {
+				return "number"
+			}
+			if left == "string" || right == "string" {
+				return "string"
+			}
+			return ""
+		}
+		return operatorTypes[n.Token.Kind]
+	case p.TernaryOp:
+		if t := resultType(n.ThenExpr); t == resultType(n.ElseExpr) {
+			return t
+		}
+	case p.List:
+		return "array"
+	case p.Object:
+		return "object"
+	case p.ValueAccess:
+		// indexing or slicing a string gives a string, and slicing an array
+		// gives an array
+		if t := resultType(n.Arr); t == "string" || t == "array" && n.IsInterval {
+			return t
+		}
+	}
+	// context values, function calls and property access
+	return ""
+}
@@ -1,10 +1,13 @@
 package interpreter
 
 import (
+	"encoding/json"
 	"fmt"
+	"math/big"
 	"reflect"
 	"regexp"
 	"runtime"
+	"strings"
 )
 
 func isString(v interface{}) bool {
@@ -28,6 +31,9 @@ func IsJSON(v interface{}) bool {
 		return true
 	}
 	switch val := v.(type) {
+	case json.Number:
+		// a number from the template that is too precise for float64
+		return true
 	case []interface{}:
 		for _, entry := range val {
 			if !IsJSON(entry) {
@@ -59,6 +65,10 @@ func IsTruthy(v interface{}) bool {
 		return len(val) > 0
 	case float64:
 		return val != 0
+	case json.Number:
+		// a number is zero when every digit before its exponent is
+		mantissa := strings.TrimLeft(strings.SplitN(strings.ToLower(val.String()), "e", 2)[0], "-")
+		return strings.Trim(mantissa, "0.") != ""
 	case bool:
 		return val
 	case *function:
@@ -71,8 +81,13 @@ func IsTruthy(v interface{}) bool {
 type function struct {
 	Function    interface{}
 	WithContext bool
+	Lazy        bool
 }
 
+// LazyArgument is an unevaluated argument to a function wrapped by
+// WrapLazyFunction, calling it evaluates the argument.
+type LazyArgument func() (interface{}, error)
+
 var allowedTypes = []reflect.Type{
 	reflect.TypeOf(float64(0)),
 	reflect.TypeOf(""),
@@ -88,7 +103,7 @@ var typeOfInterface = reflect.TypeOf((*interface{})(nil)).Elem()
 func injectedFunctionError(f interface{}, m string) error {
 	fn := runtime.FuncForPC(reflect.ValueOf(f).Pointer())
 	file, line := fn.FileLine(fn.Entry())
-	return fmt.Errorf("injected function %s:%d %s %s", file, line, fn, m)
+	return fmt.Errorf("injected function %s:%d %s %s", file, line, fn.Name(), m)
 }
 
 // IsWrappedFunction returns true, if the value is a function wrapped by
@@ -125,6 +140,15 @@ func WrapFunctionWithContext(f interface{}) interface{} {
 	return v
 }
 
+// WrapLazyFunction wraps a function that receives its arguments unevaluated,
+// so that it can decide whether to evaluate each of them.
+func WrapLazyFunction(f func(args []LazyArgument) (interface{}, error)) interface{} {
+	return &function{
+		Function: f,
+		Lazy:     true,
+	}
+}
+
 func wrapFunction(f interface{}, withContext bool) (interface{}, error) {
 	t := reflect.TypeOf(f)
 	if t.Kind() != reflect.Func {
@@ -242,7 +266,7 @@ func IsValidData(data interface{}) error {
 		return nil
 	}
 	switch val := data.(type) {
-	case *function, string, float64, bool:
+	case *function, string, float64, json.Number, bool:
 		return nil
 	case []interface{}:
 		for _, value := range val {
@@ -282,7 +306,9 @@ func IsValidContext(context map[string]interface{}) error {
 	return nil
 }
 
-func deepEquals(a, b interface{}) bool {
+// DeepEquals returns true, if a and b are equal JSON values, comparing arrays
+// and objects by their contents
+func DeepEquals(a, b interface{}) bool {
 	switch A := a.(type) {
 	case *function:
 		B, ok := b.(*function)
@@ -291,8 +317,12 @@ func deepEquals(a, b interface{}) bool {
 		B, ok := b.(string)
 		return ok && A == B
 	case float64:
-		B, ok := b.(float64)
-		return ok && A == B
+		if B, ok := b.(float64); ok {
+			return A == B
+		}
+		return equalNumbers(a, b)
+	case json.Number:
+		return equalNumbers(a, b)
 	case bool:
 		B, ok := b.(bool)
 		return ok && A == B
@@ -304,7 +334,7 @@ func deepEquals(a, b interface{}) bool {
 				return false
 			}
 			for i, v := range A {
-				if !deepEquals(v, B[i]) {
+				if !DeepEquals(v, B[i]) {
 					return false
 				}
 			}
@@ -317,7 +347,7 @@ func deepEquals(a, b interface{}) bool {
 				return false
 			}
 			for k, vA := range A {
-				if vB, ok := B[k]; !ok || !deepEquals(vA, vB) {
+				if vB, ok := B[k]; !ok || !DeepEquals(vA, vB) {
 					return false
 				}
 			}
@@ -328,3 +358,25 @@ func deepEquals(a, b interface{}) bool {
 		panic(fmt.Errorf("cannot compare unsupported type: %T", a))
 	}
 }
+
+// equalNumbers returns true, if a and b are numbers, each a float64 or a
+// json.Number too precise for one, with the same exact value
+func equalNumbers(a, b interface{}) bool {
+	A, ok := exactNumber(a)
+	if !ok {
+		return false
+	}
+	B, ok := exactNumber(b)
+	return ok && A.Cmp(B) == 0
+}
+
+func exactNumber(v interface{}) (*big.Rat, bool) {
+	switch n := v.(type) {
+	case float64:
+		r := new(big.Rat).SetFloat64(n)
+		return r, r != nil
+	case json.Number:
+		return new(big.Rat).SetString(n.String())
+	}
+	return nil, false
+}
@@ -1,9 +1,11 @@
 package jsone
 
 import (
+	"bytes"
 	"encoding/json"
 	"fmt"
 	"math"
+	"math/big"
 	"regexp"
 	"sort"
 	"strconv"
@@ -13,17 +15,82 @@ import (
 	"unicode/utf8"
 
 	i "github.com/taskcluster/json-e/interpreter"
+	"github.com/taskcluster/json-e/interpreter/parser"
 )
 
+// RenderOptions holds optional settings for RenderWithOptions.
+type RenderOptions struct {
+	// MaxElements limits the total number of array and object elements
+	// produced while rendering, zero means no limit.
+	MaxElements int
+	// ExpectType is the type the result must have, as named by the typeof
+	// builtin, empty means any type.
+	ExpectType string
+	// MaxSteps limits the number of evaluation steps, counting each
+	// expression node evaluated and each element produced, zero means no
+	// limit.
+	MaxSteps int
+	// MaxDepth limits how deeply arrays and objects, including operators,
+	// may be nested in the template being rendered, zero means no limit.
+	MaxDepth int
+	// MaxExpressionLength limits the length of each expression evaluated,
+	// whether given to an operator or interpolated with ${..}, zero means no
+	// limit.
+	MaxExpressionLength int
+	// MaxInterpolationLength limits the length in bytes of each string
+	// produced by interpolating ${..} expressions, zero means no limit.
+	MaxInterpolationLength int
+	// Timeout limits the time a render may take, zero means no limit.
+	Timeout time.Duration
+	// Partial leaves strings and operators that refer to variables not in
+	// the context as they are, so that the result can be rendered again with
+	// more context.
+	Partial bool
+}
+
+// renderState is kept in the context under stateKey for the duration of a
+// render. The key is not a valid identifier, so templates cannot refer to it.
+type renderState struct {
+	options  RenderOptions
+	elements int
+	steps    int
+	// depth is the number of arrays and objects being rendered within one
+	// another
+	depth int
+	// partial is whether strings and operators that refer to variables not
+	// in the context are left as they are; it is off within an operator
+	partial bool
+
+	// parsed holds the trees of the expressions evaluated so far in this
+	// render or, for a compiled Template, of all of its expressions
+	parsed *i.Cache
+
+	// step counts each expression node evaluated, only when there is a budget
+	// or a timeout
+	step func() error
+
+	// deadline is the time by which the render must finish, if there is a
+	// timeout
+	deadline time.Time
+}
+
+const stateKey = "$state"
+
 // Render template with given context
 func Render(template interface{}, context map[string]interface{}) (interface{}, error) {
+	return RenderWithOptions(template, context, RenderOptions{})
+}
+
+// buildContext validates the given context and adds builtins and render state,
+// keeping the trees of parsed expressions in parsed, or a new cache if nil
+func buildContext(context map[string]interface{}, options RenderOptions, parsed *i.Cache) map[string]interface{} {
 	// Validate input
 	if err := i.IsValidContext(context); err != nil {
 		panic(err)
 	}
 
 	// Inherit functions from builtins
-	c := make(map[string]interface{}, len(context)+len(builtin)+1)
+	c := make(map[string]interface{}, len(context)+len(builtin)+2)
 	c["now"] = time.Now().UTC().Format(timeFormat)
 	for k, v := range builtin {
 		c[k] = v
@@ -31,6 +98,55 @@ func Render(template interface{}, context map[string]interface{}) (interface{},
 	for k, v := range context {
 		c[k] = v
 	}
+	if parsed == nil {
+		parsed = new(i.Cache)
+	}
+	state := &renderState{options: options, partial: options.Partial, parsed: parsed}
+	if options.Timeout > 0 {
+		state.deadline = time.Now().Add(options.Timeout)
+	}
+	if options.MaxSteps > 0 || options.Timeout > 0 {
+		state.step = func() error { return state.countSteps(1) }
+	}
+	c[stateKey] = state
+	return c
+}
+
+// RenderWithOptions renders template with given context and options
+func RenderWithOptions(template interface{}, context map[string]interface{}, options RenderOptions) (interface{}, error) {
+	return renderTemplate(template, context, options, nil)
+}
+
+// Template is a template prepared for rendering many times. Its expressions
+// are parsed once, by Compile, and the trees are only read by the renders, so
+// a Template can be rendered by several goroutines at once.
+type Template struct {
+	template interface{}
+	parsed   i.Cache
+}
+
+// Compile prepares template for rendering many times, returning the first
+// problem Validate finds, if any, or else the first syntax error in its
+// expressions
+func Compile(template interface{}) (*Template, error) {
+	if errs := Validate(template); len(errs) > 0 {
+		return nil, errs[0]
+	}
+	t := &Template{template: template}
+	if err := parseAll(template, &t.parsed); err != nil {
+		return nil, nestingLimit(err)
+	}
+	t.parsed.Freeze()
+	return t, nil
+}
+
+// Render renders the template with given context and options
+func (t *Template) Render(context map[string]interface{}, options RenderOptions) (interface{}, error) {
+	return renderTemplate(t.template, context, options, &t.parsed)
+}
+
+func renderTemplate(template interface{}, context map[string]interface{}, options RenderOptions, parsed *i.Cache) (interface{}, error) {
+	c := buildContext(context, options, parsed)
 	// Render template
 	result, err := render(template, c)
 	if err != nil {
@@ -41,19 +157,202 @@ func Render(template interface{}, context map[string]interface{}) (interface{},
 	if result == deleteMarker {
 		result = nil
 	}
+	if !i.IsJSON(result) {
+		return nil, TemplateError{
+			Message:  "rendered output can only contain JSON types",
+			Template: template,
+		}
+	}
+	if options.ExpectType != "" && typeName(result) != options.ExpectType {
+		return nil, TemplateError{
+			Message:  fmt.Sprintf("rendered output is of type %s, expected %s", typeName(result), options.ExpectType),
+			Template: template,
+		}
+	}
 
 	// return result
 	return result, nil
 }
 
+// RenderInto renders template with given context, storing the result in the
+// value pointed to by out as json.Unmarshal would
+func RenderInto(template interface{}, context map[string]interface{}, out interface{}) error {
+	result, err := Render(template, context)
+	if err != nil {
+		return err
+	}
+	data, err := json.Marshal(result)
+	if err != nil {
+		return err
+	}
+	return json.Unmarshal(data, out)
+}
+
+// typeName returns the name of the type of a JSON value, as given by the typeof
+// builtin
+func typeName(value interface{}) string {
+	switch value.(type) {
+	case string:
+		return "string"
+	case float64, json.Number:
+		return "number"
+	case bool:
+		return "boolean"
+	case []interface{}:
+		return "array"
+	case map[string]interface{}:
+		return "object"
+	}
+	return "null"
+}
+
+// RenderEach renders a template that produces an array, calling emit with each
+// element as it is rendered instead of building the whole array. Array
+// templates and $map over an array are rendered one element at a time. An
+// error returned by emit stops rendering and is returned.
+func RenderEach(template interface{}, context map[string]interface{}, options RenderOptions, emit func(interface{}) error) error {
+	c := buildContext(context, options, nil)
+	emitJSON := func(value interface{}) error {
+		if !i.IsJSON(value) {
+			return TemplateError{
+				Message:  "rendered output can only contain JSON types",
+				Template: template,
+			}
+		}
+		if err := countElements(c, 1); err != nil {
+			return err
+		}
+		return emit(value)
+	}
+
+	var result interface{}
+	switch t := template.(type) {
+	case []interface{}:
+		for _, val := range t {
+			r, err := render(val, c)
+			if err != nil {
+				return err
+			}
+			if r == deleteMarker {
+				continue
+			}
+			if err := emitJSON(r); err != nil {
+				return err
+			}
+		}
+		return nil
+	case map[string]interface{}:
+		if _, ok := t["$map"]; ok {
+			m, err := parseMap("$map", t, c)
+			if err != nil {
+				return err
+			}
+			if list, ok := m.value.([]interface{}); ok {
+				return m.forEach(list, c, emitJSON)
+			}
+			break
+		}
+		r, err := render(t, c)
+		if err != nil {
+			return err
+		}
+		result = r
+	default:
+		r, err := render(t, c)
+		if err != nil {
+			return err
+		}
+		result = r
+	}
+
+	list, ok := result.([]interface{})
+	if !ok {
+		return TemplateError{
+			Message:  "RenderEach requires a template that renders to an array",
+			Template: template,
+		}
+	}
+	if !i.IsJSON(list) {
+		return TemplateError{
+			Message:  "rendered output can only contain JSON types",
+			Template: template,
+		}
+	}
+	for _, value := range list {
+		if err := emit(value); err != nil {
+			return err
+		}
+	}
+	return nil
+}
+
+// BuiltinNames returns the names of the built-in functions, in sorted order
+func BuiltinNames() []string {
+	return sortedKeys(builtin)
+}
+
 var deleteMarker = struct{}{}
 
+// evaluate parses and evaluates the expression source in context, reusing
+// the tree when source has been parsed before
+func evaluate(context map[string]interface{}, source string) (interface{}, error) {
+	state := context[stateKey].(*renderState)
+	if err := state.checkExpressionLength(len(source)); err != nil {
+		return nil, err
+	}
+	value, err := state.parsed.Parse(source, context, state.step)
+	return value, nestingLimit(err)
+}
+
+// countElements records count produced elements, failing if that exceeds the
+// limit given in RenderOptions
+func countElements(context map[string]interface{}, count int) error {
+	state := context[stateKey].(*renderState)
+	state.elements += count
+	if state.options.MaxElements > 0 && state.elements > state.options.MaxElements {
+		return limitExceeded(fmt.Sprintf("output exceeds the maximum of %d elements", state.options.MaxElements))
+	}
+	return state.countSteps(count)
+}
+
+// countSteps records count evaluation steps, failing if that exceeds the
+// MaxSteps given in RenderOptions or the render has run past its Timeout
+func (s *renderState) countSteps(count int) error {
+	s.steps += count
+	if s.options.MaxSteps > 0 && s.steps > s.options.MaxSteps {
+		return limitExceeded("evaluation budget exceeded")
+	}
+	if s.options.Timeout > 0 && time.Now().After(s.deadline) {
+		return limitExceeded(fmt.Sprintf("render exceeds the timeout of %s", s.options.Timeout))
+	}
+	return nil
+}
+
+// checkInterpolationLength fails if a string of the given length produced by
+// interpolation exceeds the MaxInterpolationLength given in RenderOptions
+func (s *renderState) checkInterpolationLength(length int) error {
+	if s.options.MaxInterpolationLength > 0 && length > s.options.MaxInterpolationLength {
+		return limitExceeded(fmt.Sprintf("interpolation exceeds the maximum length of %d", s.options.MaxInterpolationLength))
+	}
+	return nil
+}
+
+// checkExpressionLength fails if an expression of the given length exceeds
+// the MaxExpressionLength given in RenderOptions
+func (s *renderState) checkExpressionLength(length int) error {
+	if s.options.MaxExpressionLength > 0 && length > s.options.MaxExpressionLength {
+		return limitExceeded(fmt.Sprintf("expression exceeds the maximum length of %d", s.options.MaxExpressionLength))
+	}
+	return nil
+}
+
 type operator func(template, context map[string]interface{}) (interface{}, error)
 
 // TemplateError is an error in the template.
 type TemplateError struct {
 	Message  string
 	Template interface{}
+	cause    error // the error from evaluating an expression, if any
 }
 
 func (t TemplateError) Error() string {
@@ -61,6 +360,50 @@ func (t TemplateError) Error() string {
 	return fmt.Sprintf("%s in template %s", t.Message, string(data))
 }
 
+// Unwrap returns the error from evaluating an expression that caused this
+// one, if any
+func (t TemplateError) Unwrap() error {
+	return t.cause
+}
+
+// LimitExceededError is a TemplateError for a render that exceeds one of the
+// limits in RenderOptions, or an expression nested too deeply to parse.
+type LimitExceededError struct {
+	TemplateError
+}
+
+func limitExceeded(message string) error {
+	return LimitExceededError{TemplateError{Message: message}}
+}
+
+// nestingLimit returns err, or a LimitExceededError if it is a
+// parser.NestingError
+func nestingLimit(err error) error {
+	if e, ok := err.(parser.NestingError); ok {
+		return limitExceeded(e.UserMessage())
+	}
+	return err
+}
+
+// UserMessage returns the message without the offending template, which Error
+// includes for debugging.
+func (t TemplateError) UserMessage() string {
+	if t.cause != nil {
+		return UserMessage(t.cause)
+	}
+	return t.Message
+}
+
+// UserMessage returns a concise description of an error returned by Render,
+// suitable for showing to template authors; err.Error() gives the full detail
+// for logs.
+func UserMessage(err error) string {
+	if e, ok := err.(interface{ UserMessage() string }); ok {
+		return e.UserMessage()
+	}
+	return err.Error()
+}
+
 // restrictProperties returns an error if the template contains properties other
 // than those listed as allowed
 func restrictProperties(template map[string]interface{}, allowed ...string) error {
@@ -82,17 +425,44 @@ func restrictProperties(template map[string]interface{}, allowed ...string) erro
 	return nil
 }
 
-var fromNowPattern = regexp.MustCompile(strings.Join([]string{
-	`^(?:\s*(-|\+))?`,
-	`(?:\s*(\d+)\s*y(?:(?:ears?)|r)?)?`,
-	`(?:\s*(\d+)\s*mo(?:nths?)?)?`,
-	`(?:\s*(\d+)\s*w(?:(?:eeks?)|k)?)?`,
-	`(?:\s*(\d+)\s*d(?:ays?)?)?`,
-	`(?:\s*(\d+)\s*h(?:(?:ours?)|r)?)?`,
-	`(?:\s*(\d+)\s*m(?:in(?:utes?)?)?)?`,
-	`(?:\s*(\d+)\s*s(?:ec(?:onds?)?)?)?`,
-	`\s*$`,
-}, ""))
+// operatorProperties lists the properties accepted by each operator, except
+// $map, $filter, $find and $sort whose each(..) and by(..) properties are
+// checked by pattern
+var operatorProperties = map[string][]string{
+	"$eval":        {"$eval"},
+	"$flatten":     {"$flatten"},
+	"$flattenDeep": {"$flattenDeep"},
+	"$fromNow":     {"$fromNow", "from"},
+	"$if":          {"$if", "then", "else"},
+	"$json":        {"$json"},
+	"$let":         {"$let", "in"},
+	"$match":       {"$match"},
+	"$switch":      {"$switch"},
+	"$merge":       {"$merge"},
+	"$mergeDeep":   {"$mergeDeep"},
+	"$reverse":     {"$reverse"},
+	"$$":           {"$$"},
+}
+
+// fromNowUnits lists the accepted spellings of each unit, in the order the
+// units must appear, along with its length in seconds
+var fromNowUnits = []struct {
+	spellings []string
+	seconds   int
+}{
+	{[]string{"y", "yr", "yrs", "year", "years"}, 365 * 24 * 60 * 60},
+	{[]string{"mo", "mos", "month", "months"}, 30 * 24 * 60 * 60},
+	{[]string{"w", "wk", "wks", "week", "weeks"}, 7 * 24 * 60 * 60},
+	{[]string{"d", "day", "days"}, 24 * 60 * 60},
+	{[]string{"h", "hr", "hrs", "hour", "hours"}, 60 * 60},
+	{[]string{"m", "min", "mins", "minute", "minutes"}, 60},
+	{[]string{"s", "sec", "secs", "second", "seconds"}, 1},
+}
+
+var (
+	fromNowSignPattern = regexp.MustCompile(`^\s*([-+])`)
+	fromNowTermPattern = regexp.MustCompile(`^\s*(\d+)\s*([a-zA-Z]+)`)
+)
 
 const timeFormat = "2006-01-02T15:04:05.000Z"
 
@@ -101,32 +471,40 @@ func fromNow(s string, reference time.Time) (string, error) {
 		reference = time.Now()
 	}
 
-	m := fromNowPattern.FindStringSubmatch(s)
-	if m == nil {
-		return "", fmt.Errorf("invalid time expression passed to fromNow('%s')", s)
+	neg := 1
+	rest := s
+	if m := fromNowSignPattern.FindStringSubmatch(rest); m != nil {
+		if m[1] == "-" {
+			neg = -1
+		}
+		rest = rest[len(m[0]):]
 	}
 
-	neg := 1
-	if m[1] == "-" {
-		neg = -1
-	}
-	years, _ := strconv.Atoi(m[2])
-	months, _ := strconv.Atoi(m[3])
-	weeks, _ := strconv.Atoi(m[4])
-	days, _ := strconv.Atoi(m[5])
-	hours, _ := strconv.Atoi(m[6])
-	minutes, _ := strconv.Atoi(m[7])
-	seconds, _ := strconv.Atoi(m[8])
-
-	// Sum up to days
-	days += 365 * years
-	days += 30 * months
-	days += 7 * weeks
-
-	// Sum up to hours, minutes, seconds
-	hours += 24 * days
-	minutes += 60 * hours
-	seconds += 60 * minutes
+	// Parse each term, each with a unit after that of the term before it
+	seconds := 0
+	next := 0
+	for strings.TrimSpace(rest) != "" {
+		m := fromNowTermPattern.FindStringSubmatch(rest)
+		if m == nil {
+			return "", fmt.Errorf("invalid time expression '%s'", s)
+		}
+		index := -1
+		for n, unit := range fromNowUnits {
+			if parser.StringsContains(strings.ToLower(m[2]), unit.spellings) {
+				index = n
+			}
+		}
+		if index == -1 {
+			return "", fmt.Errorf("unknown time unit '%s' in time expression '%s'", m[2], s)
+		}
+		if index < next {
+			return "", fmt.Errorf("invalid time expression '%s'", s)
+		}
+		count, _ := strconv.Atoi(m[1])
+		seconds += count * fromNowUnits[index].seconds
+		next = index + 1
+		rest = rest[len(m[0]):]
+	}
 
 	result := reference.Add(time.Duration(seconds*neg) * time.Second)
 
@@ -134,26 +512,69 @@ func fromNow(s string, reference time.Time) (string, error) {
 }
 
 var builtin = map[string]interface{}{
-	"min": i.WrapFunction(func(n float64, m ...float64) float64 {
-		for _, v := range m {
+	"min": i.WrapFunction(func(args ...interface{}) (float64, error) {
+		numbers, err := extremeArguments("min", args)
+		if err != nil {
+			return 0, err
+		}
+		n := numbers[0]
+		for _, v := range numbers[1:] {
 			if v < n {
 				n = v
 			}
 		}
-		return n
+		return n, nil
 	}),
-	"max": i.WrapFunction(func(n float64, m ...float64) float64 {
-		for _, v := range m {
+	"max": i.WrapFunction(func(args ...interface{}) (float64, error) {
+		numbers, err := extremeArguments("max", args)
+		if err != nil {
+			return 0, err
+		}
+		n := numbers[0]
+		for _, v := range numbers[1:] {
 			if v > n {
 				n = v
 			}
 		}
-		return n
+		return n, nil
+	}),
+	"sqrt":  i.WrapFunction(math.Sqrt),
+	"ceil":  i.WrapFunction(math.Ceil),
+	"floor": i.WrapFunction(math.Floor),
+	"abs":   i.WrapFunction(math.Abs),
+	"sign": i.WrapFunction(func(x float64) float64 {
+		switch {
+		case x > 0:
+			return 1
+		case x < 0:
+			return -1
+		}
+		return 0
+	}),
+	"round": i.WrapFunction(func(x float64, digits ...float64) (float64, error) {
+		// round half away from zero to the given number of decimal places
+		d := 0.0
+		if len(digits) > 1 {
+			return 0, fmt.Errorf("round(number, digits) takes at-most two arguments")
+		}
+		if len(digits) == 1 {
+			d = digits[0]
+		}
+		if d < 0 || d != math.Trunc(d) {
+			return 0, fmt.Errorf("round(number, digits) requires digits to be a non-negative integer")
+		}
+		factor := math.Pow(10, d)
+		if math.IsInf(factor, 0) || math.IsInf(x*factor, 0) {
+			return x, nil
+		}
+		return math.Round(x*factor) / factor, nil
+	}),
+	"clamp": i.WrapFunction(func(x, lo, hi float64) (float64, error) {
+		if lo > hi {
+			return 0, fmt.Errorf("clamp(value, lo, hi) requires lo to be at most hi")
+		}
+		return math.Min(math.Max(x, lo), hi), nil
 	}),
-	"sqrt":      i.WrapFunction(math.Sqrt),
-	"ceil":      i.WrapFunction(math.Ceil),
-	"floor":     i.WrapFunction(math.Floor),
-	"abs":       i.WrapFunction(math.Abs),
 	"lowercase": i.WrapFunction(strings.ToLower),
 	"uppercase": i.WrapFunction(strings.ToUpper),
 	"strip":     i.WrapFunction(strings.TrimSpace),
@@ -163,12 +584,37 @@ var builtin = map[string]interface{}{
 	"rstrip": i.WrapFunction(func(s string) string {
 		return strings.TrimRightFunc(s, unicode.IsSpace)
 	}),
+	"split": i.WrapFunction(func(s, sep string) []interface{} {
+		// an empty separator splits into characters, as strings.Split does
+		parts := strings.Split(s, sep)
+		result := make([]interface{}, len(parts))
+		for idx, part := range parts {
+			result[idx] = part
+		}
+		return result
+	}),
+	"join": i.WrapFunction(func(list []interface{}, sep string) (string, error) {
+		parts := make([]string, len(list))
+		for idx, v := range list {
+			switch val := v.(type) {
+			case string:
+				parts[idx] = val
+			case float64:
+				parts[idx] = formatNumber(val)
+			default:
+				return "", fmt.Errorf("join(array, separator) only joins strings and numbers")
+			}
+		}
+		return strings.Join(parts, sep), nil
+	}),
 	"str": i.WrapFunction(func(v interface{}) (string, error) {
 		switch val := v.(type) {
 		case string:
 			return val, nil
 		case float64:
-			return strconv.FormatFloat(val, 'f', -1, 64), nil
+			return formatNumber(val), nil
+		case json.Number:
+			return val.String(), nil
 		case bool:
 			return strconv.FormatBool(val), nil
 		case nil:
@@ -184,7 +630,7 @@ var builtin = map[string]interface{}{
 		switch v.(type) {
 		case string:
 			return "string"
-		case float64:
+		case float64, json.Number:
 			return "number"
 		case bool:
 			return "boolean"
@@ -201,6 +647,65 @@ var builtin = map[string]interface{}{
 			panic(fmt.Errorf("illegal data-type injected: %T", v))
 		}
 	}),
+	"flatten":     i.WrapFunction(flatten),
+	"flattenDeep": i.WrapFunction(flattenDeep),
+	"enumerate": i.WrapFunction(func(list []interface{}) []interface{} {
+		result := make([]interface{}, len(list))
+		for idx, v := range list {
+			result[idx] = []interface{}{float64(idx), v}
+		}
+		return result
+	}),
+	"zip": i.WrapFunction(func(first, second []interface{}, rest ...[]interface{}) []interface{} {
+		lists := append([][]interface{}{first, second}, rest...)
+		length := len(first)
+		for _, list := range lists[1:] {
+			if len(list) < length {
+				length = len(list)
+			}
+		}
+		result := make([]interface{}, length)
+		for idx := range result {
+			tuple := make([]interface{}, len(lists))
+			for n, list := range lists {
+				tuple[n] = list[idx]
+			}
+			result[idx] = tuple
+		}
+		return result
+	}),
+	"keys": i.WrapFunction(func(obj map[string]interface{}) []interface{} {
+		keys := sortedKeys(obj)
+		result := make([]interface{}, len(keys))
+		for idx, k := range keys {
+			result[idx] = k
+		}
+		return result
+	}),
+	"values": i.WrapFunction(func(obj map[string]interface{}) []interface{} {
+		keys := sortedKeys(obj)
+		result := make([]interface{}, len(keys))
+		for idx, k := range keys {
+			result[idx] = obj[k]
+		}
+		return result
+	}),
+	"merge": i.WrapFunction(func(first map[string]interface{}, rest ...map[string]interface{}) map[string]interface{} {
+		result := make(map[string]interface{}, len(first))
+		for _, obj := range append([]map[string]interface{}{first}, rest...) {
+			for k, v := range obj {
+				result[k] = v
+			}
+		}
+		return result
+	}),
+	"mergeDeep": i.WrapFunction(func(first map[string]interface{}, rest ...map[string]interface{}) interface{} {
+		result := interface{}(make(map[string]interface{}))
+		for _, obj := range append([]map[string]interface{}{first}, rest...) {
+			result = mergeDeep(result, obj)
+		}
+		return result
+	}),
 	"len": i.WrapFunction(func(v interface{}) (float64, error) {
 		switch val := v.(type) {
 		case string:
@@ -210,6 +715,58 @@ var builtin = map[string]interface{}{
 		}
 		return 0, fmt.Errorf("len(value) only works on arrays and strings")
 	}),
+	"size": i.WrapFunction(func(v interface{}) (float64, error) {
+		switch val := v.(type) {
+		case string:
+			return float64(utf8.RuneCountInString(val)), nil
+		case []interface{}:
+			return float64(len(val)), nil
+		case map[string]interface{}:
+			return float64(len(val)), nil
+		}
+		return 0, fmt.Errorf("size(value) only works on objects, arrays and strings")
+	}),
+	"indexOf": i.WrapFunction(func(haystack, needle interface{}) (float64, error) {
+		switch h := haystack.(type) {
+		case []interface{}:
+			for idx, v := range h {
+				if i.DeepEquals(v, needle) {
+					return float64(idx), nil
+				}
+			}
+			return -1, nil
+		case string:
+			n, ok := needle.(string)
+			if !ok {
+				return 0, fmt.Errorf("indexOf(string, substring) requires a string to search for")
+			}
+			idx := strings.Index(h, n)
+			if idx == -1 {
+				return -1, nil
+			}
+			// count runes, as in indexing
+			return float64(utf8.RuneCountInString(h[:idx])), nil
+		}
+		return 0, fmt.Errorf("indexOf(value, needle) only works on arrays and strings")
+	}),
+	"reverse": i.WrapFunction(func(v interface{}) (interface{}, error) {
+		switch val := v.(type) {
+		case string:
+			// reverse runes, not bytes, so that characters stay intact
+			runes := []rune(val)
+			for l, r := 0, len(runes)-1; l < r; l, r = l+1, r-1 {
+				runes[l], runes[r] = runes[r], runes[l]
+			}
+			return string(runes), nil
+		case []interface{}:
+			result := make([]interface{}, len(val))
+			for idx, e := range val {
+				result[len(val)-1-idx] = e
+			}
+			return result, nil
+		}
+		return nil, fmt.Errorf("reverse(value) only works on arrays and strings")
+	}),
 	"fromNow": i.WrapFunctionWithContext(func(context map[string]interface{}, offset string, from ...string) (string, error) {
 		// We use variadic because golang doesn't support optional parameters
 		if len(from) > 1 {
@@ -233,17 +790,303 @@ var builtin = map[string]interface{}{
 		return fromNow(offset, ref)
 	}),
 	"defined": i.WrapFunctionWithContext(func(context map[string]interface{}, str string) bool {
+		// the per-render state is not a variable
 		_, ok := context[str]
-		return ok
+		return ok && str != stateKey
+	}),
+	"default": i.WrapLazyFunction(func(args []i.LazyArgument) (interface{}, error) {
+		if len(args) != 2 {
+			return nil, fmt.Errorf("default(value, fallback) takes exactly two arguments, but was given %d", len(args))
+		}
+		value, err := args[0]()
+		if e, ok := err.(parser.SyntaxError); ok && e.Variable != "" {
+			return args[1]()
+		}
+		return value, err
 	}),
 }
 
+// formatNumber formats a number the way JavaScript's Number.prototype.toString
+// does, so that all implementations produce the same strings
+func formatNumber(v float64) string {
+	switch {
+	case math.IsNaN(v):
+		return "NaN"
+	case math.IsInf(v, 1):
+		return "Infinity"
+	case math.IsInf(v, -1):
+		return "-Infinity"
+	case v == 0:
+		return "0"
+	}
+	sign := ""
+	if v < 0 {
+		sign = "-"
+		v = -v
+	}
+
+	// shortest digits that round-trip, the value is 0.<digits> * 10^n
+	s := strconv.FormatFloat(v, 'e', -1, 64)
+	e := strings.IndexByte(s, 'e')
+	digits := strings.Replace(s[:e], ".", "", 1)
+	exponent, _ := strconv.Atoi(s[e+1:])
+	k := len(digits)
+	n := exponent + 1
+
+	switch {
+	case k <= n && n <= 21:
+		return sign + digits + strings.Repeat("0", n-k)
+	case 0 < n && n <= 21:
+		return sign + digits[:n] + "." + digits[n:]
+	case -6 < n && n <= 0:
+		return sign + "0." + strings.Repeat("0", -n) + digits
+	}
+	mantissa := digits
+	if k > 1 {
+		mantissa = digits[:1] + "." + digits[1:]
+	}
+	if n > 0 {
+		return fmt.Sprintf("%s%se+%d", sign, mantissa, n-1)
+	}
+	return fmt.Sprintf("%s%se-%d", sign, mantissa, 1-n)
+}
+
+// extremeArguments returns the numbers given to min or max, either as the
+// arguments themselves or as the elements of a single array argument
+func extremeArguments(name string, args []interface{}) ([]float64, error) {
+	if len(args) == 1 {
+		if a, ok := args[0].([]interface{}); ok {
+			args = a
+		}
+	}
+	if len(args) == 0 {
+		return nil, fmt.Errorf("%s() expected at-least one number", name)
+	}
+	numbers := make([]float64, len(args))
+	for idx, arg := range args {
+		n, ok := arg.(float64)
+		if !ok {
+			return nil, fmt.Errorf("%s() only works on numbers or an array of numbers", name)
+		}
+		numbers[idx] = n
+	}
+	return numbers, nil
+}
+
+// unescapeLineSeparators writes the \u2028 and \u2029 escapes made by
+// encoding/json as the characters themselves, as JavaScript and Python do
+func unescapeLineSeparators(data string) string {
+	if !strings.Contains(data, `\u202`) {
+		return data
+	}
+	var result bytes.Buffer
+	for idx := 0; idx < len(data); idx++ {
+		switch {
+		case data[idx] != '\\':
+			result.WriteByte(data[idx])
+		// escapes are \x or \uXXXX, so a backslash never starts the next one
+		case strings.HasPrefix(data[idx:], `\u2028`):
+			result.WriteRune('\u2028')
+			idx += 5
+		case strings.HasPrefix(data[idx:], `\u2029`):
+			result.WriteRune('\u2029')
+			idx += 5
+		default:
+			result.WriteString(data[idx : idx+2])
+			idx++
+		}
+	}
+	return result.String()
+}
+
+// sortedKeys returns the keys of obj in lexicographic order
+func sortedKeys(obj map[string]interface{}) []string {
+	keys := make([]string, 0, len(obj))
+	for k := range obj {
+		keys = append(keys, k)
+	}
+	sort.Strings(keys)
+	return keys
+}
+
+// flatten concatenates the arrays in list, keeping other entries as they are
+func flatten(list []interface{}) []interface{} {
+	result := make([]interface{}, 0, len(list))
+	for _, entry := range list {
+		if a, ok := entry.([]interface{}); ok {
+			result = append(result, a...)
+		} else {
+			result = append(result, entry)
+		}
+	}
+	return result
+}
+
+// mergeDeep merges two values, preferring R but concatenating arrays and
+// recursively merging objects
+func mergeDeep(L, R interface{}) interface{} {
+	if l, ok := L.([]interface{}); ok {
+		if r, ok := R.([]interface{}); ok {
+			// copy, so that appending never writes into the backing array of l
+			return append(append(make([]interface{}, 0, len(l)+len(r)), l...), r...)
+		}
+	}
+	if l, ok := L.(map[string]interface{}); ok {
+		if r, ok := R.(map[string]interface{}); ok {
+			result := make(map[string]interface{}, len(l))
+			for k, v := range l {
+				result[k] = v
+			}
+			for k, v := range r {
+				if lv, ok := l[k]; ok {
+					result[k] = mergeDeep(lv, v)
+				} else {
+					result[k] = v
+				}
+			}
+			return result
+		}
+	}
+	return R
+}
+
+// flattenDeep recursively concatenates all arrays nested in list
+func flattenDeep(list []interface{}) []interface{} {
+	result := make([]interface{}, 0, len(list))
+	for _, entry := range list {
+		if a, ok := entry.([]interface{}); ok {
+			result = append(result, flattenDeep(a)...)
+		} else {
+			result = append(result, entry)
+		}
+	}
+	return result
+}
+
 var eachKeyPattern = regexp.MustCompile(`^each\(([a-zA-Z_][a-zA-Z0-9_]*)(,\s*([a-zA-Z_][a-zA-Z0-9_]*))?\)$`)
 var byKeyPattern = regexp.MustCompile(`^by\(([a-zA-Z_][a-zA-Z0-9_]*)\)$`)
 
+// mapTemplate holds the parts of a $map template, or the like for another
+// operator taking each(..), needed to render its elements
+type mapTemplate struct {
+	value      interface{}
+	identifier string
+	index      string
+	each       interface{}
+}
+
+// parseMap checks a template for operator, which is $map or another operator
+// taking each(..), and renders its value
+func parseMap(operator string, template, context map[string]interface{}) (*mapTemplate, error) {
+	value, err := render(template[operator], context)
+	if err != nil {
+		return nil, err
+	}
+	if len(template) != 2 {
+		return nil, TemplateError{
+			Message:  operator + " must have exactly two properties",
+			Template: template,
+		}
+	}
+	// Find the each(...) key
+	var eachKey string
+	for k := range template {
+		if k == operator {
+			continue
+		}
+		eachKey = k
+	}
+	// Validate against each(...) key pattern
+	m := eachKeyPattern.FindStringSubmatch(eachKey)
+	if m == nil {
+		return nil, TemplateError{
+			Message:  operator + " requires a property on the form 'each(identifier)'",
+			Template: template,
+		}
+	}
+	return &mapTemplate{
+		value:      value,
+		identifier: m[1],
+		index:      m[3],
+		each:       template[eachKey],
+	}, nil
+}
+
+// forEach renders the each template for the entries of list, calling emit with
+// every result that isn't deleted
+func (m *mapTemplate) forEach(list []interface{}, context map[string]interface{}, emit func(interface{}) error) error {
+	// copy the context once, each entry only replaces the each(..) variables
+	c := make(map[string]interface{}, len(context)+2)
+	for k, v := range context {
+		c[k] = v
+	}
+	for idx, entry := range list {
+		c[m.identifier] = entry
+		if len(m.index) > 0 {
+			c[m.index] = float64(idx)
+		}
+		r, err := render(m.each, c)
+		if err != nil {
+			return err
+		}
+		if r == deleteMarker {
+			continue
+		}
+		if err := emit(r); err != nil {
+			return err
+		}
+	}
+	return nil
+}
+
+// parseCondition checks a $filter or $find template and renders its value,
+// returning the value and a function telling whether the each(..) expression
+// is truthy for an element and its index or key
+func parseCondition(operator string, template, context map[string]interface{}) (interface{}, func(value, key interface{}) (bool, error), error) {
+	m, err := parseMap(operator, template, context)
+	if err != nil {
+		return nil, nil, err
+	}
+	expr, ok := m.each.(string)
+	if !ok {
+		return nil, nil, TemplateError{
+			Message:  operator + " each(..) can evaluate string expressions only",
+			Template: template,
+		}
+	}
+	// like $map, each(x) over an object gives x the entries as {key, val}
+	_, entries := m.value.(map[string]interface{})
+	entries = entries && len(m.index) == 0
+	// copy the context once, each entry only replaces the each(..) variables
+	c := make(map[string]interface{}, len(context)+2)
+	for k, v := range context {
+		c[k] = v
+	}
+	matches := func(value, key interface{}) (bool, error) {
+		if entries {
+			c[m.identifier] = map[string]interface{}{"key": key, "val": value}
+		} else {
+			c[m.identifier] = value
+		}
+		if len(m.index) > 0 {
+			c[m.index] = key
+		}
+		val, err := evaluate(c, expr)
+		if err != nil {
+			return false, TemplateError{
+				Message:  err.Error(),
+				Template: template,
+				cause:    err,
+			}
+		}
+		return i.IsTruthy(val), nil
+	}
+	return m.value, matches, nil
+}
+
 var operators = map[string]operator{
 	"$eval": func(template, context map[string]interface{}) (interface{}, error) {
-		if err := restrictProperties(template, "$eval"); err != nil {
+		if err := restrictProperties(template, operatorProperties["$eval"]...); err != nil {
 			return nil, err
 		}
 		s, ok := template["$eval"].(string)
@@ -253,17 +1096,18 @@ var operators = map[string]operator{
 				Template: template,
 			}
 		}
-		value, err := i.Parse(s, context)
+		value, err := evaluate(context, s)
 		if err != nil {
 			return nil, TemplateError{
 				Message:  err.Error(),
 				Template: template,
+				cause:    err,
 			}
 		}
 		return value, nil
 	},
 	"$flatten": func(template, context map[string]interface{}) (interface{}, error) {
-		if err := restrictProperties(template, "$flatten"); err != nil {
+		if err := restrictProperties(template, operatorProperties["$flatten"]...); err != nil {
 			return nil, err
 		}
 		value, err := render(template["$flatten"], context)
@@ -277,18 +1121,10 @@ var operators = map[string]operator{
 				Template: template,
 			}
 		}
-		result := make([]interface{}, 0, len(a))
-		for _, entry := range a {
-			if a2, ok := entry.([]interface{}); ok {
-				result = append(result, a2...)
-			} else {
-				result = append(result, entry)
-			}
-		}
-		return result, nil
+		return flatten(a), nil
 	},
 	"$flattenDeep": func(template, context map[string]interface{}) (interface{}, error) {
-		if err := restrictProperties(template, "$flattenDeep"); err != nil {
+		if err := restrictProperties(template, operatorProperties["$flattenDeep"]...); err != nil {
 			return nil, err
 		}
 		value, err := render(template["$flattenDeep"], context)
@@ -302,22 +1138,10 @@ var operators = map[string]operator{
 				Template: template,
 			}
 		}
-		result := make([]interface{}, 0, len(a))
-		var flatten func(list []interface{})
-		flatten = func(list []interface{}) {
-			for _, entry := range list {
-				if subentry, ok := entry.([]interface{}); ok {
-					flatten(subentry)
-				} else {
-					result = append(result, entry)
-				}
-			}
-		}
-		flatten(a)
-		return result, nil
+		return flattenDeep(a), nil
 	},
 	"$fromNow": func(template, context map[string]interface{}) (interface{}, error) {
-		if err := restrictProperties(template, "$fromNow", "from"); err != nil {
+		if err := restrictProperties(template, operatorProperties["$fromNow"]...); err != nil {
 			return nil, err
 		}
 
@@ -368,26 +1192,28 @@ var operators = map[string]operator{
 			return nil, TemplateError{
 				Message:  err.Error(),
 				Template: template,
+				cause:    err,
 			}
 		}
 		return result, nil
 	},
 	"$if": func(template, context map[string]interface{}) (interface{}, error) {
-		if err := restrictProperties(template, "$if", "then", "else"); err != nil {
+		if err := restrictProperties(template, operatorProperties["$if"]...); err != nil {
 			return nil, err
 		}
 		s, ok := template["$if"].(string)
 		if !ok {
 			return nil, TemplateError{
-				Message:  "$if expects a string expression",
+				Message:  "$if can evaluate string expressions only",
 				Template: template,
 			}
 		}
-		val, err := i.Parse(s, context)
+		val, err := evaluate(context, s)
 		if err != nil {
 			return nil, TemplateError{
 				Message:  err.Error(),
 				Template: template,
+				cause:    err,
 			}
 		}
 		var result interface{}
@@ -402,7 +1228,7 @@ var operators = map[string]operator{
 		return render(result, context)
 	},
 	"$json": func(template, context map[string]interface{}) (interface{}, error) {
-		if err := restrictProperties(template, "$json"); err != nil {
+		if err := restrictProperties(template, operatorProperties["$json"]...); err != nil {
 			return nil, err
 		}
 		val, err := render(template["$json"], context)
@@ -415,39 +1241,90 @@ var operators = map[string]operator{
 				Template: template,
 			}
 		}
-		data, _ := json.Marshal(val)
-		return string(data), nil
-	},
-	"$let": func(template, context map[string]interface{}) (interface{}, error) {
-		if err := restrictProperties(template, "$let", "in"); err != nil {
-			return nil, err
-		}
-		_, ok := template["$let"].(map[string]interface{})
-		if !ok {
+		// unlike json.Marshal, don't escape <, > and &, as JavaScript doesn't
+		var data bytes.Buffer
+		encoder := json.NewEncoder(&data)
+		encoder.SetEscapeHTML(false)
+		if err := encoder.Encode(val); err != nil {
 			return nil, TemplateError{
-				Message:  "$let expects an object",
+				Message:  err.Error(),
 				Template: template,
+				cause:    err,
 			}
 		}
+		return unescapeLineSeparators(strings.TrimSuffix(data.String(), "\n")), nil
+	},
+	"$let": func(template, context map[string]interface{}) (interface{}, error) {
+		if err := restrictProperties(template, operatorProperties["$let"]...); err != nil {
+			return nil, err
+		}
 		c := make(map[string]interface{}, len(context))
 		for k, v := range context {
 			c[k] = v
 		}
-		var err error
 
-		r, err := render(template["$let"], context)
-		if err != nil {
-			return nil, err
-		}
-		rv, ok := r.(map[string]interface{})
-		if ok {
-			for k, v := range rv {
-				c[k] = v
+		if bindings, ok := template["$let"].([]interface{}); ok {
+			// an array of binding objects, each rendered in the scope of those before it
+			defined := make(map[string]bool)
+			for _, binding := range bindings {
+				r, err := render(binding, c)
+				if err != nil {
+					return nil, err
+				}
+				rv, ok := r.(map[string]interface{})
+				if !ok {
+					return nil, TemplateError{
+						Message:  "$let array elements must evaluate to objects",
+						Template: template,
+					}
+				}
+				if err = i.IsValidContext(rv); err != nil {
+					return nil, TemplateError{
+						Message:  err.Error(),
+						Template: template,
+						cause:    err,
+					}
+				}
+				for k, v := range rv {
+					if defined[k] {
+						return nil, TemplateError{
+							Message:  fmt.Sprintf("$let binding '%s' is defined more than once", k),
+							Template: template,
+						}
+					}
+					defined[k] = true
+					c[k] = v
+				}
 			}
 		} else {
-			return nil, TemplateError{
-				Message:  "$let expects an object",
-				Template: template,
+			_, ok := template["$let"].(map[string]interface{})
+			if !ok {
+				return nil, TemplateError{
+					Message:  "$let expects an object",
+					Template: template,
+				}
+			}
+
+			r, err := render(template["$let"], context)
+			if err != nil {
+				return nil, err
+			}
+			rv, ok := r.(map[string]interface{})
+			if !ok {
+				return nil, TemplateError{
+					Message:  "$let expects an object",
+					Template: template,
+				}
+			}
+			if err = i.IsValidContext(rv); err != nil {
+				return nil, TemplateError{
+					Message:  err.Error(),
+					Template: template,
+					cause:    err,
+				}
+			}
+			for k, v := range rv {
+				c[k] = v
 			}
 		}
 
@@ -458,77 +1335,36 @@ var operators = map[string]operator{
 				Template: template,
 			}
 		}
-		if err = i.IsValidContext(c); err != nil {
-			return nil, TemplateError{
-				Message:  err.Error(),
-				Template: template,
-			}
-		}
 		return render(in, c)
 	},
 	"$map": func(template, context map[string]interface{}) (interface{}, error) {
-		value, err := render(template["$map"], context)
+		m, err := parseMap("$map", template, context)
 		if err != nil {
 			return nil, err
 		}
-		if len(template) != 2 {
-			return nil, TemplateError{
-				Message:  "$map must have exactly two properties",
-				Template: template,
-			}
-		}
-		// Find the each(...) key
-		var eachKey string
-		for k := range template {
-			if k == "$map" {
-				continue
-			}
-			eachKey = k
-		}
-		// Validate against each(...) key pattern
-		m := eachKeyPattern.FindStringSubmatch(eachKey)
-		if m == nil {
-			return nil, TemplateError{
-				Message:  "$map requires a property on the form 'each(identifier)'",
-				Template: template,
-			}
-		}
-		eachIdentifier := m[1]
-		eachIndex := m[3]
-		additionalContextVars := 1
-		if len(eachIndex) > 0 {
-			additionalContextVars = 2
-		}
-		eachTemplate := template[eachKey]
-		switch val := value.(type) {
+		eachIdentifier, eachIndex, eachTemplate := m.identifier, m.index, m.each
+		switch val := m.value.(type) {
 		case []interface{}:
-			var result []interface{}
-			for idx, entry := range val {
-				c := make(map[string]interface{}, len(context)+additionalContextVars)
-				for k, v := range context {
-					c[k] = v
-				}
-				c[eachIdentifier] = entry
-				if len(eachIndex) > 0 {
-					c[eachIndex] = float64(idx)
-				}
-				r, err := render(eachTemplate, c)
-				if err != nil {
-					return nil, err
-				}
-				if r == deleteMarker {
-					continue
-				}
+			result := make([]interface{}, 0, len(val))
+			err := m.forEach(val, context, func(r interface{}) error {
 				result = append(result, r)
+				return nil
+			})
+			if err != nil {
+				return nil, err
+			}
+			if err := countElements(context, len(result)); err != nil {
+				return nil, err
 			}
 			return result, nil
 		case map[string]interface{}:
 			result := make(map[string]interface{})
+			// copy the context once, each entry only replaces the each(..) variables
+			c := make(map[string]interface{}, len(context)+2)
+			for k, v := range context {
+				c[k] = v
+			}
 			for K, V := range val {
-				c := make(map[string]interface{}, len(context)+additionalContextVars)
-				for k, v := range context {
-					c[k] = v
-				}
 				if len(eachIndex) > 0 {
 					c[eachIdentifier] = V
 					c[eachIndex] = K
@@ -543,30 +1379,99 @@ var operators = map[string]operator{
 				if err != nil {
 					return nil, err
 				}
-				R, ok := r.(map[string]interface{})
-				if !ok {
-					return nil, TemplateError{
-						Message:  fmt.Sprintf("$map on objects expects 'each(%s)' to evaluate to an object", eachIdentifier),
-						Template: eachTemplate,
-					}
-				}
-				for k, v := range R {
-					if v == deleteMarker {
-						continue
-					}
+				R, ok := r.(map[string]interface{})
+				if !ok {
+					return nil, TemplateError{
+						Message:  fmt.Sprintf("$map on objects expects 'each(%s)' to evaluate to an object", eachIdentifier),
+						Template: eachTemplate,
+					}
+				}
+				for k, v := range R {
+					if v == deleteMarker {
+						continue
+					}
+					if _, exists := result[k]; exists {
+						return nil, TemplateError{
+							Message:  fmt.Sprintf("$map on objects produced duplicate key \"%s\"", k),
+							Template: eachTemplate,
+						}
+					}
+					result[k] = v
+				}
+			}
+			if err := countElements(context, len(result)); err != nil {
+				return nil, err
+			}
+			return result, nil
+		default:
+			return nil, TemplateError{
+				Message:  "$map requires a value that evaluates to either an object or an array",
+				Template: template,
+			}
+		}
+	},
+	"$filter": func(template, context map[string]interface{}) (interface{}, error) {
+		value, matches, err := parseCondition("$filter", template, context)
+		if err != nil {
+			return nil, err
+		}
+		switch val := value.(type) {
+		case []interface{}:
+			result := make([]interface{}, 0, len(val))
+			for idx, v := range val {
+				ok, err := matches(v, float64(idx))
+				if err != nil {
+					return nil, err
+				}
+				if ok {
+					result = append(result, v)
+				}
+			}
+			return result, nil
+		case map[string]interface{}:
+			result := make(map[string]interface{})
+			for k, v := range val {
+				ok, err := matches(v, k)
+				if err != nil {
+					return nil, err
+				}
+				if ok {
 					result[k] = v
 				}
 			}
 			return result, nil
 		default:
 			return nil, TemplateError{
-				Message:  "$map requires a value that evaluates to either an object or an array",
+				Message:  "$filter value must evaluate to an array or object",
+				Template: template,
+			}
+		}
+	},
+	"$find": func(template, context map[string]interface{}) (interface{}, error) {
+		value, matches, err := parseCondition("$find", template, context)
+		if err != nil {
+			return nil, err
+		}
+		list, ok := value.([]interface{})
+		if !ok {
+			return nil, TemplateError{
+				Message:  "$find value must evaluate to an array",
 				Template: template,
 			}
 		}
+		for idx, v := range list {
+			ok, err := matches(v, float64(idx))
+			if err != nil {
+				return nil, err
+			}
+			if ok {
+				return v, nil
+			}
+		}
+		return deleteMarker, nil
 	},
 	"$match": func(template, context map[string]interface{}) (interface{}, error) {
-		if err := restrictProperties(template, "$match"); err != nil {
+		if err := restrictProperties(template, operatorProperties["$match"]...); err != nil {
 			return nil, err
 		}
 
@@ -588,11 +1493,12 @@ var operators = map[string]operator{
 		result := make([]interface{}, 0, len(match))
 
 		for _, key := range conditions {
-			check, err := i.Parse(key, context)
+			check, err := evaluate(context, key)
 			if err != nil {
 				return nil, TemplateError{
 					Message:  err.Error(),
 					Template: template,
+					cause:    err,
 				}
 			}
 
@@ -603,6 +1509,7 @@ var operators = map[string]operator{
 					return nil, TemplateError{
 						Message:  err.Error(),
 						Template: template,
+						cause:    err,
 					}
 				}
 				result = append(result, r)
@@ -612,7 +1519,7 @@ var operators = map[string]operator{
 		return result, nil
 	},
 	"$switch": func(template, context map[string]interface{}) (interface{}, error) {
-		if err := restrictProperties(template, "$switch"); err != nil {
+		if err := restrictProperties(template, operatorProperties["$switch"]...); err != nil {
 			return nil, err
 		}
 
@@ -626,17 +1533,21 @@ var operators = map[string]operator{
 
 		conditions := make([]string, 0, len(match))
 		for condition := range match {
+			if condition == "$default" {
+				continue
+			}
 			conditions = append(conditions, condition)
 		}
 
 		result := make([]interface{}, 0, len(match))
 
 		for _, key := range conditions {
-			check, err := i.Parse(key, context)
+			check, err := evaluate(context, key)
 			if err != nil {
 				return nil, TemplateError{
 					Message:  err.Error(),
 					Template: template,
+					cause:    err,
 				}
 			}
 
@@ -647,6 +1558,7 @@ var operators = map[string]operator{
 					return nil, TemplateError{
 						Message:  err.Error(),
 						Template: template,
+						cause:    err,
 					}
 				}
 				result = append(result, r)
@@ -660,6 +1572,18 @@ var operators = map[string]operator{
 			}
 		}
 
+		if value, ok := match["$default"]; ok && len(result) == 0 {
+			r, err := render(value, context)
+			if err != nil {
+				return nil, TemplateError{
+					Message:  err.Error(),
+					Template: template,
+					cause:    err,
+				}
+			}
+			result = append(result, r)
+		}
+
 		if len(result) == 0 {
 			return deleteMarker, nil
 		}
@@ -667,7 +1591,7 @@ var operators = map[string]operator{
 		return result[0], nil
 	},
 	"$merge": func(template, context map[string]interface{}) (interface{}, error) {
-		if err := restrictProperties(template, "$merge"); err != nil {
+		if err := restrictProperties(template, operatorProperties["$merge"]...); err != nil {
 			return nil, err
 		}
 		value, err := render(template["$merge"], context)
@@ -697,7 +1621,7 @@ var operators = map[string]operator{
 		return result, nil
 	},
 	"$mergeDeep": func(template, context map[string]interface{}) (interface{}, error) {
-		if err := restrictProperties(template, "$mergeDeep"); err != nil {
+		if err := restrictProperties(template, operatorProperties["$mergeDeep"]...); err != nil {
 			return nil, err
 		}
 		value, err := render(template["$mergeDeep"], context)
@@ -711,31 +1635,6 @@ var operators = map[string]operator{
 				Template: template,
 			}
 		}
-		var merge func(L, R interface{}) interface{}
-		merge = func(L, R interface{}) interface{} {
-			if l, ok := L.([]interface{}); ok {
-				if r, ok := R.([]interface{}); ok {
-					return append(l, r...)
-				}
-			}
-			if l, ok := L.(map[string]interface{}); ok {
-				if r, ok := R.(map[string]interface{}); ok {
-					result := make(map[string]interface{}, len(l))
-					for k, v := range l {
-						result[k] = v
-					}
-					for k, v := range r {
-						if lv, ok := l[k]; ok {
-							result[k] = merge(lv, v)
-						} else {
-							result[k] = v
-						}
-					}
-					return result
-				}
-			}
-			return R
-		}
 		result := interface{}(make(map[string]interface{}))
 		for _, entry := range a {
 			obj, ok := entry.(map[string]interface{})
@@ -745,12 +1644,12 @@ var operators = map[string]operator{
 					Template: template,
 				}
 			}
-			result = merge(result, obj)
+			result = mergeDeep(result, obj)
 		}
 		return result, nil
 	},
 	"$reverse": func(template, context map[string]interface{}) (interface{}, error) {
-		if err := restrictProperties(template, "$reverse"); err != nil {
+		if err := restrictProperties(template, operatorProperties["$reverse"]...); err != nil {
 			return nil, err
 		}
 		value, err := render(template["$reverse"], context)
@@ -782,23 +1681,40 @@ var operators = map[string]operator{
 				Template: template,
 			}
 		}
-		if len(template) > 2 {
+		reverse := false
+		if r, ok := template["reverse"]; ok {
+			rv, err := render(r, context)
+			if err != nil {
+				return nil, err
+			}
+			if reverse, ok = rv.(bool); !ok {
+				return nil, TemplateError{
+					Message:  "$sort reverse must be a boolean",
+					Template: template,
+				}
+			}
+		}
+		properties := len(template)
+		if _, ok := template["reverse"]; ok {
+			properties--
+		}
+		if properties > 2 {
 			return nil, TemplateError{
-				Message:  "$sort can have at-most two properties",
+				Message:  "only one by(..) is allowed",
 				Template: template,
 			}
 		}
 
 		// Find values to sort by
 		byValues := make([]interface{}, len(items))
-		if len(template) == 1 {
+		if properties == 1 {
 			// If we have no by(...) key, we just take the items themselves
 			copy(byValues, items)
 		} else {
 			// Find the by(...) key
 			var byKey string
 			for k := range template {
-				if k == "$sort" {
+				if k == "$sort" || k == "reverse" {
 					continue
 				}
 				byKey = k
@@ -815,66 +1731,57 @@ var operators = map[string]operator{
 			byExpr, ok := template[byKey].(string)
 			if !ok {
 				return nil, TemplateError{
-					Message:  "$sort requires that a string expression be passed to the 'by(identifier)' property",
+					Message:  "$sort by(..) can evaluate string expressions only",
 					Template: template,
 				}
 			}
 			// Find the byValues
+			c := make(map[string]interface{}, len(context)+1)
+			for k, v := range context {
+				c[k] = v
+			}
 			for j, item := range items {
-				c := make(map[string]interface{}, len(context)+1)
-				for k, v := range context {
-					c[k] = v
-				}
 				c[byIdentifier] = item
-				val, err := i.Parse(byExpr, c)
+				val, err := evaluate(c, byExpr)
 				if err != nil {
 					return nil, TemplateError{
 						Message:  err.Error(),
 						Template: template,
+						cause:    err,
 					}
 				}
 				byValues[j] = val
 			}
 		}
 
-		// Check that byValues are all integers or strings
+		// Check that byValues are all numbers or strings, or arrays of them
+		// when given by(...)
 		if len(byValues) == 0 {
 			return []interface{}{}, nil
 		}
-		mixedTypes := false
-		switch byValues[0].(type) {
-		case float64:
-			for _, val := range byValues {
-				if _, ok := val.(float64); !ok {
-					mixedTypes = true
-					break
-				}
-			}
-		case string:
-			for _, val := range byValues {
-				if _, ok := val.(string); !ok {
-					mixedTypes = true
-					break
-				}
-			}
-		default:
-			return nil, TemplateError{
-				Message:  "$sort can only operate on strings and numbers, add a 'by(identifier)' to sort by a key",
-				Template: template,
-			}
-		}
-		if mixedTypes {
+		if msg := checkSortKeys(byValues, properties == 2); msg != "" {
 			return nil, TemplateError{
-				Message:  "$sort cannot handle mixed types, tweak the 'by(identifier)' property to conform values",
+				Message:  msg,
 				Template: template,
 			}
 		}
 
-		// Sort items
+		// Sort items, keeping the order of items with equal byValues
 		items = append([]interface{}(nil), items...)
-		sort.Sort(sortable{items, byValues})
+		if reverse {
+			sort.Stable(sort.Reverse(sortable{items, byValues}))
+		} else {
+			sort.Stable(sortable{items, byValues})
+		}
 		return items, nil
 	},
+	// {"$$": value} is value itself, with nothing inside it rendered
+	"$$": func(template, context map[string]interface{}) (interface{}, error) {
+		if err := restrictProperties(template, operatorProperties["$$"]...); err != nil {
+			return nil, err
+		}
+		return template["$$"], nil
+	},
 }
 
 type sortable struct {
@@ -892,24 +1799,103 @@ func (s sortable) Swap(i, j int) {
 }
 
 func (s sortable) Less(i, j int) bool {
-	if I, ok := s.ByValues[i].(string); ok {
-		if J, ok := s.ByValues[j].(string); ok {
-			return strings.Compare(I, J) == -1
+	return compareSortKeys(s.ByValues[i], s.ByValues[j]) < 0
+}
+
+// checkSortKeys returns why keys cannot be sorted, or an empty string if they
+// are all numbers, all strings or, with arrays, all arrays of keys where the
+// keys at each position are all numbers or all strings
+func checkSortKeys(keys []interface{}, arrays bool) string {
+	if _, ok := keys[0].([]interface{}); ok && arrays {
+		var columns [][]interface{}
+		for _, key := range keys {
+			elements, ok := key.([]interface{})
+			if !ok {
+				return "$sort cannot handle mixed types, tweak the 'by(identifier)' property to conform values"
+			}
+			for idx, element := range elements {
+				if idx == len(columns) {
+					columns = append(columns, nil)
+				}
+				columns[idx] = append(columns[idx], element)
+			}
 		}
+		for _, column := range columns {
+			if msg := checkSortKeys(column, false); msg != "" {
+				return msg
+			}
+		}
+		return ""
+	}
+	switch keys[0].(type) {
+	case float64:
+		for _, key := range keys {
+			if _, ok := key.(float64); !ok {
+				return "$sort cannot handle mixed types, tweak the 'by(identifier)' property to conform values"
+			}
+		}
+	case string:
+		for _, key := range keys {
+			if _, ok := key.(string); !ok {
+				return "$sort cannot handle mixed types, tweak the 'by(identifier)' property to conform values"
+			}
+		}
+	default:
+		return "$sort can only operate on strings and numbers, add a 'by(identifier)' to sort by a key"
 	}
-	if I, ok := s.ByValues[i].(float64); ok {
-		if J, ok := s.ByValues[j].(float64); ok {
-			return I < J
+	return ""
+}
+
+// compareSortKeys returns a negative number, zero or a positive number as a is
+// less than, equal to or greater than b; arrays of keys are compared
+// lexicographically, so a shorter array sorts before any it is a prefix of
+func compareSortKeys(a, b interface{}) int {
+	switch A := a.(type) {
+	case string:
+		if B, ok := b.(string); ok {
+			return strings.Compare(A, B)
+		}
+	case float64:
+		if B, ok := b.(float64); ok {
+			switch {
+			case A < B:
+				return -1
+			case A > B:
+				return 1
+			}
+			return 0
+		}
+	case []interface{}:
+		if B, ok := b.([]interface{}); ok {
+			for idx := 0; idx < len(A) && idx < len(B); idx++ {
+				if c := compareSortKeys(A[idx], B[idx]); c != 0 {
+					return c
+				}
+			}
+			return len(A) - len(B)
 		}
 	}
-	panic(fmt.Sprintf("sortable expected strings or float64 by found: %T and %T", s.ByValues[i], s.ByValues[j]))
+	panic(fmt.Sprintf("sortable expected strings or float64 by found: %T and %T", a, b))
 }
 
 var interpolationPattern = regexp.MustCompile(`\$?\${`)
 
 func interpolate(template string, context map[string]interface{}) (string, error) {
+	value, err := interpolateValue(template, context, false)
+	if err != nil {
+		return "", err
+	}
+	return value.(string), nil
+}
+
+// interpolateValue interpolates ${..} expressions in template. With allowValue,
+// a template that consists of a single ${..} evaluates to the array or object
+// it produces.
+func interpolateValue(template string, context map[string]interface{}, allowValue bool) (interface{}, error) {
 	result := ""
 	remaining := template
+	state := context[stateKey].(*renderState)
+	parsed := state.parsed.Template(template)
 	for {
 		loc := interpolationPattern.FindStringIndex(remaining)
 		if loc == nil {
@@ -919,22 +1905,48 @@ func interpolate(template string, context map[string]interface{}) (string, error
 
 		result += remaining[:offset]
 		if remaining[offset+1] != '$' {
-			value, end, err := i.ParseUntilTerminator(remaining, offset+2, "}", context)
+			_, end, err := parsed.Tree(remaining, offset+2, "}")
+			if err != nil {
+				return "", nestingLimit(err)
+			}
+			// the expression is between the ${ and the }
+			if err := state.checkExpressionLength(end - offset - 3); err != nil {
+				return "", err
+			}
+			value, _, err := parsed.ParseUntilTerminator(remaining, offset+2, "}", context, state.step)
 			if err != nil {
 				return "", err
 			}
+			expression := remaining[offset+2 : end-1]
 			remaining = remaining[end:]
 			switch v := value.(type) {
 			case string:
 				result += v
 			case float64:
-				result += strconv.FormatFloat(v, 'f', -1, 64)
+				result += formatNumber(v)
+			case json.Number:
+				result += v.String()
 			case bool:
 				result += strconv.FormatBool(v)
 			case nil:
 				// null, interpolates as empty string
+			case []interface{}, map[string]interface{}:
+				// the ${..} is the whole template if it spans from the first to the last character
+				if allowValue && offset == 0 && end == len(template) {
+					return value, nil
+				}
+				return "", TemplateError{
+					Message:  fmt.Sprintf("interpolation of '%s' produced an array or object", expression),
+					Template: template,
+				}
 			default:
-				return "", fmt.Errorf("cannot interpolate array/object in '%s'", template)
+				return "", TemplateError{
+					Message:  fmt.Sprintf("interpolation of '%s' produced a function", expression),
+					Template: template,
+				}
+			}
+			if err := state.checkInterpolationLength(len(result)); err != nil {
+				return "", err
 			}
 		} else {
 			result += "${"
@@ -942,9 +1954,28 @@ func interpolate(template string, context map[string]interface{}) (string, error
 		}
 	}
 
+	if err := state.checkInterpolationLength(len(result) + len(remaining)); err != nil {
+		return "", err
+	}
 	return result + remaining, nil
 }
 
+// renderNumber renders a number from a template decoded with
+// json.Decoder.UseNumber. It is a float64 as usual, unless that would lose
+// precision, in which case the json.Number passes through unchanged.
+func renderNumber(n json.Number) interface{} {
+	f, err := n.Float64()
+	if err != nil {
+		return n
+	}
+	exact, ok := new(big.Rat).SetString(n.String())
+	shortest, _ := new(big.Rat).SetString(strconv.FormatFloat(f, 'g', -1, 64))
+	if !ok || exact.Cmp(shortest) != 0 {
+		return n
+	}
+	return f
+}
+
 // Hack to work around golang limitation that render() can be referenced in the
 // definition of "operators" if "operators" references render().
 // Whether this is a compiler bug, or just done this way to keep golang simple
@@ -955,17 +1986,41 @@ func init() {
 	operatorsDefined = operators
 }
 
-var reservedIdentifiers = regexp.MustCompile(`^\$[a-zA-Z][a-zA-Z0-9]*$`)
+var reservedIdentifiers = regexp.MustCompile(`^\$[a-zA-Z_][a-zA-Z0-9_]*$`)
 
+// render renders a template, an array or object one level deeper, failing if
+// that exceeds the MaxDepth given in RenderOptions
 func render(template interface{}, context map[string]interface{}) (interface{}, error) {
+	switch template.(type) {
+	case []interface{}, map[string]interface{}:
+	default:
+		return renderInner(template, context)
+	}
+	state := context[stateKey].(*renderState)
+	if state.options.MaxDepth > 0 && state.depth >= state.options.MaxDepth {
+		return nil, limitExceeded(fmt.Sprintf("template nesting exceeds the maximum depth of %d", state.options.MaxDepth))
+	}
+	state.depth++
+	defer func() { state.depth-- }()
+	return renderInner(template, context)
+}
+
+func renderInner(template interface{}, context map[string]interface{}) (interface{}, error) {
 	if template == nil {
 		return nil, nil
 	}
 	switch v := template.(type) {
 	case float64, bool:
 		return v, nil
+	case json.Number:
+		return renderNumber(v), nil
 	case string:
-		return interpolate(v, context)
+		if context[stateKey].(*renderState).partial {
+			return renderPartially(v, context, func() (interface{}, error) {
+				return interpolateValue(v, context, true)
+			})
+		}
+		return interpolateValue(v, context, true)
 	case []interface{}:
 		result := make([]interface{}, 0, len(v))
 		for _, val := range v {
@@ -977,6 +2032,9 @@ func render(template interface{}, context map[string]interface{}) (interface{},
 				result = append(result, r)
 			}
 		}
+		if err := countElements(context, len(result)); err != nil {
+			return nil, err
+		}
 		return result, nil
 	case map[string]interface{}:
 		// Search for operator
@@ -994,11 +2052,17 @@ func render(template interface{}, context map[string]interface{}) (interface{},
 		}
 		// Apply operator
 		if op != nil {
+			if context[stateKey].(*renderState).partial {
+				return renderPartially(v, context, func() (interface{}, error) {
+					return op(v, context)
+				})
+			}
 			return op(v, context)
 		}
 
 		// Clone object
 		result := make(map[string]interface{}, len(v))
+		partial := context[stateKey].(*renderState).partial
 		for k, v := range v {
 
 			r, err := render(v, context)
@@ -1006,21 +2070,31 @@ func render(template interface{}, context map[string]interface{}) (interface{},
 				return nil, err
 			}
 			if r != deleteMarker {
-				if strings.HasPrefix(k, "$$") {
-					k = k[1:]
-				} else if reservedIdentifiers.MatchString(k) {
-					return nil, TemplateError{
-						Message:  fmt.Sprintf("'$%s' is reserved used '$$%s' instead", k, k),
-						Template: v,
-					}
+				key := k
+				k, err = renderKey(k, v, context)
+				if partial && (undefinedVariable(err) || err == nil && !isInertKey(k)) {
+					// leave the entry as it is, to be rendered again
+					result[key] = v
+					continue
 				}
-				k, err = interpolate(k, context)
 				if err != nil {
 					return nil, err
 				}
+				// keys written out in the template are distinct, so a
+				// duplicate comes from interpolation, and which value should
+				// win is not obvious
+				if _, ok := result[k]; ok {
+					return nil, TemplateError{
+						Message:  fmt.Sprintf("interpolated keys produced duplicate key \"%s\"", k),
+						Template: v,
+					}
+				}
 				result[k] = r
 			}
 		}
+		if err := countElements(context, len(result)); err != nil {
+			return nil, err
+		}
 		return result, nil
 	default:
 		return nil, TemplateError{
@@ -1029,3 +2103,288 @@ func render(template interface{}, context map[string]interface{}) (interface{},
 		}
 	}
 }
+
+// renderKey renders the key of an object entry with the given value
+func renderKey(key string, value interface{}, context map[string]interface{}) (string, error) {
+	if strings.HasPrefix(key, "$$") {
+		key = key[1:]
+	} else if reservedIdentifiers.MatchString(key) {
+		return "", TemplateError{
+			Message:  fmt.Sprintf("%s is reserved; use $%s", key, key),
+			Template: value,
+		}
+	}
+	return interpolate(key, context)
+}
+
+// renderPartially renders a string or operator template with renderValue in a
+// partial render: one that refers to a variable not in the context is left as
+// it is, to be rendered again with more context, and a value that rendering
+// again would change is escaped with $$. Everything within is rendered as
+// usual, as a variable bound by an operator would be lost if it was left for
+// later.
+func renderPartially(template interface{}, context map[string]interface{}, renderValue func() (interface{}, error)) (interface{}, error) {
+	state := context[stateKey].(*renderState)
+	state.partial = false
+	defer func() { state.partial = true }()
+	value, err := renderValue()
+	if undefinedVariable(err) {
+		return template, nil
+	}
+	if err != nil {
+		return nil, err
+	}
+	if value == deleteMarker || isInert(value) {
+		return value, nil
+	}
+	return map[string]interface{}{"$$": value}, nil
+}
+
+// undefinedVariable reports whether err is, or was caused by, a reference to a
+// variable that is not in the context
+func undefinedVariable(err error) bool {
+	for {
+		switch e := err.(type) {
+		case parser.SyntaxError:
+			return e.Variable != ""
+		case TemplateError:
+			if e.cause == nil {
+				return false
+			}
+			err = e.cause
+		default:
+			return false
+		}
+	}
+}
+
+// isInert reports whether rendering value as a template gives value itself
+func isInert(value interface{}) bool {
+	switch v := value.(type) {
+	case string:
+		return !strings.Contains(v, "${")
+	case []interface{}:
+		for _, e := range v {
+			if !isInert(e) {
+				return false
+			}
+		}
+	case map[string]interface{}:
+		for k, e := range v {
+			if !isInertKey(k) || !isInert(e) {
+				return false
+			}
+		}
+	}
+	return true
+}
+
+func isInertKey(key string) bool {
+	return !strings.HasPrefix(key, "$") && !strings.Contains(key, "${")
+}
+
+// Validate checks the structure of a template without rendering it, returning
+// every problem found instead of stopping at the first. Unknown $-operators,
+// properties an operator does not accept and malformed each(..) and by(..)
+// properties are reported.
+func Validate(template interface{}) []error {
+	var errs []error
+	switch v := template.(type) {
+	case []interface{}:
+		for _, val := range v {
+			errs = append(errs, Validate(val)...)
+		}
+	case map[string]interface{}:
+		var operators []string
+		for _, k := range sortedKeys(v) {
+			if _, ok := operatorsDefined[k]; ok {
+				operators = append(operators, k)
+			}
+		}
+		if len(operators) > 1 {
+			errs = append(errs, TemplateError{
+				Message:  "at-most one $<keyword> operator is allowed",
+				Template: v,
+			})
+		} else if len(operators) == 1 {
+			if err := checkOperatorProperties(operators[0], v); err != nil {
+				errs = append(errs, err)
+			}
+		}
+
+		for _, k := range sortedKeys(v) {
+			val := v[k]
+			if k == "$$" {
+				continue
+			}
+			if len(operators) > 0 {
+				// the keys of $match and $switch are conditions, their values templates
+				conditions, ok := val.(map[string]interface{})
+				if ok && (k == "$match" || k == "$switch") {
+					for _, c := range sortedKeys(conditions) {
+						errs = append(errs, Validate(conditions[c])...)
+					}
+				} else {
+					errs = append(errs, Validate(val)...)
+				}
+				continue
+			}
+			if !strings.HasPrefix(k, "$$") && reservedIdentifiers.MatchString(k) {
+				errs = append(errs, TemplateError{
+					Message:  fmt.Sprintf("%s is reserved; use $%s", k, k),
+					Template: v,
+				})
+			}
+			errs = append(errs, Validate(val)...)
+		}
+	}
+	return errs
+}
+
+// parseAll parses every expression and interpolation in template into
+// parsed, as a render would, returning the first syntax error, so that it is
+// found without rendering the template
+func parseAll(template interface{}, parsed *i.Cache) error {
+	switch v := template.(type) {
+	case string:
+		return parseInterpolations(v, parsed)
+	case []interface{}:
+		for _, val := range v {
+			if err := parseAll(val, parsed); err != nil {
+				return err
+			}
+		}
+	case map[string]interface{}:
+		operator := ""
+		for _, k := range sortedKeys(v) {
+			if _, ok := operatorsDefined[k]; ok {
+				operator = k
+				break
+			}
+		}
+		if operator == "$$" {
+			return nil
+		}
+		for _, k := range sortedKeys(v) {
+			if err := parseEntry(operator, k, v[k], parsed); err != nil {
+				return err
+			}
+		}
+	}
+	return nil
+}
+
+// parseEntry parses the expressions and interpolations of the entry with key
+// k of an object template using operator, or none
+func parseEntry(operator, k string, val interface{}, parsed *i.Cache) error {
+	isExpression := k == "$eval" || k == "$if" || strings.HasPrefix(k, "by(") ||
+		strings.HasPrefix(k, "each(") && (operator == "$filter" || operator == "$find")
+	conditions, isConditions := val.(map[string]interface{})
+	switch {
+	case operator == "":
+		key := k
+		if strings.HasPrefix(k, "$$") {
+			key = k[1:]
+		}
+		if err := parseInterpolations(key, parsed); err != nil {
+			return err
+		}
+	case isExpression:
+		if s, ok := val.(string); ok {
+			_, err := parsed.Tree(s)
+			return err
+		}
+		return nil
+	case isConditions && (k == "$match" || k == "$switch"):
+		for _, c := range sortedKeys(conditions) {
+			if k == "$match" || c != "$default" {
+				if _, err := parsed.Tree(c); err != nil {
+					return err
+				}
+			}
+			if err := parseAll(conditions[c], parsed); err != nil {
+				return err
+			}
+		}
+		return nil
+	}
+	return parseAll(val, parsed)
+}
+
+// parseInterpolations parses the ${..} expressions of template into parsed
+func parseInterpolations(template string, parsed *i.Cache) error {
+	t := parsed.Template(template)
+	start := 0
+	for {
+		loc := interpolationPattern.FindStringIndex(template[start:])
+		if loc == nil {
+			return nil
+		}
+		offset := start + loc[0]
+		if template[offset+1] == '$' {
+			start = offset + 3
+			continue
+		}
+		_, end, err := t.Tree(template, offset+2, "}")
+		if err != nil {
+			return err
+		}
+		start = end
+	}
+}
+
+// checkOperatorProperties returns an error if a template using operator has
+// properties the operator does not accept
+func checkOperatorProperties(operator string, template map[string]interface{}) error {
+	switch operator {
+	case "$map", "$filter", "$find":
+		if len(template) != 2 {
+			return TemplateError{
+				Message:  operator + " must have exactly two properties",
+				Template: template,
+			}
+		}
+		for k := range template {
+			if k != operator && !eachKeyPattern.MatchString(k) {
+				return TemplateError{
+					Message:  operator + " requires a property on the form 'each(identifier)'",
+					Template: template,
+				}
+			}
+		}
+		return nil
+	case "$sort":
+		properties := 0
+		for k := range template {
+			if k == "$sort" || k == "reverse" {
+				continue
+			}
+			if !byKeyPattern.MatchString(k) {
+				return TemplateError{
+					Message:  "$sort may only have on other property on the form 'by(identifier)'",
+					Template: template,
+				}
+			}
+			properties++
+		}
+		if properties > 1 {
+			return TemplateError{
+				Message:  "only one by(..) is allowed",
+				Template: template,
+			}
+		}
+		return nil
+	case "$let":
+		if err := restrictProperties(template, operatorProperties[operator]...); err != nil {
+			return err
+		}
+		if _, ok := template["in"]; !ok {
+			return TemplateError{
+				Message:  "$let requires an 'in' clause",
+				Template: template,
+			}
+		}
+		return nil
+	}
+	return restrictProperties(template, operatorProperties[operator]...)
+}
@@ -19,6 +19,14 @@ class UnaryOp(ASTNode):
         self.expr = expr
 
 
+class TernaryOp(ASTNode):
+    def __init__(self, token, condition, thenExpr, elseExpr):
+        ASTNode.__init__(self, token)
+        self.condition = condition
+        self.thenExpr = thenExpr
+        self.elseExpr = elseExpr
+
+
 class FunctionCall(ASTNode):
     def __init__(self, token, name, args):
         ASTNode.__init__(self, token)
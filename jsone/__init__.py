@@ -1,21 +1,161 @@
 from __future__ import absolute_import, print_function, unicode_literals
 
 import re
-from .render import renderValue
-from .shared import JSONTemplateError, DeleteMarker, TemplateError, fromNow
+import time
+from collections import OrderedDict
+from .render import renderValue, renderElements, validate, parse_all, count_steps, parse_expression, tokenizer, \
+    STATE
+from .result_type import result_type
+from .free_variables import free_variables as _free_variables
+from .shared import JSONTemplateError, DeleteMarker, LimitExceededError, TemplateError, fromNow, is_json, string
 from . import builtins
 
 _context_re = re.compile(r'[a-zA-Z_][a-zA-Z0-9_]*$')
 
+# the builtins do not depend on the context, so they are only built once
+_builtins = builtins.build()
 
-def render(template, context):
+
+def _parse_caches():
+    """The parsed trees of expressions, by source, and of interpolations, by
+    string and offset; these are kept for one render, or for every render of a
+    compiled template"""
+    return {'expressions': {}, 'interpolations': {}}
+
+
+def _build_context(context, max_elements, max_steps, partial=False, parsed=None, max_depth=None,
+                   max_expression_length=None, max_interpolation_length=None, timeout=None):
     if not all(_context_re.match(c) for c in context):
         raise TemplateError('top level keys of context must follow '
                             '/[a-zA-Z_][a-zA-Z0-9_]*/')
     full_context = {'now': fromNow('0 seconds', None)}
-    full_context.update(builtins.build())
+    full_context.update(_builtins)
     full_context.update(context)
+    full_context[STATE] = {
+        'max_elements': max_elements,
+        'elements': 0,
+        'max_steps': max_steps,
+        'steps': 0,
+        'max_depth': max_depth,
+        # the number of arrays and objects being rendered within one another
+        'depth': 0,
+        'max_expression_length': max_expression_length,
+        'max_interpolation_length': max_interpolation_length,
+        # the time by which the render must finish, from time.time()
+        'deadline': None if timeout is None else time.time() + timeout,
+        'timeout': timeout,
+        # whether strings and operators that refer to variables not in the
+        # context are left as they are; off within an operator
+        'partial': partial,
+        # counts each expression node evaluated, only when there is a budget
+        'step': None,
+    }
+    full_context[STATE].update(parsed or _parse_caches())
+    if max_steps is not None or timeout is not None:
+        full_context[STATE]['step'] = lambda: count_steps(full_context, 1)
+    return full_context
+
+
+def _type_name(value):
+    """The name of the type of a JSON value, as given by the typeof builtin"""
+    if isinstance(value, bool):
+        return 'boolean'
+    if isinstance(value, string):
+        return 'string'
+    if isinstance(value, list):
+        return 'array'
+    if isinstance(value, dict):
+        return 'object'
+    if value is None:
+        return 'null'
+    return 'number'
+
+
+def _sort_keys(value):
+    """A copy of a JSON value with the keys of each object sorted by code
+    point, in OrderedDicts, since dicts only keep their order from Python 3.7"""
+    if isinstance(value, list):
+        return [_sort_keys(v) for v in value]
+    if isinstance(value, dict):
+        return OrderedDict((k, _sort_keys(value[k])) for k in sorted(value))
+    return value
+
+
+def render(template, context, max_elements=None, expect_type=None, max_steps=None, partial=False,
+           max_depth=None, sort_keys=False, max_expression_length=None, max_interpolation_length=None,
+           timeout=None):
+    return _render(template, context, None, max_elements, expect_type, max_steps, partial, max_depth, sort_keys,
+                   max_expression_length, max_interpolation_length, timeout)
+
+
+def _render(template, context, parsed, max_elements=None, expect_type=None, max_steps=None, partial=False,
+            max_depth=None, sort_keys=False, max_expression_length=None, max_interpolation_length=None,
+            timeout=None):
+    full_context = _build_context(context, max_elements, max_steps, partial, parsed, max_depth,
+                                  max_expression_length, max_interpolation_length, timeout)
     rv = renderValue(template, full_context)
     if rv is DeleteMarker:
-        return None
+        rv = None
+    if not is_json(rv):
+        raise TemplateError('rendered output can only contain JSON types')
+    if expect_type is not None and _type_name(rv) != expect_type:
+        raise TemplateError('rendered output is of type {}, expected {}'.format(_type_name(rv), expect_type))
+    if sort_keys:
+        rv = _sort_keys(rv)
     return rv
+
+
+def compile(template):
+    """Prepare a template for rendering many times, returning a function taking
+    the context and the keyword arguments of render. The first problem validate
+    finds is raised, as is the first syntax error, since every expression is
+    parsed here and the trees kept for the renders."""
+    errors = validate(template)
+    if errors:
+        raise errors[0]
+    parsed = _parse_caches()
+    parse_all(template, parsed)
+
+    def render_compiled(context, **options):
+        return _render(template, context, parsed, **options)
+    return render_compiled
+
+
+def builtin_names():
+    """The names of the built-in functions, in sorted order"""
+    return sorted(_builtins)
+
+
+def static_result_type(expression):
+    """The type of the value an expression evaluates to, as named by the typeof
+    builtin, when that does not depend on the context; otherwise None"""
+    return result_type(parse_expression(expression))
+
+
+def free_variables(expression):
+    """The names of the context values an expression refers to, in sorted
+    order, including the built-in functions it calls"""
+    return _free_variables(parse_expression(expression))
+
+
+def tokenize(expression):
+    """The tokens of an expression, as (kind, value, start, end) tuples, for
+    debugging; expressions are not evaluated from these"""
+    return list(tokenizer.generate_tokens(expression))
+
+
+def render_each(template, context, max_elements=None, max_steps=None, max_depth=None, max_expression_length=None,
+                max_interpolation_length=None, timeout=None):
+    """Render a template that produces an array, returning an iterator over its
+    elements that renders them as they are consumed instead of building the
+    whole array. The timeout runs while the elements are consumed."""
+    full_context = _build_context(context, max_elements, max_steps, max_depth=max_depth,
+                                  max_expression_length=max_expression_length,
+                                  max_interpolation_length=max_interpolation_length, timeout=timeout)
+
+    def elements():
+        for rv in renderElements(template, full_context):
+            if not is_json(rv):
+                raise TemplateError('rendered output can only contain JSON types')
+            yield rv
+    return elements()
@@ -0,0 +1,96 @@
+"""Render a template from the command line:
+
+    python -m jsone [--context FILE]... [--pretty] [--yaml] [TEMPLATE]
+
+The template is read from the TEMPLATE file, or from stdin if that is - or not
+given. Each --context file holds a JSON object of variables, those of later
+files overriding those of earlier ones. Files named *.yml or *.yaml are read as
+YAML, which requires PyYAML; with --yaml, every file and stdin is, and the
+output is YAML too. The rendered JSON is written to stdout; an error is written
+to stderr, with exit status 1."""
+from __future__ import absolute_import, print_function, unicode_literals
+
+import argparse
+import io
+import json
+import sys
+
+from . import render, JSONTemplateError
+from .shared import json_numbers
+
+
+def import_yaml(purpose):
+    try:
+        import yaml
+    except ImportError:
+        raise ValueError('{} requires PyYAML'.format(purpose))
+    return yaml
+
+
+def load(filename, stdin, as_yaml=False):
+    if filename == '-':
+        if not as_yaml:
+            return json.load(stdin)
+        return load_yaml(stdin, 'reading stdin')
+    with io.open(filename, encoding='utf-8') as f:
+        if not as_yaml and not filename.endswith(('.yml', '.yaml')):
+            return json.load(f)
+        return load_yaml(f, 'reading {}'.format(filename))
+
+
+def load_yaml(f, purpose):
+    yaml = import_yaml(purpose)
+    try:
+        return yaml.safe_load(f)
+    except yaml.YAMLError as e:
+        raise ValueError(e)
+
+
+def main(argv=None, stdin=None, stdout=None, stderr=None):
+    stdin = stdin or sys.stdin
+    stdout = stdout or sys.stdout
+    stderr = stderr or sys.stderr
+
+    parser = argparse.ArgumentParser(prog='json-e', description='Render a json-e template.')
+    parser.add_argument('template', nargs='?', default='-',
+                        help='the JSON file holding the template, - for stdin (the default)')
+    parser.add_argument('--context', action='append', default=[], metavar='FILE',
+                        help='a JSON file holding an object of context variables; may be repeated, '
+                             'later files overriding earlier ones')
+    parser.add_argument('--pretty', action='store_true', help='indent the output')
+    parser.add_argument('--yaml', action='store_true',
+                        help='read the template and context files, including stdin, as YAML and write YAML')
+    args = parser.parse_args(argv)
+
+    try:
+        template = load(args.template, stdin, args.yaml)
+        context = {}
+        for filename in args.context:
+            variables = load(filename, stdin, args.yaml)
+            if not isinstance(variables, dict):
+                raise ValueError('{} does not hold a JSON object'.format(filename))
+            context.update(variables)
+        # integral floats, as from the context files, are written as JS would
+        result = json_numbers(render(template, context))
+        if args.yaml:
+            yaml = import_yaml('writing YAML')
+    except (IOError, ValueError, JSONTemplateError) as e:
+        stderr.write('json-e: {}\n'.format(e))
+        return 1
+
+    if args.yaml:
+        output = yaml.safe_dump(result, default_flow_style=not args.pretty, allow_unicode=True, encoding=None)
+        # a scalar is followed by an end-of-document marker
+        if output.endswith('\n...\n'):
+            output = output[:-len('...\n')]
+        output = output.rstrip('\n')
+    elif args.pretty:
+        output = json.dumps(result, indent=2, separators=(',', ': '))
+    else:
+        output = json.dumps(result, separators=(',', ':'))
+    stdout.write(output + '\n')
+    return 0
+
+
+if __name__ == '__main__':
+    sys.exit(main())
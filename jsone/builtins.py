@@ -1,7 +1,10 @@
 from __future__ import absolute_import, print_function, unicode_literals
 
+import functools
 import math
-from .shared import string, to_str, fromNow, JSONTemplateError
+from .shared import string, number, to_str, fromNow, merge_deep, JSONTemplateError, UndefinedVariableError
+from .interpreter import deep_equals
+from .render import STATE
 
 
 class BuiltinError(JSONTemplateError):
@@ -11,9 +14,14 @@ class BuiltinError(JSONTemplateError):
 def build():
     builtins = {}
 
-    def builtin(name, variadic=None, argument_tests=None, minArgs=None, needs_context=False):
+    def builtin(name, variadic=None, argument_tests=None, minArgs=None, needs_context=False, lazy=False):
         def wrap(fn):
-            if variadic:
+            if lazy:
+                # arguments are unevaluated thunks; the builtin checks them itself
+                def invoke(context, *args):
+                    return fn(*args)
+
+            elif variadic:
                 def invoke(context, *args):
                     if minArgs:
                         if len(args) < minArgs:
@@ -45,30 +53,57 @@ def build():
                     return fn(*args)
 
             invoke._jsone_builtin = True
+            if lazy:
+                invoke._jsone_lazy = True
             builtins[name] = invoke
             return fn
 
         return wrap
 
     def is_number(v):
-        return isinstance(v, (int, float)) and not isinstance(v, bool)
+        return isinstance(v, number) and not isinstance(v, bool)
 
     def is_string(v):
         return isinstance(v, string)
 
+    def is_array(v):
+        return isinstance(v, list)
+
+    def is_object(v):
+        return isinstance(v, dict)
+
+    def is_number_or_array(v):
+        return is_number(v) or is_array(v)
+
     def is_string_or_array(v):
         return isinstance(v, (string, list))
 
+    def is_string_array_or_object(v):
+        return isinstance(v, (string, list, dict))
+
     def anything_except_array(v):
-        return isinstance(v, (string, int, float, bool)) or v is None
+        return isinstance(v, string) or isinstance(v, number) or v is None
 
     def anything(v):
-        return isinstance(v, (string, int, float, list, dict)) or v is None or callable(v)
+        return isinstance(v, (string, list, dict)) or isinstance(v, number) or v is None or callable(v)
 
     # ---
 
-    builtin('min', variadic=is_number, minArgs=1)(min)
-    builtin('max', variadic=is_number, minArgs=1)(max)
+    def extreme(name, fn):
+        @builtin(name, variadic=is_number_or_array, minArgs=1)
+        def invoke(*args):
+            # a single array argument gives the numbers to compare
+            if len(args) == 1 and is_array(args[0]):
+                args = args[0]
+                if not args:
+                    raise BuiltinError(
+                        'invalid arguments to builtin: {}: expected at least 1 arguments'.format(name))
+            if not all(is_number(arg) for arg in args):
+                raise BuiltinError('invalid arguments to builtin: {}'.format(name))
+            return fn(args)
+
+    extreme('min', min)
+    extreme('max', max)
     builtin('sqrt', argument_tests=[is_number])(math.sqrt)
     builtin('abs', argument_tests=[is_number])(abs)
 
@@ -80,6 +115,39 @@ def build():
     def floor(v):
         return int(math.floor(v))
 
+    @builtin('sign', argument_tests=[is_number])
+    def sign(v):
+        # an int, like ceil and floor, so that it renders as such
+        return (v > 0) - (v < 0)
+
+    @builtin('round', variadic=is_number, minArgs=1)
+    def round_builtin(v, digits=0, *rest):
+        # round half away from zero to the given number of decimal places
+        if rest or float(digits) != int(digits) or digits < 0:
+            raise BuiltinError('invalid arguments to builtin: round')
+        if digits > 308 or digits > 0 and not isinstance(v, float):
+            # an integer has no decimal places to round, and a float has none
+            # beyond 308
+            return v
+        factor = 10 ** int(digits)
+        scaled = abs(v) * factor
+        if isinstance(scaled, float) and math.isinf(scaled):
+            return v
+        rounded = int(math.floor(scaled))
+        if scaled - rounded >= 0.5:
+            rounded += 1
+        if v < 0:
+            rounded = -rounded
+        if digits == 0:
+            return rounded
+        return rounded / float(factor)
+
+    @builtin('clamp', argument_tests=[is_number, is_number, is_number])
+    def clamp(v, lo, hi):
+        if lo > hi:
+            raise BuiltinError('invalid arguments to builtin: clamp')
+        return min(max(v, lo), hi)
+
     @builtin('lowercase', argument_tests=[is_string])
     def lowercase(v):
         return v.lower()
@@ -88,7 +156,55 @@ def build():
     def lowercase(v):
         return v.upper()
 
+    @builtin('flatten', argument_tests=[is_array])
+    def flatten(v):
+        result = []
+        for e in v:
+            if isinstance(e, list):
+                result.extend(e)
+            else:
+                result.append(e)
+        return result
+
+    @builtin('flattenDeep', argument_tests=[is_array])
+    def flattenDeep(v):
+        result = []
+        for e in v:
+            if isinstance(e, list):
+                result.extend(flattenDeep(e))
+            else:
+                result.append(e)
+        return result
+
+    @builtin('enumerate', argument_tests=[is_array])
+    def enumerate_builtin(v):
+        return [[i, e] for i, e in enumerate(v)]
+
+    @builtin('zip', variadic=is_array, minArgs=2)
+    def zip_builtin(*arrays):
+        return [list(t) for t in zip(*arrays)]
+
+    @builtin('keys', argument_tests=[is_object])
+    def keys(v):
+        return sorted(v)
+
+    @builtin('values', argument_tests=[is_object])
+    def values(v):
+        return [v[k] for k in sorted(v)]
+
+    @builtin('merge', variadic=is_object, minArgs=1)
+    def merge(*objects):
+        result = {}
+        for obj in objects:
+            result.update(obj)
+        return result
+
+    @builtin('mergeDeep', variadic=is_object, minArgs=1)
+    def mergeDeep(*objects):
+        return functools.reduce(merge_deep, objects, {})
+
     builtin('len', argument_tests=[is_string_or_array])(len)
+    builtin('size', argument_tests=[is_string_array_or_object])(len)
     builtin('str', argument_tests=[anything_except_array])(to_str)
     builtin('number', variadic=is_string, minArgs=1)(float)
 
@@ -104,6 +220,34 @@ def build():
     def lstrip(s):
         return s.lstrip()
 
+    @builtin('split', argument_tests=[is_string, is_string])
+    def split(s, sep):
+        # an empty separator splits into characters
+        if sep == '':
+            return list(s)
+        return s.split(sep)
+
+    @builtin('join', argument_tests=[is_array, is_string])
+    def join(a, sep):
+        if not all(is_string(v) or is_number(v) for v in a):
+            raise BuiltinError('invalid arguments to builtin: join')
+        return sep.join(to_str(v) for v in a)
+
+    @builtin('indexOf', argument_tests=[is_string_or_array, anything])
+    def indexOf(haystack, needle):
+        if isinstance(haystack, list):
+            for i, v in enumerate(haystack):
+                if deep_equals(v, needle):
+                    return i
+            return -1
+        if not isinstance(needle, string):
+            raise BuiltinError('invalid arguments to builtin: indexOf')
+        return haystack.find(needle)
+
+    @builtin('reverse', argument_tests=[is_string_or_array])
+    def reverse(v):
+        return v[::-1]
+
     @builtin('fromNow', variadic=is_string, minArgs=1, needs_context=True)
     def fromNow_builtin(context, offset, reference=None):
         return fromNow(offset, reference or context.get('now'))
@@ -114,7 +258,7 @@ def build():
             return 'boolean'
         elif isinstance(v, string):
             return 'string'
-        elif isinstance(v, (int, float)):
+        elif isinstance(v, number):
             return 'number'
         elif isinstance(v, list):
             return 'array'
@@ -127,9 +271,17 @@ def build():
 
     @builtin('defined', argument_tests=[is_string], needs_context=True)
     def defined(context, s):
-        if s not in context:
-            return False
-        else:
-            return True
+        # the per-render state is not a variable
+        return s != STATE and s in context
+
+    @builtin('default', lazy=True)
+    def default(*args):
+        if len(args) != 2:
+            raise BuiltinError('invalid arguments to builtin: default')
+        value, fallback = args
+        try:
+            return value()
+        except UndefinedVariableError:
+            return fallback()
 
     return builtins
@@ -0,0 +1,39 @@
+from __future__ import absolute_import, print_function, unicode_literals
+
+
+def free_variables(tree):
+    """The names of the context values an expression tree refers to, in sorted
+    order, including the built-in functions it calls. The right side of `.`
+    and the keys of an object literal are not variables."""
+    names = set()
+
+    def visit(node):
+        kind = type(node).__name__
+        if kind == 'ContextValue':
+            names.add(node.token.value)
+        elif kind == 'BinOp':
+            visit(node.left)
+            if node.token.kind not in ('.', '?.'):
+                visit(node.right)
+        elif kind == 'UnaryOp':
+            visit(node.expr)
+        elif kind == 'TernaryOp':
+            visit(node.condition)
+            visit(node.thenExpr)
+            visit(node.elseExpr)
+        elif kind == 'FunctionCall':
+            visit(node.name)
+            for arg in node.args:
+                visit(arg)
+        elif kind == 'List':
+            for item in node.list:
+                visit(item)
+        elif kind == 'ValueAccess':
+            # the bounds of a slice may be left out
+            for part in (node.arr, node.left, node.right):
+                visit(part)
+        elif kind == 'Object':
+            for value in node.obj.values():
+                visit(value)
+    visit(tree)
+    return sorted(names)
@@ -1,5 +1,7 @@
-from .shared import InterpreterError, string
+from .shared import InterpreterError, UndefinedVariableError, integral, string, number
+from .parser import parse_string
 import operator
+import math
 
 
 def infixExpectationError(operator, expected):
@@ -7,11 +9,26 @@ def infixExpectationError(operator, expected):
                             format(operator, expected, operator, expected))
 
 
+# the value of a link in an optional chain whose object was null, so that the
+# rest of the chain is skipped, as in `x?.a.b` for a null x
+SKIPPED = object()
+
+
 class Interpreter:
-    def __init__(self, context):
+    def __init__(self, context, step=None):
+        # step, if given, is called before each node of the tree is evaluated,
+        # and may raise to stop the evaluation
         self.context = context
+        self.step = step
 
     def visit(self, node):
+        value = self.visit_link(node)
+        return None if value is SKIPPED else value
+
+    def visit_link(self, node):
+        # visit the object of a property access, index or call, which may be SKIPPED
+        if self.step:
+            self.step()
         method_name = 'visit_' + type(node).__name__
         visitor = getattr(self, method_name)
         return visitor(node)
@@ -23,7 +40,7 @@ class Interpreter:
         elif node.token.kind == "null":
             return None
         elif node.token.kind == "string":
-            return node.token.value[1:-1]
+            return parse_string(node.token.value)
         elif node.token.kind == "true":
             return True
         elif node.token.kind == "false":
@@ -44,12 +61,24 @@ class Interpreter:
         elif node.token.kind == "!":
             return not self.visit(node.expr)
 
+    def visit_TernaryOp(self, node):
+        if self.visit(node.condition):
+            return self.visit(node.thenExpr)
+        return self.visit(node.elseExpr)
+
     def visit_BinOp(self, node):
-        left = self.visit(node.left)
+        if node.token.kind in ('.', '?.'):
+            left = self.visit_link(node.left)
+            if left is SKIPPED:
+                return SKIPPED
+        else:
+            left = self.visit(node.left)
         if node.token.kind == "||":
-            return bool(left or self.visit(node.right))
+            test_logical_operand("||", left)
+            return left or test_logical_operand("||", self.visit(node.right))
         elif node.token.kind == "&&":
-            return bool(left and self.visit(node.right))
+            test_logical_operand("&&", left)
+            return left and test_logical_operand("&&", self.visit(node.right))
         else:
             right = self.visit(node.right)
 
@@ -61,16 +90,28 @@ class Interpreter:
             if type(right) != type(left) and \
                     (isinstance(left, string) or isinstance(right, string)):
                 raise infixExpectationError('+', 'numbers/strings')
-            return left + right
+            return integral(left + right)
         elif node.token.kind == "-":
             test_math_operands("-", left, right)
-            return left - right
+            return integral(left - right)
         elif node.token.kind == "/":
             test_math_operands("/", left, right)
-            return operator.truediv(left, right)
+            return integral(operator.truediv(left, right))
+        elif node.token.kind == "//":
+            test_math_operands("//", left, right)
+            if right == 0:
+                raise InterpreterError('infix: // expects a non-zero divisor')
+            # floor of the true quotient, as JS computes it
+            return int(math.floor(operator.truediv(left, right)))
         elif node.token.kind == "*":
             test_math_operands("*", left, right)
-            return left * right
+            return integral(left * right)
+        elif node.token.kind == "%":
+            test_math_operands("%", left, right)
+            if right == 0:
+                raise InterpreterError('infix: % expects a non-zero divisor')
+            # truncated remainder, taking the sign of the dividend as JS does
+            return integral(math.fmod(left, right))
         elif node.token.kind == ">":
             test_comparison_operands(">", left, right)
             return left > right
@@ -84,26 +125,27 @@ class Interpreter:
             test_comparison_operands("<=", left, right)
             return left <= right
         elif node.token.kind == "!=":
-            return left != right
+            return not deep_equals(left, right)
         elif node.token.kind == "==":
-            return left == right
+            return deep_equals(left, right)
         elif node.token.kind == "**":
             test_math_operands("**", left, right)
-            return right ** left
+            # a negative base with a fractional exponent has no real result
+            if right < 0 and not float(left).is_integer():
+                raise InterpreterError('infix: ** result is not a number')
+            return integral(right ** left)
         elif node.token.value == "in":
             if isinstance(right, dict):
                 if not isinstance(left, string):
-                    raise infixExpectationError('in-object', 'string on left side')
+                    raise InterpreterError('infix: in-object expects string on left side')
             elif isinstance(right, string):
                 if not isinstance(left, string):
-                    raise infixExpectationError('in-string', 'string on left side')
-            elif not isinstance(right, list):
-                raise infixExpectationError(
-                    'in', 'Array, string, or object on right side')
-            try:
-                return left in right
-            except TypeError:
-                raise infixExpectationError('in', 'scalar value, collection')
+                    raise InterpreterError('infix: in-string expects string on left side')
+            elif isinstance(right, list):
+                return any(deep_equals(left, e) for e in right)
+            else:
+                raise InterpreterError('infix: in expects array, string, or object on right side')
+            return left in right
 
         elif node.token.kind == ".":
             if not isinstance(left, dict):
@@ -113,18 +155,22 @@ class Interpreter:
             except KeyError:
                 raise InterpreterError(
                     'object has no property "{}"'.format(right))
+        elif node.token.kind == "?.":
+            # like `.`, but None for a missing property; a null object skips the
+            # rest of the chain
+            if left is None:
+                return SKIPPED
+            if not isinstance(left, dict):
+                raise InterpreterError('infix: {} expects {}'.format("?.", 'objects or null'))
+            return left.get(right)
 
     def visit_List(self, node):
-        list = []
-
-        if node.list[0] is not None:
-            for item in node.list:
-                list.append(self.visit(item))
-
-        return list
+        return [self.visit(item) for item in node.list]
 
     def visit_ValueAccess(self, node):
-        value = self.visit(node.arr)
+        value = self.visit_link(node.arr)
+        if value is SKIPPED:
+            return SKIPPED
         left = 0
         right = None
 
@@ -137,17 +183,18 @@ class Interpreter:
             if node.isInterval:
                 if right is None:
                     right = len(value)
-                try:
-                    return value[left:right]
-                except TypeError:
+                left, right = as_index(left), as_index(right)
+                if left is None or right is None:
                     raise InterpreterError('cannot perform interval access with non-integers')
+                return value[left:right]
             else:
+                left = as_index(left)
+                if left is None:
+                    raise InterpreterError('should only use integers to access arrays or strings')
                 try:
                     return value[left]
                 except IndexError:
                     raise InterpreterError('index out of bounds')
-                except TypeError:
-                    raise InterpreterError('should only use integers to access arrays or strings')
 
         if not isinstance(value, dict):
             raise InterpreterError('infix: {} expects {}'.format('"[..]"', 'object, array, or string'))
@@ -157,23 +204,28 @@ class Interpreter:
         try:
             return value[left]
         except KeyError:
-            return None
+            raise InterpreterError('object has no property "{}"'.format(left))
 
     def visit_ContextValue(self, node):
         try:
             contextValue = self.context[node.token.value]
         except KeyError:
-            raise InterpreterError(
-                'unknown context value {}'.format(node.token.value))
+            raise UndefinedVariableError(
+                'unknown context value {}'.format(node.token.value), node.token.value)
         return contextValue
 
     def visit_FunctionCall(self, node):
         args = []
-        func_name = self.visit(node.name)
+        func_name = self.visit_link(node.name)
+        if func_name is SKIPPED:
+            return SKIPPED
         if callable(func_name):
             if node.args is not None:
                 for item in node.args:
-                    args.append(self.visit(item))
+                    if hasattr(func_name, "_jsone_lazy"):
+                        args.append(lambda item=item: self.visit(item))
+                    else:
+                        args.append(self.visit(item))
                 if hasattr(func_name, "_jsone_builtin"):
                     return func_name(self.context, *args)
                 else:
@@ -192,6 +244,14 @@ class Interpreter:
         return self.visit(tree)
 
 
+def test_logical_operand(op, operand):
+    # && and || only take booleans, so that a value is not mistaken for its
+    # truthiness; the operand is returned when it is one
+    if not isinstance(operand, bool):
+        raise InterpreterError('infix: {} expects boolean {} boolean; compare other values, as in x != null'.format(op, op))
+    return operand
+
+
 def test_math_operands(op, left, right):
     if not is_number(left):
         raise infixExpectationError(op, 'number')
@@ -208,4 +268,24 @@ def test_comparison_operands(op, left, right):
 
 
 def is_number(v):
-    return isinstance(v, (int, float)) and not isinstance(v, bool)
+    return isinstance(v, number) and not isinstance(v, bool)
+
+
+def deep_equals(a, b):
+    """Compare JSON values structurally, without treating booleans as numbers"""
+    if isinstance(a, bool) or isinstance(b, bool):
+        return isinstance(a, bool) and isinstance(b, bool) and a == b
+    if isinstance(a, list) and isinstance(b, list):
+        return len(a) == len(b) and all(deep_equals(x, y) for x, y in zip(a, b))
+    if isinstance(a, dict) and isinstance(b, dict):
+        return set(a) == set(b) and all(deep_equals(a[k], b[k]) for k in a)
+    return a == b
+
+
+def as_index(v):
+    """Convert an integral number to an int suitable for indexing, or return None"""
+    if isinstance(v, float) and v.is_integer():
+        return int(v)
+    if isinstance(v, int) and not isinstance(v, bool):
+        return v
+    return None
@@ -0,0 +1 @@
+Expressions now support the conditional operator `cond ? a : b`, which evaluates only the branch selected by the truthiness of `cond`.
@@ -0,0 +1 @@
+Expressions now support the remainder operator `%`, with the same precedence as `*` and `/`.
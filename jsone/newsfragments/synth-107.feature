@@ -0,0 +1 @@
+Accessing a missing object property with bracket syntax (`obj["prop"]`) is now an `InterpreterError`, matching dot syntax, instead of evaluating to `null`.
@@ -0,0 +1 @@
+Add a `default(value, fallback)` built-in that evaluates to `fallback` when evaluating `value` fails, for example because it refers to an undefined variable.
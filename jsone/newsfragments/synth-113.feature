@@ -0,0 +1 @@
+Add `flatten(array)` and `flattenDeep(array)` built-ins, mirroring the `$flatten` and `$flattenDeep` operators.
@@ -0,0 +1 @@
+Add a render option limiting the total number of array and object elements produced, to guard against untrusted templates producing enormous outputs: `maxElements` in JavaScript, `max_elements` in Python and `RenderOptions.MaxElements` in Go.
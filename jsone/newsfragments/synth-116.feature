@@ -0,0 +1 @@
+Add a `reverse` property to `$sort` for sorting in descending order.
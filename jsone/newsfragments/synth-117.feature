@@ -0,0 +1 @@
+Add `keys(object)` and `values(object)` built-ins.
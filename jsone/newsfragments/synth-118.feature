@@ -0,0 +1 @@
+A string consisting of only a single `${..}` interpolation now evaluates to the array or object its expression produces, instead of failing. Arrays and objects still cannot be interpolated into surrounding text or object keys.
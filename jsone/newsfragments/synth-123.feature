@@ -0,0 +1 @@
+The `min` and `max` builtins accept a single array of numbers, e.g. `max([3, 1, 2])`.
@@ -0,0 +1 @@
+Add `renderEach` (JavaScript), `render_each` (Python) and `RenderEach` (Go) to render a template producing an array one element at a time, without building the whole array in memory.
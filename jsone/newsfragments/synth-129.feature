@@ -0,0 +1 @@
+Add a `split(string, separator)` builtin.
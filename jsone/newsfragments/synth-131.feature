@@ -0,0 +1 @@
+Add a `round(number, digits)` builtin, rounding half away from zero to `digits` decimal places (default 0).
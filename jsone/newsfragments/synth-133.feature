@@ -0,0 +1 @@
+Add `builtinNames` (JavaScript), `builtin_names` (Python) and `BuiltinNames` (Go), listing the built-in functions.
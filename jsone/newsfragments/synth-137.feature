@@ -0,0 +1 @@
+The `$switch` operator now accepts a `$default` key, whose value is used when no other condition matches.
@@ -0,0 +1 @@
+Add a `validate` function that checks the structure of a template without rendering it, reporting all problems found at once.
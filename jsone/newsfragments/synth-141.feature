@@ -0,0 +1 @@
+Add an `enumerate(array)` builtin returning `[index, value]` pairs.
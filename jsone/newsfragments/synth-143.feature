@@ -0,0 +1 @@
+Add an `indexOf(haystack, needle)` builtin finding an element of an array or a substring of a string.
@@ -0,0 +1 @@
+`$let` now accepts an array of binding objects, rendered in order so that later bindings can refer to earlier ones.
@@ -0,0 +1 @@
+Add an `expectType` render option (`expect_type` in Python, `ExpectType` in Go) that requires the rendered output to have the given type.
@@ -0,0 +1 @@
+Add a `zip(array, array, ...)` builtin combining arrays into tuples.
@@ -0,0 +1 @@
+The `$fromNow` operator and `fromNow` builtin accept more abbreviations (`yr`, `wk`, `hr`, `hrs`, `mins`, `secs` and so on) and ignore the case of units in every implementation. An unknown unit or malformed offset is now a `TemplateError` naming the problem.
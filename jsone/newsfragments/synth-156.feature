@@ -0,0 +1 @@
+Add `UserMessage(err)` to the Go package, returning the message of a rendering error without the template and source positions included in `Error()`.
@@ -0,0 +1 @@
+Rendering is faster in JavaScript and Python: builtins are defined once rather than on every render, operator property patterns are compiled once, and the Python tokenizer no longer copies the remaining source or scans every group for each token. Go has benchmarks for representative templates.
@@ -0,0 +1 @@
+Add a `size(value)` builtin, like `len` but also giving the number of properties of an object.
@@ -0,0 +1 @@
+Expressions are now parsed once per render, so an expression evaluated for every element of a `$map`, `$sort` or `$switch` is no longer re-parsed each time.
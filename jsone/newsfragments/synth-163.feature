@@ -0,0 +1 @@
+The expression language supports optional chaining: `obj?.prop` is `null` when `obj` is `null` or has no property `prop`, where `obj.prop` is an error, and a `null` `obj` skips the rest of the chain, so `obj?.prop.sub` is `null` too.
@@ -0,0 +1 @@
+The expression language has a floor division operator `//`, so `7 // 2` is `3` and `-7 // 2` is `-4`.
@@ -0,0 +1 @@
+The `merge(a, b, ...)` and `mergeDeep(a, b, ...)` built-ins merge objects within an expression, as the `$merge` and `$mergeDeep` operators do.
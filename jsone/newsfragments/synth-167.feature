@@ -0,0 +1 @@
+In Go, numbers in a template decoded with `json.Decoder.UseNumber` pass through rendering unchanged when they do not fit in a `float64`, so large integers are preserved exactly.
@@ -0,0 +1 @@
+The new ``{$$: value}`` form passes value through verbatim, without applying any operators or interpolating any strings inside it.
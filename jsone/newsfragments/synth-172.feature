@@ -0,0 +1 @@
+The new ``maxSteps`` (JavaScript), ``max_steps`` (Python) and ``MaxSteps`` (Go) render options limit the number of expression nodes evaluated and elements produced, failing with "evaluation budget exceeded".
@@ -0,0 +1 @@
+The new ``jsone.staticResultType`` (JavaScript), ``jsone.static_result_type`` (Python) and ``interpreter.StaticResultType`` (Go) functions give the type of the value an expression evaluates to, when that does not depend on the context.
@@ -0,0 +1 @@
+The ``by(var)`` property of ``$sort`` may evaluate to an array of keys, sorting lexicographically by each key in turn.
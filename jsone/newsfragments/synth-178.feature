@@ -0,0 +1 @@
+The new ``jsone.tokenize`` (JavaScript and Python) and ``interpreter.Tokenize`` (Go) functions return the tokens of an expression, for debugging.
@@ -0,0 +1 @@
+The new ``clamp(value, lo, hi)`` built-in constrains a number to a range.
@@ -0,0 +1 @@
+The new ``reverse(value)`` built-in reverses an array or the characters of a string; the ``$reverse`` operator still only accepts arrays.
@@ -0,0 +1 @@
+The new ``partial`` (JavaScript), ``partial`` (Python) and ``Partial`` (Go) render options leave strings and operators that refer to variables not in the context as they are, so that the output can be rendered again with more context.
@@ -0,0 +1 @@
+The new ``sign(x)`` built-in gives -1, 0 or 1 as a number is negative, zero or positive.
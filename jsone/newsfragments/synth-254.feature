@@ -0,0 +1 @@
+The new ``join(array, separator)`` built-in joins an array of strings and numbers into a string, formatting numbers as ``str`` does.
@@ -0,0 +1 @@
+The new ``$filter`` operator keeps the elements of an array or the entries of an object for which its ``each(..)`` expression is true, and the new ``$find`` operator gives the first such element of an array.
@@ -0,0 +1 @@
+The Go package has a new ``RenderInto`` function, which renders a template and decodes the result into a value of the caller's type.
@@ -0,0 +1 @@
+Syntax errors now have ``start`` and ``end`` attributes giving the offsets of the part of the expression that could not be parsed, as the Go ``SyntaxError`` already did.
@@ -0,0 +1 @@
+The Python distribution installs a ``json-e`` command (also ``python -m jsone``) that renders a template file or stdin with context from ``--context`` files.
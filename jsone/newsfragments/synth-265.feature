@@ -0,0 +1 @@
+The ``json-e`` command reads template and context files named ``*.yml`` or ``*.yaml`` as YAML, with the new ``yaml`` extra installing PyYAML.
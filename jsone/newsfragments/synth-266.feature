@@ -0,0 +1 @@
+A new ``compile`` function (``Compile`` for Go) prepares a template for rendering many times, parsing all of its expressions once, up front, so that a syntax error in any of them is raised by ``compile``.
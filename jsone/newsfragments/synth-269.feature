@@ -0,0 +1 @@
+New options limit the resources a render of an untrusted template may use: ``max_depth`` limits how deeply a template may be nested, ``max_expression_length`` the length of each expression, ``max_interpolation_length`` the length of each interpolated string, and ``timeout`` the time a render may take (``maxDepth``, ``maxExpressionLength``, ``maxInterpolationLength`` and ``timeout`` in JavaScript, and the ``RenderOptions`` fields ``MaxDepth``, ``MaxExpressionLength``, ``MaxInterpolationLength`` and ``Timeout`` in Go). Exceeding a limit, or nesting an expression more than 64 levels deep, raises the new ``LimitExceededError``, a ``TemplateError``.
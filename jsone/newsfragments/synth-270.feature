@@ -0,0 +1 @@
+New ``freeVariables`` and ``parseExpression`` functions (``free_variables`` and ``parse_expression`` in Python, ``FreeVariables`` and ``ParseExpression`` in Go) analyze an expression without evaluating it.
@@ -0,0 +1 @@
+String literals in expressions may contain the escapes ``\'``, ``\"``, ``\\``, ``\n``, ``\t`` and ``\uXXXX``, and a ``\uXXXX`` surrogate that is not part of a pair is a ``SyntaxError``. A backslash before any other character is still kept as it is.
@@ -0,0 +1 @@
+The ``sortKeys`` option in JavaScript and ``sort_keys`` keyword argument in Python sort the keys of every object in the rendered output by code point, instead of keeping the template's order.
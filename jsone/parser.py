@@ -1,34 +1,50 @@
-from .AST import Primitive, UnaryOp, ContextValue, BinOp, FunctionCall, ValueAccess, Object, List
+from .AST import Primitive, UnaryOp, TernaryOp, ContextValue, BinOp, FunctionCall, ValueAccess, Object, List
 from collections import namedtuple
 import re
-from .shared import TemplateError
+from .shared import TemplateError, LimitExceededError
 
 Token = namedtuple('Token', ['kind', 'value', 'start', 'end'])
 
 expectedTokens = ["!", "(", "+", "-", "[", "false", "identifier", "null", "number", "string", "true", "{"]
 
+# the deepest an expression may nest, counting parentheses, brackets, braces,
+# unary operators and the right-associative `**`; expressions are parsed and
+# evaluated recursively, so this keeps that within the stack
+MAX_NESTING = 64
+
 
 class SyntaxError(TemplateError):
+    """start and end are the offsets of the part of the expression that could
+    not be parsed, when known"""
+
+    def __init__(self, message, start=None, end=None):
+        super(SyntaxError, self).__init__(message)
+        self.start = start
+        self.end = end
 
     @classmethod
     def unexpected(cls, got, exp):
         exp = ', '.join(sorted(exp))
-        return cls('Found {}, expected {}'.format(got.value, exp))
+        return cls('Found {}, expected {}'.format(got.value, exp), got.start, got.end)
 
 
 class Parser(object):
     def __init__(self, source, tokenizer):
         self.tokens = tokenizer.generate_tokens(source)
         self.source = source
-        self.current_token = next(self.tokens)
+        try:
+            self.current_token = next(self.tokens)
+        except StopIteration:
+            self.current_token = None
         self.unaryOpTokens = ["-", "+", "!"]
         self.primitivesTokens = ["number", "null", "true", "false", "string"]
         self.operatorsByPriority = [["||"], ["&&"], ["in"], ["==", "!="], ["<", ">", "<=", ">="], ["+", "-"],
-                                    ["*", "/"], ["**"]]
+                                    ["*", "/", "//", "%"], ["**"]]
+        self.depth = 0
 
     def take_token(self, *kinds):
         if not self.current_token:
-            raise SyntaxError('Unexpected end of input')
+            raise SyntaxError('Unexpected end of input', len(self.source), len(self.source))
         if kinds and self.current_token.kind not in kinds:
             raise SyntaxError.unexpected(self.current_token, kinds)
         try:
@@ -38,45 +54,88 @@ class Parser(object):
         except SyntaxError as exc:
             raise exc
 
+    def nest(self, count):
+        # go count levels deeper into the expression, or back when negative
+        self.depth += count
+        if self.depth > MAX_NESTING:
+            raise LimitExceededError('expression is nested more than {} levels deep'.format(MAX_NESTING))
+
     def parse(self, level=0):
-        """  expr : logicalAnd (OR logicalAnd)* """
+        """  expr : logicalOr (QUESTION expr COLON expr)? """
+        """  logicalOr : logicalAnd (OR logicalAnd)* """
         """  logicalAnd : inStatement (AND inStatement)* """
         """  inStatement : equality (IN equality)*  """
         """  equality : comparison (EQUALITY | INEQUALITY  comparison)* """
         """  comparison : addition (LESS | GREATER | LESSEQUAL | GREATEREQUAL addition)* """
         """  addition : multiplication (PLUS | MINUS multiplication)* """
-        """  multiplication : exponentiation (MUL | DIV exponentiation)* """
+        """  multiplication : exponentiation (MUL | DIV | FLOORDIV | MOD exponentiation)* """
         """  exponentiation : propertyAccessOrFunc (EXP exponentiation)* """
+        # a whole expression, as within parentheses, is a level deeper, as is
+        # each operand of `**`, which nests to the right
+        chain = 1 if level == 0 else 0
+        self.nest(chain)
         if level == len(self.operatorsByPriority) - 1:
             node = self.parse_property_access_or_func()
             token = self.current_token
 
             while token is not None and token.kind in self.operatorsByPriority[level]:
+                self.check_operand(node, token)
                 self.take_token(token.kind)
-                node = BinOp(token, self.parse(level), node)
+                self.nest(1)
+                chain += 1
+                right = self.parse(level)
+                self.check_operand(right, self.current_token)
+                node = BinOp(token, right, node)
                 token = self.current_token
         else:
             node = self.parse(level + 1)
             token = self.current_token
 
             while token is not None and token.kind in self.operatorsByPriority[level]:
+                self.check_operand(node, token)
                 self.take_token(token.kind)
-                node = BinOp(token, node, self.parse(level + 1))
+                right = self.parse(level + 1)
+                self.check_operand(right, self.current_token)
+                node = BinOp(token, node, right)
                 token = self.current_token
 
+        if level == 0 and self.current_token is not None and self.current_token.kind == "?":
+            self.check_operand(node, self.current_token)
+            node = self.parse_ternary(node)
+
+        self.nest(-chain)
         return node
 
+    def check_operand(self, node, token):
+        # a binary operator needs an operand on either side, so `2 * *3` is
+        # not parsed as a multiplication with a missing operand
+        if node is None:
+            raise SyntaxError.unexpected(token, expectedTokens)
+
+    def parse_ternary(self, condition):
+        """  ternary : logicalOr QUESTION expr COLON expr """
+        token = self.current_token
+        self.take_token("?")
+        then_expr = self.parse()
+        if then_expr is None:
+            raise SyntaxError.unexpected(self.current_token, expectedTokens)
+        self.take_token(":")
+        else_expr = self.parse()
+        if else_expr is None:
+            raise SyntaxError.unexpected(self.current_token, expectedTokens)
+        return TernaryOp(token, condition, then_expr, else_expr)
+
     def parse_property_access_or_func(self):
-        """  propertyAccessOrFunc : unit (accessWithBrackets | DOT id | functionCall)* """
+        """  propertyAccessOrFunc : unit (accessWithBrackets | (DOT | QUESTIONDOT) id | functionCall)* """
         node = self.parse_unit()
         token = self.current_token
-        operators = ["[", "(", "."]
+        operators = ["[", "(", ".", "?."]
         while token is not None and token.kind in operators:
             if token.kind == "[":
                 node = self.parse_access_with_brackets(node)
-            elif token.kind == ".":
+            elif token.kind in (".", "?."):
                 token = self.current_token
-                self.take_token(".")
+                self.take_token(token.kind)
                 right_part = Primitive(self.current_token)
                 self.take_token("identifier")
                 node = BinOp(token, node, right_part)
@@ -86,16 +145,23 @@ class Parser(object):
         return node
 
     def parse_unit(self):
-        # unit : unaryOp unit | primitives | contextValue | LPAREN expr RPAREN | list | object
+        # unit : unaryOp exponentiation | primitives | contextValue | LPAREN expr RPAREN | list | object
         token = self.current_token
         if self.current_token is None:
-            raise SyntaxError('Unexpected end of input')
+            raise SyntaxError('Unexpected end of input', len(self.source), len(self.source))
         node = None
 
         if token.kind in self.unaryOpTokens:
             self.take_token(token.kind)
-            node = UnaryOp(token, self.parse_unit())
+            # the operand is an exponentiation, so `-2 ** 2` is `-(2 ** 2)`
+            self.nest(1)
+            operand = self.parse(len(self.operatorsByPriority) - 1)
+            self.check_operand(operand, self.current_token)
+            self.nest(-1)
+            node = UnaryOp(token, operand)
         elif token.kind in self.primitivesTokens:
+            if token.kind == "string":
+                check_string(token)
             self.take_token(token.kind)
             node = Primitive(token)
         elif token.kind == "identifier":
@@ -120,7 +186,7 @@ class Parser(object):
         token = self.current_token
         self.take_token("(")
 
-        if self.current_token.kind != ")":
+        if self.current_token is None or self.current_token.kind != ")":
             node = self.parse()
             args.append(node)
 
@@ -137,12 +203,12 @@ class Parser(object):
         return node
 
     def parse_list(self):
-        """  list: LSQAREBRAKET (expr (COMMA expr)*)? RSQAREBRAKET """
+        """  list: LSQAREBRAKET (expr (COMMA expr)* COMMA?)? RSQAREBRAKET """
         arr = []
         token = self.current_token
         self.take_token("[")
 
-        if self.current_token != "]":
+        if self.current_token is None or self.current_token.kind != "]":
             node = self.parse()
             arr.append(node)
 
@@ -150,6 +216,8 @@ class Parser(object):
                 if arr[-1] is None:
                     raise SyntaxError.unexpected(self.current_token, expectedTokens)
                 self.take_token(",")
+                if self.current_token and self.current_token.kind == "]":
+                    break
                 node = self.parse()
                 arr.append(node)
 
@@ -165,17 +233,17 @@ class Parser(object):
         is_interval = False
         token = self.current_token
         self.take_token("[")
-        if self.current_token.kind == "]":
+        if self.current_token is not None and self.current_token.kind == "]":
             raise SyntaxError.unexpected(self.current_token, expectedTokens)
-        if self.current_token.kind != ":":
+        if self.current_token is None or self.current_token.kind != ":":
             left = self.parse()
-        if self.current_token.kind == ":":
+        if self.current_token is not None and self.current_token.kind == ":":
             is_interval = True
             self.take_token(":")
-        if self.current_token.kind != "]":
+        if self.current_token is None or self.current_token.kind != "]":
             right = self.parse()
 
-        if is_interval and right is None and self.current_token.kind != "]":
+        if is_interval and right is None and self.current_token is not None and self.current_token.kind != "]":
             raise SyntaxError.unexpected(self.current_token, expectedTokens)
 
         self.take_token("]")
@@ -194,6 +262,7 @@ class Parser(object):
         while token is not None and (token.kind == "string" or token.kind == "identifier"):
             key = token.value
             if token.kind == "string":
+                check_string(token)
                 key = parse_string(key)
             self.take_token(token.kind)
             self.take_token(":")
@@ -213,8 +282,50 @@ class Parser(object):
         return node
 
 
+# the characters of a string literal's escapes, other than \uXXXX
+ESCAPES = {'n': '\n', 't': '\t', '\\': '\\', "'": "'", '"': '"'}
+
+# a surrogate pair is a single escape, so that it gives one character
+ESCAPE_RE = re.compile(r'\\(u[dD][89abAB][0-9a-fA-F]{2}\\u[dD][c-fC-F][0-9a-fA-F]{2}|u[0-9a-fA-F]{4}|[\s\S])')
+
+
+def expand_escape(match):
+    escape = match.group(1)
+    if len(escape) == 11:
+        high, low = int(escape[1:5], 16), int(escape[7:], 16)
+        code_point = 0x10000 + (high - 0xD800) * 0x400 + (low - 0xDC00)
+    elif len(escape) == 5:
+        code_point = int(escape[1:], 16)
+    else:
+        return ESCAPES.get(escape, match.group(0))
+    # works with both narrow and wide builds of Python 2
+    return ('\\U%08x' % code_point).encode('ascii').decode('unicode-escape')
+
+
+def check_string(token):
+    """Raise a SyntaxError if a string literal has a \\uXXXX escape for a
+    surrogate that is not part of a pair, as not every language can represent
+    one in a string"""
+    for match in ESCAPE_RE.finditer(token.value, 1, len(token.value) - 1):
+        escape = match.group(1)
+        if len(escape) == 5 and 0xD800 <= int(escape[1:], 16) <= 0xDFFF:
+            raise SyntaxError('string contains a lone surrogate {}'.format(match.group(0)),
+                              token.start, token.end)
+
+
 def parse_string(string):
-    return string[1:-1]
+    """Remove the quotes of a string literal and expand its escapes; a
+    backslash that does not begin an escape is kept as it is"""
+    return ESCAPE_RE.sub(expand_escape, string[1:-1])
+
+
+# snippets of source in error messages are elided beyond this length, so that a
+# very long expression does not produce an even longer message
+MAX_SNIPPET_LENGTH = 50
+
+
+def _snippet(s):
+    return s if len(s) <= MAX_SNIPPET_LENGTH else s[:MAX_SNIPPET_LENGTH] + '...'
 
 
 class Tokenizer(object):
@@ -228,28 +339,27 @@ class Tokenizer(object):
             for t in self.tokens]
         if self.ignore:
             token_patterns.append('(?:{})'.format(self.ignore))
-        self.token_re = re.compile('^(?:' + '|'.join(token_patterns) + ')')
+        self.token_re = re.compile('(?:' + '|'.join(token_patterns) + ')')
 
     def generate_tokens(self, source):
         offset = 0
         while True:
             start = offset
-            remainder = source[offset:]
-            mo = self.token_re.match(remainder)
+            mo = self.token_re.match(source, offset)
             if not mo:
-                if remainder:
+                if offset < len(source):
                     raise SyntaxError(
-                        "Unexpected input for '{}' at '{}'".format(source, remainder))
+                        "Unexpected input for '{}' at '{}'".format(_snippet(source), _snippet(source[offset:])),
+                        offset, len(source))
                 break
-            offset += mo.end()
+            offset = mo.end()
 
-            # figure out which token matched (note that idx is 0-based)
-            indexes = list(
-                filter(lambda x: x[1] is not None, enumerate(mo.groups())))
-            if indexes:
-                idx = indexes[0][0]
+            # figure out which token matched; only token patterns are groups, so
+            # the ignore pattern has no lastindex (note that mo.group is 1-based)
+            idx = mo.lastindex
+            if idx is not None:
                 yield Token(
-                    kind=self.tokens[idx],
-                    value=mo.group(idx + 1),  # (mo.group is 1-based)
+                    kind=self.tokens[idx - 1],
+                    value=mo.group(idx),
                     start=start,
                     end=offset)
@@ -1,24 +1,99 @@
 from __future__ import absolute_import, print_function, unicode_literals
 
 import re
+import time
 import json as json
-from .shared import JSONTemplateError, TemplateError, DeleteMarker, string, to_str
+from .shared import JSONTemplateError, TemplateError, LimitExceededError, UndefinedVariableError, DeleteMarker, string, to_str
 from . import shared
 from .six import viewitems
-from .parser import Parser, Tokenizer
+from .parser import Parser, Tokenizer, SyntaxError as ParserSyntaxError
 from .interpreter import Interpreter
 import functools
 
 operators = {}
 IDENTIFIER_RE = re.compile(r'[a-zA-Z_][a-zA-Z0-9_]*$')
 
-
-class SyntaxError(TemplateError):
+# The properties accepted by each operator, as regular expressions; the first is
+# the operator itself
+EACH_RE = r'each\([a-zA-Z_][a-zA-Z0-9_]*(,\s*([a-zA-Z_][a-zA-Z0-9_]*))?\)'
+BY_RE = r'by\([a-zA-Z_][a-zA-Z0-9_]*\)'
+OPERATOR_PROPERTIES = {
+    '$eval': [r'\$eval'],
+    '$flatten': [r'\$flatten'],
+    '$flattenDeep': [r'\$flattenDeep'],
+    '$fromNow': [r'\$fromNow', 'from'],
+    '$if': [r'\$if', 'then', 'else'],
+    '$json': [r'\$json'],
+    '$let': [r'\$let', 'in'],
+    '$map': [r'\$map', EACH_RE],
+    '$filter': [r'\$filter', EACH_RE],
+    '$find': [r'\$find', EACH_RE],
+    '$match': [r'\$match'],
+    '$switch': [r'\$switch'],
+    '$merge': [r'\$merge'],
+    '$mergeDeep': [r'\$mergeDeep'],
+    '$reverse': [r'\$reverse'],
+    '$sort': [r'\$sort', BY_RE, 'reverse'],
+    '$$': [r'\$\$'],
+}
+
+# Per-render state, such as the options given to render, is kept in the context
+# under this key. It is not a valid identifier, so templates cannot refer to it.
+STATE = '$state'
+
+
+class SyntaxError(ParserSyntaxError):
 
     @classmethod
     def unexpected(cls, got):
-        return cls('Found {}, expected !=, &&, (, *, **, +, -, ., /, <, <=, ==, >, >=, [, in,'
-                   ' ||'.format(got.value))
+        return cls('Found {}, expected !=, %, &&, (, *, **, +, -, ., /, //, <, <=, ==, >, >=, ?, ?., [,'
+                   ' in, ||'.format(got.value), got.start, got.end)
+
+
+class Context(object):
+    """The variables of a scope, falling back to those of the enclosing scope.
+    Creating one doesn't copy the enclosing scope, however large it is."""
+
+    def __init__(self, parent):
+        self.parent = parent
+        self.variables = {}
+
+    def __getitem__(self, key):
+        try:
+            return self.variables[key]
+        except KeyError:
+            return self.parent[key]
+
+    def __setitem__(self, key, value):
+        self.variables[key] = value
+
+    def __contains__(self, key):
+        return key in self.variables or key in self.parent
+
+    def get(self, key, default=None):
+        try:
+            return self[key]
+        except KeyError:
+            return default
+
+
+def count_steps(context, count):
+    """Record count evaluation steps, failing if that exceeds max_steps or the
+    render has run past its timeout"""
+    state = context[STATE]
+    state['steps'] += count
+    if state['max_steps'] is not None and state['steps'] > state['max_steps']:
+        raise LimitExceededError('evaluation budget exceeded')
+    if state['deadline'] is not None and time.time() > state['deadline']:
+        raise LimitExceededError('render exceeds the timeout of {} seconds'.format(state['timeout']))
+
+
+def count_elements(context, count):
+    state = context[STATE]
+    state['elements'] += count
+    if state['max_elements'] is not None and state['elements'] > state['max_elements']:
+        raise LimitExceededError('output exceeds the maximum of {} elements'.format(state['max_elements']))
+    count_steps(context, count)
 
 
 def operator(name):
@@ -33,7 +108,8 @@ tokenizer = Tokenizer(
     {
         'number': '[0-9]+(?:\\.[0-9]+)?',
         'identifier': '[a-zA-Z_][a-zA-Z_0-9]*',
-        'string': '\'[^\']*\'|"[^"]*"',
+        # a backslash escapes the character after it, even a quote
+        'string': '\'[^\'\\\\]*(?:\\\\[\\s\\S][^\'\\\\]*)*\'|"[^"\\\\]*(?:\\\\[\\s\\S][^"\\\\]*)*"',
         # avoid matching these as prefixes of identifiers e.g., `insinutations`
         'true': 'true(?![a-zA-Z_0-9])',
         'false': 'false(?![a-zA-Z_0-9])',
@@ -41,73 +117,139 @@ tokenizer = Tokenizer(
         'null': 'null(?![a-zA-Z_0-9])',
     },
     [
-        '**', '+', '-', '*', '/', '[', ']', '.', '(', ')', '{', '}', ':', ',',
+        '**', '//', '?.', '+', '-', '*', '/', '%', '[', ']', '.', '(', ')', '{', '}', ':', ',', '?',
         '>=', '<=', '<', '>', '==', '!=', '!', '&&', '||', 'true', 'false', 'in',
         'null', 'number', 'identifier', 'string',
     ],
 )
 
 
-def parse(source, context):
+def parse_expression(source):
+    """The tree of an expression, for analyzing it without evaluating it"""
     parser = Parser(source, tokenizer)
+    if parser.current_token is None:
+        raise SyntaxError('empty expression', 0, len(source))
     tree = parser.parse()
     if parser.current_token is not None:
         raise SyntaxError.unexpected(parser.current_token)
+    return tree
+
+
+def parse_cached(source, parsed):
+    # the tree of an expression, keeping the trees in parsed by source
+    tree = parsed.get(source)
+    if tree is None:
+        tree = parsed[source] = parse_expression(source)
+    return tree
+
 
-    interp = Interpreter(context)
+def check_expression_length(length, context):
+    limit = context[STATE]['max_expression_length']
+    if limit is not None and length > limit:
+        raise LimitExceededError('expression exceeds the maximum length of {}'.format(limit))
+
+
+def parse(source, context):
+    # expressions are parsed once per render; the trees are kept in the render state
+    check_expression_length(len(source), context)
+    tree = parse_cached(source, context[STATE]['expressions'])
+    interp = Interpreter(context, context[STATE]['step'])
     result = interp.interpret(tree)
     return result
 
 
-def parse_until_terminator(source, context, terminator):
-    parser = Parser(source, tokenizer)
-    tree = parser.parse()
-    if parser.current_token.kind != terminator:
-        raise SyntaxError.unexpected(parser.current_token)
-    interp = Interpreter(context)
+def parse_interpolation(string, start, terminator, parsed):
+    # the tree of the expression at start in string and the offset of the
+    # terminator after it, keeping these in parsed by start
+    entry = parsed.get(start)
+    if entry is None:
+        parser = Parser(string[start:], tokenizer)
+        if parser.current_token is not None and parser.current_token.kind == terminator:
+            raise SyntaxError('empty expression', parser.current_token.start, parser.current_token.end)
+        tree = parser.parse()
+        if parser.current_token is None:
+            end = len(string) - start
+            raise SyntaxError('Found end of string, expected {}'.format(terminator), end, end)
+        if parser.current_token.kind != terminator:
+            raise SyntaxError.unexpected(parser.current_token)
+        entry = parsed[start] = (tree, parser.current_token.start)
+    return entry
+
+
+def parse_until_terminator(string, start, context, terminator, parsed):
+    # parse the expression at start in string, keeping the trees in parsed by start
+    tree, end = parse_interpolation(string, start, terminator, parsed)
+    check_expression_length(end, context)
+    interp = Interpreter(context, context[STATE]['step'])
     result = interp.interpret(tree)
-    return result, parser.current_token.start
+    return result, end
 
 
 _interpolation_start_re = re.compile(r'\$?\${')
 
 
-def interpolate(string, context):
+def interpolate(string, context, allow_value=False):
+    """Interpolate ${..} expressions in a string.  With allow_value, a string
+    that consists of a single ${..} evaluates to the array or object it produces."""
     mo = _interpolation_start_re.search(string)
     if not mo:
         return string
 
     result = []
+    whole = string
+    # the parsed expressions of this string, by offset
+    trees = context[STATE]['interpolations'].setdefault(whole, {})
+    max_length = context[STATE]['max_interpolation_length']
+    # the length of the result so far, without the string after the last ${..}
+    length = 0
 
     while True:
         result.append(string[:mo.start()])
+        length += mo.start()
         if mo.group() != '$${':
             string = string[mo.end():]
-            parsed, offset = parse_until_terminator(string, context, '}')
+            parsed, offset = parse_until_terminator(whole, len(whole) - len(string), context, '}', trees)
             if isinstance(parsed, (list, dict)):
+                # the ${..} is the whole string if it started at index 0 and ends at the end
+                if allow_value and len(string) + 2 == len(whole) and offset + 1 == len(string):
+                    return parsed
                 raise TemplateError(
                     "interpolation of '{}' produced an array or object".format(string[:offset]))
-            if parsed is None:
-                result.append("")
-            else:
-                result.append(to_str(parsed))
+            if callable(parsed):
+                raise TemplateError(
+                    "interpolation of '{}' produced a function".format(string[:offset]))
+            if parsed is not None:
+                value = to_str(parsed)
+                result.append(value)
+                length += len(value)
+                if max_length is not None and length > max_length:
+                    raise LimitExceededError('interpolation exceeds the maximum length of {}'.format(max_length))
             string = string[offset + 1:]
         else:  # found `$${`
             result.append('${')
+            length += 2
             string = string[mo.end():]
 
         mo = _interpolation_start_re.search(string)
         if not mo:
+            if max_length is not None and length + len(string) > max_length:
+                raise LimitExceededError('interpolation exceeds the maximum length of {}'.format(max_length))
             result.append(string)
             break
     return ''.join(result)
 
 
+# compiled regular expressions matching the allowed properties of each operator
+_allowed_properties_re = {}
+
+
 def checkUndefinedProperties(template, allowed):
-    unknownKeys = []
-    combined = "|".join(allowed) + "$"
-    unknownKeys = [key for key in sorted(template)
-                   if not re.match(combined, key)]
+    key = tuple(allowed)
+    combined = _allowed_properties_re.get(key)
+    if combined is None:
+        combined = _allowed_properties_re[key] = re.compile("|".join(allowed) + "$")
+    unknownKeys = [k for k in sorted(template)
+                   if not combined.match(k)]
     if unknownKeys:
         raise TemplateError(allowed[0].replace('\\', '') +
                             " has undefined properties: " + " ".join(unknownKeys))
@@ -115,7 +257,7 @@ def checkUndefinedProperties(template, allowed):
 
 @operator('$eval')
 def eval(template, context):
-    checkUndefinedProperties(template, [r'\$eval'])
+    checkUndefinedProperties(template, OPERATOR_PROPERTIES['$eval'])
     if not isinstance(template['$eval'], string):
         raise TemplateError("$eval must be given a string expression")
     return parse(template['$eval'], context)
@@ -123,7 +265,7 @@ def eval(template, context):
 
 @operator('$flatten')
 def flatten(template, context):
-    checkUndefinedProperties(template, [r'\$flatten'])
+    checkUndefinedProperties(template, OPERATOR_PROPERTIES['$flatten'])
     value = renderValue(template['$flatten'], context)
     if not isinstance(value, list):
         raise TemplateError('$flatten value must evaluate to an array')
@@ -140,7 +282,7 @@ def flatten(template, context):
 
 @operator('$flattenDeep')
 def flattenDeep(template, context):
-    checkUndefinedProperties(template, [r'\$flattenDeep'])
+    checkUndefinedProperties(template, OPERATOR_PROPERTIES['$flattenDeep'])
     value = renderValue(template['$flattenDeep'], context)
     if not isinstance(value, list):
         raise TemplateError('$flattenDeep value must evaluate to an array')
@@ -158,7 +300,7 @@ def flattenDeep(template, context):
 
 @operator('$fromNow')
 def fromNow(template, context):
-    checkUndefinedProperties(template, [r'\$fromNow', 'from'])
+    checkUndefinedProperties(template, OPERATOR_PROPERTIES['$fromNow'])
     offset = renderValue(template['$fromNow'], context)
     reference = renderValue(
         template['from'], context) if 'from' in template else context.get('now')
@@ -170,7 +312,9 @@ def fromNow(template, context):
 
 @operator('$if')
 def ifConstruct(template, context):
-    checkUndefinedProperties(template, [r'\$if', 'then', 'else'])
+    checkUndefinedProperties(template, OPERATOR_PROPERTIES['$if'])
+    if not isinstance(template['$if'], string):
+        raise TemplateError('$if can evaluate string expressions only')
     condition = parse(template['$if'], context)
     try:
         if condition:
@@ -184,26 +328,41 @@ def ifConstruct(template, context):
 
 @operator('$json')
 def jsonConstruct(template, context):
-    checkUndefinedProperties(template, [r'\$json'])
+    checkUndefinedProperties(template, OPERATOR_PROPERTIES['$json'])
     value = renderValue(template['$json'], context)
-    return json.dumps(value, separators=(',', ':'), sort_keys=True, ensure_ascii=False)
+    if not shared.is_json(value):
+        raise TemplateError('$json can only stringify JSON types')
+    return shared.to_json(value)
 
 
 @operator('$let')
 def let(template, context):
-    checkUndefinedProperties(template, [r'\$let', 'in'])
-    if not isinstance(template['$let'], dict):
-        raise TemplateError("$let value must be an object")
-
-    subcontext = context.copy()
-    initial_result = renderValue(template['$let'], context)
-    if not isinstance(initial_result, dict):
-        raise TemplateError("$let value must be an object")
-    for k, v in initial_result.items():
-        if not IDENTIFIER_RE.match(k):
-            raise TemplateError("top level keys of $let must follow /[a-zA-Z_][a-zA-Z0-9_]*/")
-        else:
+    checkUndefinedProperties(template, OPERATOR_PROPERTIES['$let'])
+    subcontext = Context(context)
+
+    def add_variables(result, unique):
+        for k, v in result.items():
+            if not IDENTIFIER_RE.match(k):
+                raise TemplateError("top level keys of $let must follow /[a-zA-Z_][a-zA-Z0-9_]*/")
+            if unique and k in subcontext.variables:
+                raise TemplateError('$let binding "{}" is defined more than once'.format(k))
             subcontext[k] = v
+
+    if isinstance(template['$let'], list):
+        # an array of binding objects, each rendered in the scope of those before it
+        for binding in template['$let']:
+            result = renderValue(binding, subcontext)
+            if not isinstance(result, dict):
+                raise TemplateError("$let array elements must evaluate to objects")
+            add_variables(result, True)
+    else:
+        if not isinstance(template['$let'], dict):
+            raise TemplateError("$let value must be an object")
+
+        initial_result = renderValue(template['$let'], context)
+        if not isinstance(initial_result, dict):
+            raise TemplateError("$let value must be an object")
+        add_variables(initial_result, False)
     try:
         in_expression = template['in']
     except KeyError:
@@ -211,54 +370,109 @@ def let(template, context):
     return renderValue(in_expression, subcontext)
 
 
-@operator('$map')
-def map(template, context):
-    EACH_RE = r'each\([a-zA-Z_][a-zA-Z0-9_]*(,\s*([a-zA-Z_][a-zA-Z0-9_]*))?\)'
-    checkUndefinedProperties(template, [r'\$map', EACH_RE])
-    value = renderValue(template['$map'], context)
+def parse_map(template, context, operator='$map'):
+    """Check a $map template, or the like for another operator taking each(..),
+    and render its value, returning the value, the each(..) key, the element
+    and index variable names and the each template"""
+    checkUndefinedProperties(template, OPERATOR_PROPERTIES[operator])
+    value = renderValue(template[operator], context)
     if not isinstance(value, list) and not isinstance(value, dict):
-        raise TemplateError("$map value must evaluate to an array or object")
-
-    is_obj = isinstance(value, dict)
+        raise TemplateError("{0} value must evaluate to an array or object".format(operator))
 
     each_keys = [k for k in template if k.startswith('each(')]
     if len(each_keys) != 1:
         raise TemplateError(
-            "$map requires exactly one other property, each(..)")
+            "{0} requires exactly one other property, each(..)".format(operator))
     each_key = each_keys[0]
     each_args = [x.strip() for x in each_key[5:-1].split(',')]
     each_var = each_args[0]
     each_idx = each_args[1] if len(each_args) > 1 else None
 
-    each_template = template[each_key]
+    return value, each_key, each_var, each_idx, template[each_key]
 
-    def gen(val):
-        subcontext = context.copy()
-        for i, elt in enumerate(val):
-            if each_idx is None:
-                subcontext[each_var] = elt
-            else:
-                subcontext[each_var] = elt['val'] if is_obj else elt
-                subcontext[each_idx] = elt['key'] if is_obj else i
-            elt = renderValue(each_template, subcontext)
-            if elt is not DeleteMarker:
-                yield elt
+
+def map_elements(value, each_var, each_idx, each_template, context):
+    """Generate the rendered, non-deleted results of a $map"""
+    is_obj = isinstance(value, dict)
     if is_obj:
         value = [{'key': v[0], 'val': v[1]} for v in value.items()]
+    subcontext = Context(context)
+    for i, elt in enumerate(value):
+        if each_idx is None:
+            subcontext[each_var] = elt
+        else:
+            subcontext[each_var] = elt['val'] if is_obj else elt
+            subcontext[each_idx] = elt['key'] if is_obj else i
+        elt = renderValue(each_template, subcontext)
+        if elt is not DeleteMarker:
+            yield elt
+
+
+@operator('$map')
+def map(template, context):
+    value, each_key, each_var, each_idx, each_template = parse_map(template, context)
+    elements = map_elements(value, each_var, each_idx, each_template, context)
+    if isinstance(value, dict):
         v = dict()
-        for e in gen(value):
+        for e in elements:
             if not isinstance(e, dict):
                 raise TemplateError(
                     "$map on objects expects {0} to evaluate to an object".format(each_key))
+            for key in e:
+                if key in v:
+                    raise TemplateError(
+                        '$map on objects produced duplicate key "{0}"'.format(key))
             v.update(e)
+        count_elements(context, len(v))
         return v
     else:
-        return list(gen(value))
+        v = list(elements)
+        count_elements(context, len(v))
+        return v
+
+
+def parse_condition(operator, template, context):
+    """Check a $filter or $find template and render its value, returning the
+    value and a function telling whether the each(..) expression is truthy for
+    an element and its index or key"""
+    value, each_key, each_var, each_idx, each_expr = parse_map(template, context, operator)
+    if not isinstance(each_expr, string):
+        raise TemplateError("{0} each(..) can evaluate string expressions only".format(operator))
+
+    # like $map, each(x) over an object gives x the entries as {key, val}
+    entries = isinstance(value, dict) and each_idx is None
+    subcontext = Context(context)
+
+    def matches(elt, key):
+        subcontext[each_var] = {'key': key, 'val': elt} if entries else elt
+        if each_idx is not None:
+            subcontext[each_idx] = key
+        return bool(parse(each_expr, subcontext))
+    return value, matches
+
+
+@operator('$filter')
+def filterConstruct(template, context):
+    value, matches = parse_condition('$filter', template, context)
+    if isinstance(value, dict):
+        return dict((k, v) for k, v in viewitems(value) if matches(v, k))
+    return [v for i, v in enumerate(value) if matches(v, i)]
+
+
+@operator('$find')
+def find(template, context):
+    value, matches = parse_condition('$find', template, context)
+    if not isinstance(value, list):
+        raise TemplateError("$find value must evaluate to an array")
+    for i, v in enumerate(value):
+        if matches(v, i):
+            return v
+    return DeleteMarker
 
 
 @operator('$match')
 def matchConstruct(template, context):
-    checkUndefinedProperties(template, [r'\$match'])
+    checkUndefinedProperties(template, OPERATOR_PROPERTIES['$match'])
 
     if not isinstance(template['$match'], dict):
         raise TemplateError("$match can evaluate objects only")
@@ -273,25 +487,30 @@ def matchConstruct(template, context):
 
 @operator('$switch')
 def switch(template, context):
-    checkUndefinedProperties(template, [r'\$switch'])
+    checkUndefinedProperties(template, OPERATOR_PROPERTIES['$switch'])
 
     if not isinstance(template['$switch'], dict):
         raise TemplateError("$switch can evaluate objects only")
 
     result = []
     for condition in template['$switch']:
+        if condition == '$default':
+            continue
         if parse(condition, context):
             result.append(renderValue(template['$switch'][condition], context))
 
     if len(result) > 1:
         raise TemplateError("$switch can only have one truthy condition")
 
+    if len(result) == 0 and '$default' in template['$switch']:
+        result.append(renderValue(template['$switch']['$default'], context))
+
     return result[0] if len(result) > 0 else DeleteMarker
 
 
 @operator('$merge')
 def merge(template, context):
-    checkUndefinedProperties(template, [r'\$merge'])
+    checkUndefinedProperties(template, OPERATOR_PROPERTIES['$merge'])
     value = renderValue(template['$merge'], context)
     if not isinstance(value, list) or not all(isinstance(e, dict) for e in value):
         raise TemplateError(
@@ -304,32 +523,19 @@ def merge(template, context):
 
 @operator('$mergeDeep')
 def merge(template, context):
-    checkUndefinedProperties(template, [r'\$mergeDeep'])
+    checkUndefinedProperties(template, OPERATOR_PROPERTIES['$mergeDeep'])
     value = renderValue(template['$mergeDeep'], context)
     if not isinstance(value, list) or not all(isinstance(e, dict) for e in value):
         raise TemplateError(
             "$mergeDeep value must evaluate to an array of objects")
-
-    def merge(l, r):
-        if isinstance(l, list) and isinstance(r, list):
-            return l + r
-        if isinstance(l, dict) and isinstance(r, dict):
-            res = l.copy()
-            for k, v in viewitems(r):
-                if k in l:
-                    res[k] = merge(l[k], v)
-                else:
-                    res[k] = v
-            return res
-        return r
     if len(value) == 0:
         return {}
-    return functools.reduce(merge, value[1:], value[0])
+    return functools.reduce(shared.merge_deep, value[1:], value[0])
 
 
 @operator('$reverse')
 def reverse(template, context):
-    checkUndefinedProperties(template, [r'\$reverse'])
+    checkUndefinedProperties(template, OPERATOR_PROPERTIES['$reverse'])
     value = renderValue(template['$reverse'], context)
     if not isinstance(value, list):
         raise TemplateError("$reverse value must evaluate to an array of objects")
@@ -338,21 +544,26 @@ def reverse(template, context):
 
 @operator('$sort')
 def sort(template, context):
-    BY_RE = r'by\([a-zA-Z_][a-zA-Z0-9_]*\)'
-    checkUndefinedProperties(template, [r'\$sort', BY_RE])
+    checkUndefinedProperties(template, OPERATOR_PROPERTIES['$sort'])
     value = renderValue(template['$sort'], context)
     if not isinstance(value, list):
         raise TemplateError('$sorted values to be sorted must have the same type')
 
+    reverse = renderValue(template.get('reverse', False), context)
+    if not isinstance(reverse, bool):
+        raise TemplateError('$sort reverse must be a boolean')
+
     # handle by(..) if given, applying the schwartzian transform
     by_keys = [k for k in template if k.startswith('by(')]
     if len(by_keys) == 1:
         by_key = by_keys[0]
         by_var = by_key[3:-1]
         by_expr = template[by_key]
+        if not isinstance(by_expr, string):
+            raise TemplateError('$sort by(..) can evaluate string expressions only')
 
         def xform():
-            subcontext = context.copy()
+            subcontext = Context(context)
             for e in value:
                 subcontext[by_var] = e
                 yield parse(by_expr, subcontext), e
@@ -362,52 +573,194 @@ def sort(template, context):
     else:
         raise TemplateError('only one by(..) is allowed')
 
-    # check types
-    try:
-        eltype = type(to_sort[0][0])
-    except IndexError:
+    # check types before sorting; only by(..) can give arrays of keys
+    if not to_sort:
         return []
-    if eltype in (list, dict, bool, type(None)):
-        raise TemplateError('$sorted values to be sorted must have the same type')
-    if not all(isinstance(e[0], eltype) for e in to_sort):
+    if not sortable_keys([e[0] for e in to_sort], arrays=bool(by_keys)):
         raise TemplateError('$sorted values to be sorted must have the same type')
 
-    # unzip the schwartzian transform
-    return list(e[1] for e in sorted(to_sort))
+    # unzip the schwartzian transform; sorting on the key alone keeps the sort
+    # stable for elements with equal keys, even when reversed. Python compares
+    # lists lexicographically, as arrays of keys are.
+    return list(e[1] for e in sorted(to_sort, key=lambda e: e[0], reverse=reverse))
+
+
+def sort_type(v):
+    """The type of a sort key, treating ints and floats alike (but not bools,
+    which are ints in Python), or None if it cannot be sorted"""
+    if isinstance(v, string):
+        return string
+    if isinstance(v, shared.number) and not isinstance(v, bool):
+        return shared.number
+    return None
+
+
+def sortable_keys(keys, arrays=False):
+    """Whether keys are all numbers, all strings or, with arrays, all arrays of
+    keys, which are compared lexicographically; the keys at each position of
+    the arrays must be all numbers or all strings"""
+    if arrays and keys and all(isinstance(k, list) for k in keys):
+        length = max(len(k) for k in keys)
+        return all(sortable_keys([k[i] for k in keys if i < len(k)]) for i in range(length))
+    types = set(sort_type(k) for k in keys)
+    return len(types) <= 1 and None not in types
+
+
+@operator('$$')
+def literal(template, context):
+    """{$$: value} is value itself, with nothing inside it rendered"""
+    checkUndefinedProperties(template, OPERATOR_PROPERTIES['$$'])
+    return template['$$']
+
+
+def renderElements(template, context):
+    """Generate the elements of a template that renders to an array, rendering
+    array templates and $map over an array one element at a time"""
+    if isinstance(template, list):
+        for i, e in enumerate(template):
+            try:
+                v = renderValue(e, context)
+            except JSONTemplateError as e:
+                e.add_location('[{}]'.format(i))
+                raise
+            if v is not DeleteMarker:
+                count_elements(context, 1)
+                yield v
+        return
+
+    if isinstance(template, dict) and '$map' in template:
+        value, each_key, each_var, each_idx, each_template = parse_map(template, context)
+        if isinstance(value, list):
+            for v in map_elements(value, each_var, each_idx, each_template, context):
+                count_elements(context, 1)
+                yield v
+            return
+        result = None
+    else:
+        result = renderValue(template, context)
+    if not isinstance(result, list):
+        raise TemplateError('render_each requires a template that renders to an array')
+    for v in result:
+        yield v
+
+
+def key_location(key):
+    if IDENTIFIER_RE.match(key):
+        return '.{}'.format(key)
+    return '[{}]'.format(json.dumps(key))
+
+
+def is_inert(value):
+    """Whether rendering value as a template gives value itself"""
+    if isinstance(value, string):
+        return '${' not in value
+    if isinstance(value, list):
+        return all(is_inert(v) for v in value)
+    if isinstance(value, dict):
+        return all(is_inert_key(k) and is_inert(v) for k, v in viewitems(value))
+    return True
+
+
+def is_inert_key(key):
+    return not key.startswith('$') and '${' not in key
+
+
+def render_partially(template, context, render):
+    """Render a string or operator template with render. In a partial render,
+    one that refers to a variable not in the context is left as it is, to be
+    rendered again with more context, and a value that rendering again would
+    change is escaped with $$. Everything within is rendered as usual, as a
+    variable bound by an operator would be lost if it was left for later."""
+    state = context[STATE]
+    if not state['partial']:
+        return render()
+    state['partial'] = False
+    try:
+        value = render()
+    except UndefinedVariableError:
+        return template
+    finally:
+        state['partial'] = True
+    if value is DeleteMarker or is_inert(value):
+        return value
+    return {'$$': value}
+
+
+def render_key(key, context):
+    if key.startswith('$$'):
+        return key[1:]
+    if key.startswith('$') and IDENTIFIER_RE.match(key[1:]):
+        raise TemplateError('{0} is reserved; use ${0}'.format(key))
+    return interpolate(key, context)
+
+
+def render_partial_key(key, context):
+    """In a partial render, the rendered key of an object entry, or None if the
+    entry is left as it is because its key refers to a variable not in the
+    context or would be rendered differently again"""
+    try:
+        rendered = render_key(key, context)
+    except UndefinedVariableError:
+        return None
+    return rendered if is_inert_key(rendered) else None
 
 
 def renderValue(template, context):
+    """Render a template, an array or object one level deeper, failing if
+    that exceeds max_depth"""
+    if not isinstance(template, (dict, list)):
+        return render_inner(template, context)
+    state = context[STATE]
+    if state['max_depth'] is not None and state['depth'] >= state['max_depth']:
+        raise LimitExceededError('template nesting exceeds the maximum depth of {}'.format(state['max_depth']))
+    state['depth'] += 1
+    try:
+        return render_inner(template, context)
+    finally:
+        state['depth'] -= 1
+
+
+def render_inner(template, context):
     if isinstance(template, string):
-        return interpolate(template, context)
+        return render_partially(template, context, lambda: interpolate(template, context, allow_value=True))
 
     elif isinstance(template, dict):
         matches = [k for k in template if k in operators]
         if matches:
             if len(matches) > 1:
                 raise TemplateError("only one operator allowed")
-            return operators[matches[0]](template, context)
+            return render_partially(template, context, lambda: operators[matches[0]](template, context))
+
+        partial = context[STATE]['partial']
 
         def updated():
+            seen = set()
             for k, v in viewitems(template):
-                if k.startswith('$$'):
-                    k = k[1:]
-                elif k.startswith('$') and IDENTIFIER_RE.match(k[1:]):
-                    raise TemplateError(
-                        '$<identifier> is reserved; use $$<identifier>')
+                if partial:
+                    rendered = render_partial_key(k, context)
+                    if rendered is None:
+                        yield k, v
+                        continue
+                    k = rendered
                 else:
-                    k = interpolate(k, context)
+                    k = render_key(k, context)
 
                 try:
                     v = renderValue(v, context)
                 except JSONTemplateError as e:
-                    if IDENTIFIER_RE.match(k):
-                        e.add_location('.{}'.format(k))
-                    else:
-                        e.add_location('[{}]'.format(json.dumps(k)))
+                    e.add_location(key_location(k))
                     raise
                 if v is not DeleteMarker:
+                    # keys written out in the template are distinct, so a
+                    # duplicate comes from interpolation, and which value
+                    # should win is not obvious
+                    if k in seen:
+                        raise TemplateError('interpolated keys produced duplicate key "{0}"'.format(k))
+                    seen.add(k)
                     yield k, v
-        return dict(updated())
+        result = dict(updated())
+        count_elements(context, len(result))
+        return result
 
     elif isinstance(template, list):
         def updated():
@@ -420,7 +773,122 @@ def renderValue(template, context):
                     e.add_location('[{}]'.format(i))
                     raise
 
-        return list(updated())
+        result = list(updated())
+        count_elements(context, len(result))
+        return result
 
     else:
         return template
+
+
+def validate(template):
+    """Check the structure of a template without rendering it, returning a list
+    of every problem found instead of stopping at the first. Unknown
+    $-operators, properties an operator does not accept and malformed each(..)
+    and by(..) keys are reported."""
+    errors = []
+
+    def add_errors(inner, location=None):
+        for e in inner:
+            if location:
+                e.add_location(location)
+            errors.append(e)
+
+    if isinstance(template, list):
+        for i, v in enumerate(template):
+            add_errors(validate(v), '[{}]'.format(i))
+        return errors
+    if not isinstance(template, dict):
+        return errors
+
+    matches = [k for k in template if k in operators]
+    if len(matches) > 1:
+        errors.append(TemplateError("only one operator allowed"))
+    elif matches:
+        operator = matches[0]
+        try:
+            checkUndefinedProperties(template, OPERATOR_PROPERTIES[operator])
+            if operator in ('$map', '$filter', '$find') and len(template) != 2:
+                raise TemplateError(
+                    "{0} requires exactly one other property, each(..)".format(operator))
+            if operator == '$let' and 'in' not in template:
+                raise TemplateError("$let operator requires an `in` clause")
+            if operator == '$sort' and len([k for k in template if k.startswith('by(')]) > 1:
+                raise TemplateError('only one by(..) is allowed')
+        except TemplateError as e:
+            errors.append(e)
+
+    for k in sorted(template):
+        v = template[k]
+        if k == '$$':
+            continue
+        if matches:
+            # the keys of $match and $switch are conditions, their values templates
+            if k in ('$match', '$switch') and isinstance(v, dict):
+                for condition in sorted(v):
+                    add_errors(validate(v[condition]))
+            else:
+                add_errors(validate(v))
+            continue
+        if not k.startswith('$$') and k.startswith('$') and IDENTIFIER_RE.match(k[1:]):
+            errors.append(TemplateError('{0} is reserved; use ${0}'.format(k)))
+        add_errors(validate(v), key_location(k))
+    return errors
+
+
+def parse_all(template, parsed):
+    """Parse every expression and interpolation in a template into parse
+    caches like those of a render, raising the first syntax error, so that it
+    is found without rendering the template"""
+    def parse_template(value, location=None):
+        try:
+            parse_value(value)
+        except JSONTemplateError as e:
+            if location:
+                e.add_location(location)
+            raise
+
+    def parse_strings(string):
+        trees = parsed['interpolations'].setdefault(string, {})
+        start = 0
+        while True:
+            mo = _interpolation_start_re.search(string, start)
+            if not mo:
+                return
+            start = mo.end()
+            if mo.group() != '$${':
+                _, end = parse_interpolation(string, start, '}', trees)
+                start += end + 1
+
+    def parse_value(value):
+        if isinstance(value, string):
+            parse_strings(value)
+        elif isinstance(value, list):
+            for i, v in enumerate(value):
+                parse_template(v, '[{}]'.format(i))
+        elif isinstance(value, dict):
+            matches = [k for k in value if k in operators]
+            if not matches:
+                for k in sorted(value):
+                    if not k.startswith('$$'):
+                        parse_strings(k)
+                    parse_template(value[k], key_location(k))
+                return
+            operator = matches[0]
+            if operator == '$$':
+                return
+            for k in sorted(value):
+                v = value[k]
+                if k in ('$eval', '$if') or k.startswith('each(') and operator in ('$filter', '$find') or \
+                        k.startswith('by('):
+                    if isinstance(v, string):
+                        parse_cached(v, parsed['expressions'])
+                elif k in ('$match', '$switch') and isinstance(v, dict):
+                    for condition in sorted(v):
+                        if condition != '$default' or k == '$match':
+                            parse_cached(condition, parsed['expressions'])
+                        parse_template(v[condition])
+                else:
+                    parse_template(v)
+
+    parse_template(template)
@@ -0,0 +1,46 @@
+from __future__ import absolute_import, print_function, unicode_literals
+
+# the operators whose result, if any, always has the same type
+OPERATOR_TYPES = {
+    '-': 'number', '*': 'number', '/': 'number', '//': 'number', '%': 'number', '**': 'number',
+    '<': 'boolean', '>': 'boolean', '<=': 'boolean', '>=': 'boolean', '==': 'boolean', '!=': 'boolean',
+    '&&': 'boolean', '||': 'boolean', 'in': 'boolean',
+}
+
+LITERAL_TYPES = {'number': 'number', 'string': 'string', 'true': 'boolean', 'false': 'boolean', 'null': 'null'}
+
+
+def result_type(node):
+    """The type of the value the expression tree evaluates to, as named by the
+    typeof builtin, or None if that depends on the context. An expression that
+    fails when it is evaluated has no value, so it is not considered here."""
+    kind = type(node).__name__
+    if kind == 'ASTNode':
+        return LITERAL_TYPES.get(node.token.kind)
+    if kind == 'UnaryOp':
+        return 'boolean' if node.token.kind == '!' else 'number'
+    if kind == 'BinOp':
+        if node.token.kind == '+':
+            # + adds numbers or concatenates strings, so one side is enough
+            types = (result_type(node.left), result_type(node.right))
+            if 'number' in types:
+                return 'number'
+            if 'string' in types:
+                return 'string'
+            return None
+        return OPERATOR_TYPES.get(node.token.kind)
+    if kind == 'TernaryOp':
+        then_type = result_type(node.thenExpr)
+        return then_type if then_type == result_type(node.elseExpr) else None
+    if kind == 'List':
+        return 'array'
+    if kind == 'Object':
+        return 'object'
+    if kind == 'ValueAccess':
+        # indexing or slicing a string gives a string, and slicing an array
+        # gives an array
+        array_type = result_type(node.arr)
+        if array_type == 'string' or array_type == 'array' and node.isInterval:
+            return array_type
+    # context values, function calls and property access
+    return None
@@ -1,6 +1,9 @@
 from __future__ import absolute_import, print_function, unicode_literals
 
 import re
+import json
+import math
+import decimal
 import datetime
 
 
@@ -8,7 +11,21 @@ class DeleteMarker:
     pass
 
 
+def is_json(value):
+    """Return True if value consists of JSON types only"""
+    if isinstance(value, (string, number, bool)) or value is None:
+        return True
+    if isinstance(value, list):
+        return all(is_json(v) for v in value)
+    if isinstance(value, dict):
+        return all(isinstance(k, string) and is_json(v) for k, v in value.items())
+    return False
+
+
 class JSONTemplateError(Exception):
+    # the name of the error in messages, if not that of its class
+    name = None
+
     def __init__(self, message):
         super(JSONTemplateError, self).__init__(message)
         self.location = []
@@ -19,7 +36,7 @@ class JSONTemplateError(Exception):
     def __str__(self):
         location = ' at template' + ''.join(self.location)
         return "{}{}: {}".format(
-            self.__class__.__name__,
+            self.name or self.__class__.__name__,
             location if self.location else '',
             self.args[0])
 
@@ -28,65 +45,87 @@ class TemplateError(JSONTemplateError):
     pass
 
 
+class LimitExceededError(TemplateError):
+    """A TemplateError for a render that exceeds one of its limits, or an
+    expression nested too deeply to parse"""
+    name = 'TemplateError'
+
+
 class InterpreterError(JSONTemplateError):
     pass
 
 
-# Regular expression matching: X days Y hours Z minutes
-# todo: support hr, wk, yr
-FROMNOW_RE = re.compile(''.join([
-    r'^(\s*(?P<years>\d+)\s*y(ears?)?)?',
-    r'(\s*(?P<months>\d+)\s*mo(nths?)?)?',
-    r'(\s*(?P<weeks>\d+)\s*w(eeks?)?)?',
-    r'(\s*(?P<days>\d+)\s*d(ays?)?)?',
-    r'(\s*(?P<hours>\d+)\s*h(ours?)?)?',
-    r'(\s*(?P<minutes>\d+)\s*m(in(utes?)?)?)?\s*',
-    r'(\s*(?P<seconds>\d+)\s*s(ec(onds?)?)?)?\s*$',
-]))
+class UndefinedVariableError(InterpreterError):
+    """An InterpreterError for a variable that is not in the context"""
+    name = 'InterpreterError'
 
+    def __init__(self, message, variable):
+        super(UndefinedVariableError, self).__init__(message)
+        self.variable = variable
 
-def fromNow(offset, reference):
-    # copied from taskcluster-client.py
+
+# The accepted spellings of each unit, in the order the units must appear
+FROMNOW_UNITS = [
+    ('years', ('y', 'yr', 'yrs', 'year', 'years')),
+    ('months', ('mo', 'mos', 'month', 'months')),
+    ('weeks', ('w', 'wk', 'wks', 'week', 'weeks')),
+    ('days', ('d', 'day', 'days')),
+    ('hours', ('h', 'hr', 'hrs', 'hour', 'hours')),
+    ('minutes', ('m', 'min', 'mins', 'minute', 'minutes')),
+    ('seconds', ('s', 'sec', 'secs', 'second', 'seconds')),
+]
+
+# Regular expression matching a number and its unit
+FROMNOW_TERM_RE = re.compile(r'\s*(\d+)\s*([a-z]+)', re.I)
+
+
+def parse_offset(offset):
+    """Parse a fromNow offset into the number of each unit, and whether it is
+    in the future"""
     # We want to handle past dates as well as future
     future = True
-    offset = offset.lstrip()
-    if offset.startswith('-'):
+    rest = offset.lstrip()
+    if rest.startswith('-'):
         future = False
-        offset = offset[1:].lstrip()
-    if offset.startswith('+'):
-        offset = offset[1:].lstrip()
+        rest = rest[1:]
+    elif rest.startswith('+'):
+        rest = rest[1:]
+
+    # Parse each term, each with a unit after that of the term before it
+    counts = {}
+    next_unit = 0
+    while rest.strip():
+        m = FROMNOW_TERM_RE.match(rest)
+        if m is None:
+            raise TemplateError("invalid time expression '{}'".format(offset))
+        spelling = m.group(2).lower()
+        index = next((i for i, (_, spellings) in enumerate(FROMNOW_UNITS) if spelling in spellings), None)
+        if index is None:
+            raise TemplateError("unknown time unit '{}' in time expression '{}'".format(m.group(2), offset))
+        if index < next_unit:
+            raise TemplateError("invalid time expression '{}'".format(offset))
+        counts[FROMNOW_UNITS[index][0]] = int(m.group(1))
+        next_unit = index + 1
+        rest = rest[m.end():]
+    return counts, future
 
-    # Parse offset
-    m = FROMNOW_RE.match(offset)
-    if m is None:
-        raise ValueError("offset string: '%s' does not parse" % offset)
+
+def fromNow(offset, reference):
+    # copied from taskcluster-client.py
+    counts, future = parse_offset(offset)
 
     # In order to calculate years and months we need to calculate how many days
     # to offset the offset by, since timedelta only goes as high as weeks
-    days = 0
-    hours = 0
-    minutes = 0
-    seconds = 0
-    if m.group('years'):
-        # forget leap years, a year is 365 days
-        years = int(m.group('years'))
-        days += 365 * years
-    if m.group('months'):
-        # assume "month" means 30 days
-        months = int(m.group('months'))
-        days += 30 * months
-    days += int(m.group('days') or 0)
-    hours += int(m.group('hours') or 0)
-    minutes += int(m.group('minutes') or 0)
-    seconds += int(m.group('seconds') or 0)
+    # (forget leap years, a year is 365 days, and assume "month" means 30 days)
+    days = 365 * counts.get('years', 0) + 30 * counts.get('months', 0) + counts.get('days', 0)
 
     # Offset datetime from utc
     delta = datetime.timedelta(
-        weeks=int(m.group('weeks') or 0),
+        weeks=counts.get('weeks', 0),
         days=days,
-        hours=hours,
-        minutes=minutes,
-        seconds=seconds,
+        hours=counts.get('hours', 0),
+        minutes=counts.get('minutes', 0),
+        seconds=counts.get('seconds', 0),
     )
 
     if isinstance(reference, string):
@@ -97,6 +136,22 @@ def fromNow(offset, reference):
     return stringDate(reference + delta if future else reference - delta)
 
 
+def merge_deep(l, r):
+    """Merge two values, preferring the right but concatenating lists and
+    recursively merging objects"""
+    if isinstance(l, list) and isinstance(r, list):
+        return l + r
+    if isinstance(l, dict) and isinstance(r, dict):
+        res = l.copy()
+        for k, v in r.items():
+            if k in l:
+                res[k] = merge_deep(l[k], v)
+            else:
+                res[k] = v
+        return res
+    return r
+
+
 datefmt_re = re.compile(r'(\.[0-9]{3})[0-9]*(\+00:00)?')
 
 
@@ -109,10 +164,77 @@ def to_str(v):
         return 'null'
     elif isinstance(v, string):
         return v
+    elif isinstance(v, number):
+        return number_to_str(v)
     else:
         return str(v)
 
 
+def number_to_str(v):
+    """Format a number the way JavaScript's Number.prototype.toString does, so
+    that all implementations produce the same strings"""
+    if not isinstance(v, float) and abs(v) < 10 ** 21:
+        return str(v)
+    v = float(v)
+    if math.isnan(v):
+        return 'NaN'
+    if math.isinf(v):
+        return 'Infinity' if v > 0 else '-Infinity'
+    if v == 0:
+        return '0'
+    sign = '-' if v < 0 else ''
+
+    # repr gives the shortest digits that round-trip; the value is 0.<digits> * 10 ** n
+    _, digits, exponent = decimal.Decimal(repr(abs(v))).normalize().as_tuple()
+    s = ''.join(str(d) for d in digits)
+    k = len(s)
+    n = k + exponent
+
+    if k <= n <= 21:
+        return sign + s + '0' * (n - k)
+    if 0 < n <= 21:
+        return sign + s[:n] + '.' + s[n:]
+    if -6 < n <= 0:
+        return sign + '0.' + '0' * -n + s
+    mantissa = s if k == 1 else s[0] + '.' + s[1:]
+    return '{}{}e{}{}'.format(sign, mantissa, '+' if n > 0 else '-', abs(n - 1))
+
+
+def integral(v):
+    """An integral float as an int, which JSON serializes as JavaScript does,
+    as 3 rather than 3.0; beyond 2 ** 53 JavaScript does not give every digit,
+    so larger floats are left as they are"""
+    if isinstance(v, float) and v.is_integer() and abs(v) < 2 ** 53:
+        return int(v)
+    return v
+
+
+def json_numbers(v):
+    """A copy of a JSON value with its integral floats as ints"""
+    if isinstance(v, list):
+        return [json_numbers(e) for e in v]
+    if isinstance(v, dict):
+        return type(v)((k, json_numbers(e)) for k, e in v.items())
+    return integral(v)
+
+
+def to_json(v):
+    """Serialize a JSON value compactly with sorted keys, formatting numbers as
+    JavaScript's JSON.stringify does, so that all implementations produce the
+    same string"""
+    if isinstance(v, bool) or v is None:
+        return json.dumps(v)
+    if isinstance(v, number):
+        if isinstance(v, float) and (math.isnan(v) or math.isinf(v)):
+            return 'null'
+        return number_to_str(v)
+    if isinstance(v, list):
+        return '[' + ','.join(to_json(e) for e in v) + ']'
+    if isinstance(v, dict):
+        return '{' + ','.join(json.dumps(k, ensure_ascii=False) + ':' + to_json(v[k]) for k in sorted(v)) + '}'
+    return json.dumps(v, ensure_ascii=False)
+
+
 def stringDate(date):
     # Convert to isoFormat
     try:
@@ -131,3 +253,8 @@ try:
     string = basestring
 except NameError:
     string = str
+
+try:
+    number = (int, long, float)
+except NameError:
+    number = (int, float)
@@ -2,13 +2,20 @@ package jsone
 
 import (
 	"encoding/json"
+	"errors"
 	"fmt"
 	"io/ioutil"
+	"math"
+	"sort"
 	"strings"
 	"testing"
+	"time"
 
 	"github.com/stretchr/testify/require"
 	yaml "gopkg.in/yaml.v2"
+
+	i "github.com/taskcluster/json-e/interpreter"
+	p "github.com/taskcluster/json-e/interpreter/parser"
 )
 
 // testCase represents an entry in specification.yml
@@ -115,3 +122,713 @@ func convertSimpleJSONTypes(val interface{}) interface{} {
 		return val
 	}
 }
+
+func TestCustomFunction(t *testing.T) {
+	context := map[string]interface{}{
+		"double": i.WrapFunction(func(x float64) float64 { return 2 * x }),
+		"x":      float64(4),
+	}
+	result, err := Render(map[string]interface{}{"$eval": "double(x) + 1"}, context)
+	require.NoError(t, err)
+	require.Equal(t, float64(9), result)
+}
+
+func TestCustomFunctionsInNamespace(t *testing.T) {
+	context := map[string]interface{}{
+		"math": map[string]interface{}{
+			"sqrt": i.WrapFunction(math.Sqrt),
+			"abs":  i.WrapFunction(math.Abs),
+		},
+	}
+	result, err := Render(map[string]interface{}{"$eval": "math.sqrt(16) + math.abs(-1)"}, context)
+	require.NoError(t, err)
+	require.Equal(t, float64(5), result)
+}
+
+func TestCustomFunctionOverridesBuiltin(t *testing.T) {
+	context := map[string]interface{}{
+		"min": i.WrapFunction(func(x, y float64) float64 { return x + y }),
+	}
+	result, err := Render(map[string]interface{}{"$eval": "min(3, 4)"}, context)
+	require.NoError(t, err)
+	require.Equal(t, float64(7), result)
+}
+
+func TestCustomFunctionErrors(t *testing.T) {
+	secrets := map[string]string{"my/path": "s3cret"}
+	context := map[string]interface{}{
+		"secret": i.WrapFunction(func(path string) (string, error) {
+			s, ok := secrets[path]
+			if !ok {
+				return "", fmt.Errorf("no secret at %s", path)
+			}
+			return s, nil
+		}),
+	}
+	result, err := Render(map[string]interface{}{"token": "${secret(\"my/\" + \"path\")}"}, context)
+	require.NoError(t, err)
+	require.Equal(t, map[string]interface{}{"token": "s3cret"}, result)
+	_, err = Render(map[string]interface{}{"$eval": "secret(\"other\")"}, context)
+	require.Error(t, err)
+	require.Contains(t, err.Error(), "no secret at other")
+	// WrapFunction checks the number and types of the arguments
+	_, err = Render(map[string]interface{}{"$eval": "secret(\"my/path\", 1)"}, context)
+	require.Error(t, err)
+	_, err = Render(map[string]interface{}{"$eval": "secret(1)"}, context)
+	require.Error(t, err)
+}
+
+func TestUntakenBranchesNotCalled(t *testing.T) {
+	calls := 0
+	context := map[string]interface{}{
+		"expensive": i.WrapFunction(func() float64 {
+			calls++
+			return float64(calls)
+		}),
+	}
+	template := map[string]interface{}{
+		"and":     map[string]interface{}{"$eval": "false && expensive()"},
+		"or":      map[string]interface{}{"$eval": "true || expensive()"},
+		"ternary": map[string]interface{}{"$eval": "true ? 0 : expensive()"},
+		"if": map[string]interface{}{
+			"$if":  "false",
+			"then": map[string]interface{}{"$eval": "expensive()"},
+			"else": "${expensive()}",
+		},
+	}
+	result, err := Render(template, context)
+	require.NoError(t, err)
+	require.Equal(t, map[string]interface{}{"and": false, "or": true, "ternary": 0.0, "if": "1"}, result)
+	require.Equal(t, 1, calls)
+}
+
+func TestRenderWithMaxElements(t *testing.T) {
+	items := make([]interface{}, 100)
+	for idx := range items {
+		items[idx] = float64(idx)
+	}
+	context := map[string]interface{}{"items": items}
+	template := map[string]interface{}{
+		"$map": map[string]interface{}{"$eval": "items"},
+		"each(x)": map[string]interface{}{
+			"$map":    map[string]interface{}{"$eval": "items"},
+			"each(y)": map[string]interface{}{"$eval": "x * y"},
+		},
+	}
+
+	result, err := Render(template, context)
+	require.NoError(t, err)
+	require.Len(t, result, 100)
+
+	result, err = RenderWithOptions(template, context, RenderOptions{MaxElements: 100*100 + 100})
+	require.NoError(t, err)
+	require.Len(t, result, 100)
+
+	_, err = RenderWithOptions(template, context, RenderOptions{MaxElements: 1000})
+	require.Error(t, err)
+	require.Contains(t, err.Error(), "output exceeds the maximum of 1000 elements")
+}
+
+func TestRenderWithMaxSteps(t *testing.T) {
+	items := make([]interface{}, 100)
+	for idx := range items {
+		items[idx] = float64(idx)
+	}
+	context := map[string]interface{}{"items": items}
+	template := map[string]interface{}{
+		"$map": map[string]interface{}{"$eval": "items"},
+		"each(x)": map[string]interface{}{
+			"$map":    map[string]interface{}{"$eval": "items"},
+			"each(y)": map[string]interface{}{"$eval": "x * y"},
+		},
+	}
+
+	result, err := RenderWithOptions(template, context, RenderOptions{MaxSteps: 100000})
+	require.NoError(t, err)
+	require.Len(t, result, 100)
+
+	_, err = RenderWithOptions(template, context, RenderOptions{MaxSteps: 10000})
+	require.Error(t, err)
+	require.Contains(t, err.Error(), "evaluation budget exceeded")
+
+	// each node of an expression is a step
+	result, err = RenderWithOptions(map[string]interface{}{"$eval": "1 + 2 * 3"}, context, RenderOptions{MaxSteps: 5})
+	require.NoError(t, err)
+	require.Equal(t, float64(7), result)
+	_, err = RenderWithOptions("${1 + 2 * 3}", context, RenderOptions{MaxSteps: 4})
+	require.Error(t, err)
+}
+
+func TestRenderWithMaxDepth(t *testing.T) {
+	// an object, an array, and an operator
+	template := map[string]interface{}{"a": []interface{}{map[string]interface{}{"$eval": "1"}}}
+	result, err := RenderWithOptions(template, nil, RenderOptions{MaxDepth: 3})
+	require.NoError(t, err)
+	require.Equal(t, map[string]interface{}{"a": []interface{}{1.0}}, result)
+	_, err = RenderWithOptions(template, nil, RenderOptions{MaxDepth: 2})
+	require.Error(t, err)
+	require.Contains(t, err.Error(), "template nesting exceeds the maximum depth of 2")
+
+	var deep interface{} = 1.0
+	for idx := 0; idx < 100000; idx++ {
+		deep = []interface{}{deep}
+	}
+	_, err = RenderWithOptions(deep, nil, RenderOptions{MaxDepth: 100})
+	require.Error(t, err)
+}
+
+func TestRenderWithLimits(t *testing.T) {
+	var limit LimitExceededError
+	context := map[string]interface{}{"x": 1.0, "s": "abcd"}
+
+	// however deeply an expression nests, it does not exhaust the stack
+	deep := map[string]interface{}{"$eval": strings.Repeat("(", 5000) + "1" + strings.Repeat(")", 5000)}
+	_, err := RenderWithOptions(deep, context, RenderOptions{MaxDepth: 10, MaxSteps: 100})
+	require.True(t, errors.As(err, &limit))
+	require.Contains(t, err.Error(), "expression is nested more than 64 levels deep")
+
+	options := RenderOptions{MaxExpressionLength: 5}
+	result, err := RenderWithOptions([]interface{}{map[string]interface{}{"$eval": "x + 1"}, "${x + 1}"}, context, options)
+	require.NoError(t, err)
+	require.Equal(t, []interface{}{2.0, "2"}, result)
+	_, err = RenderWithOptions(map[string]interface{}{"$eval": "x + 10"}, context, options)
+	require.True(t, errors.As(err, &limit))
+	_, err = RenderWithOptions("${x + 10}", context, options)
+	require.True(t, errors.As(err, &limit))
+	require.Contains(t, err.Error(), "expression exceeds the maximum length of 5")
+
+	options = RenderOptions{MaxInterpolationLength: 10}
+	result, err = RenderWithOptions("<${s}${s}>", context, options)
+	require.NoError(t, err)
+	require.Equal(t, "<abcdabcd>", result)
+	_, err = RenderWithOptions("<${s}${s}>!", context, options)
+	require.True(t, errors.As(err, &limit))
+	_, err = RenderWithOptions("${s}${s}${s}", context, options)
+	require.True(t, errors.As(err, &limit))
+	require.Contains(t, err.Error(), "interpolation exceeds the maximum length of 10")
+
+	template := map[string]interface{}{"$map": map[string]interface{}{"$eval": "items"}, "each(y)": map[string]interface{}{"$eval": "y * 2"}}
+	items := make([]interface{}, 1000)
+	for idx := range items {
+		items[idx] = float64(idx)
+	}
+	context["items"] = items
+	result, err = RenderWithOptions(template, context, RenderOptions{Timeout: time.Minute})
+	require.NoError(t, err)
+	require.Len(t, result, 1000)
+	_, err = RenderWithOptions(template, context, RenderOptions{Timeout: time.Nanosecond})
+	require.True(t, errors.As(err, &limit))
+	require.Contains(t, err.Error(), "render exceeds the timeout of 1ns")
+
+	// the other limits are LimitExceededErrors too
+	_, err = RenderWithOptions(template, context, RenderOptions{MaxSteps: 1})
+	require.True(t, errors.As(err, &limit))
+}
+
+func TestRenderWithPartial(t *testing.T) {
+	template := map[string]interface{}{
+		"greeting": "hello ${name}",
+		"count":    map[string]interface{}{"$eval": "x + 1"},
+		"items":    []interface{}{"${y}", map[string]interface{}{"$if": "x > 0", "then": "positive"}},
+		"${key}":   "keyed",
+		"escaped":  map[string]interface{}{"$eval": "dollar"},
+		"scoped":   map[string]interface{}{"$let": map[string]interface{}{"a": 1.0}, "in": "${a}${name}"},
+	}
+	first, err := RenderWithOptions(template, map[string]interface{}{"x": 1.0, "dollar": "${y}"}, RenderOptions{Partial: true})
+	require.NoError(t, err)
+	require.Equal(t, map[string]interface{}{
+		"greeting": "hello ${name}",
+		"count":    2.0,
+		"items":    []interface{}{"${y}", "positive"},
+		"${key}":   "keyed",
+		"escaped":  map[string]interface{}{"$$": "${y}"},
+		"scoped":   map[string]interface{}{"$let": map[string]interface{}{"a": 1.0}, "in": "${a}${name}"},
+	}, first)
+
+	result, err := Render(first, map[string]interface{}{"name": "pat", "y": "why", "key": "k"})
+	require.NoError(t, err)
+	require.Equal(t, map[string]interface{}{
+		"greeting": "hello pat",
+		"count":    2.0,
+		"items":    []interface{}{"why", "positive"},
+		"k":        "keyed",
+		"escaped":  "${y}",
+		"scoped":   "1pat",
+	}, result)
+
+	// other errors are still errors, and without Partial so are undefined
+	// variables
+	_, err = RenderWithOptions(map[string]interface{}{"a": "${1 + true}"}, nil, RenderOptions{Partial: true})
+	require.Error(t, err)
+	_, err = Render("${x}", nil)
+	require.Error(t, err)
+}
+
+func TestInterpolationErrors(t *testing.T) {
+	context := map[string]interface{}{
+		"list": []interface{}{1.0},
+		"f":    i.WrapFunction(func() float64 { return 1 }),
+	}
+	var templateError TemplateError
+
+	_, err := Render("a ${list}", context)
+	require.True(t, errors.As(err, &templateError))
+	require.Equal(t, "interpolation of 'list' produced an array or object", templateError.Message)
+	require.Equal(t, "a ${list}", templateError.Template)
+
+	_, err = Render("${f} b", context)
+	require.True(t, errors.As(err, &templateError))
+	require.Equal(t, "interpolation of 'f' produced a function", templateError.Message)
+	require.Equal(t, "${f} b", templateError.Template)
+}
+
+func TestRenderPreservesLargeNumbers(t *testing.T) {
+	decoder := json.NewDecoder(strings.NewReader(`{
+		"big": 123456789012345678901234567890,
+		"list": [9007199254740993, 0.1, 2],
+		"let": {"$let": {"x": 9007199254740993}, "in": {"v": {"$eval": "x"}, "s": "${x}", "t": {"$eval": "typeof(x)"}, "n": {"$eval": "!x"}}}
+	}`))
+	decoder.UseNumber()
+	var template interface{}
+	require.NoError(t, decoder.Decode(&template))
+
+	result, err := Render(template, nil)
+	require.NoError(t, err)
+	// numbers that fit in a float64 are rendered as usual
+	require.Equal(t, 2.0, result.(map[string]interface{})["list"].([]interface{})[2])
+	output, err := json.Marshal(result)
+	require.NoError(t, err)
+	require.Equal(t, `{"big":123456789012345678901234567890,`+
+		`"let":{"n":false,"s":"9007199254740993","t":"number","v":9007199254740993},`+
+		`"list":[9007199254740993,0.1,2]}`, string(output))
+
+	// such numbers compare exactly, and are numbers to ExpectType
+	decoder = json.NewDecoder(strings.NewReader(`{"$let": {"x": 9007199254740993, "y": 9007199254740993}, "in": [
+		{"$eval": "x == y"}, {"$eval": "x == 9007199254740992"}, {"$eval": "x in [1, y]"},
+		{"$eval": "indexOf([y], x)"}, {"$eval": "str(x)"}
+	]}`))
+	decoder.UseNumber()
+	require.NoError(t, decoder.Decode(&template))
+	result, err = Render(template, nil)
+	require.NoError(t, err)
+	require.Equal(t, []interface{}{true, false, true, 0.0, "9007199254740993"}, result)
+
+	decoder = json.NewDecoder(strings.NewReader(`{"$let": {"x": 9007199254740993}, "in": {"$eval": "x"}}`))
+	decoder.UseNumber()
+	require.NoError(t, decoder.Decode(&template))
+	result, err = RenderWithOptions(template, nil, RenderOptions{ExpectType: "number"})
+	require.NoError(t, err)
+	require.Equal(t, json.Number("9007199254740993"), result)
+}
+
+func TestRenderWithExpectType(t *testing.T) {
+	template := map[string]interface{}{"a": map[string]interface{}{"$eval": "x"}}
+	context := map[string]interface{}{"x": 1.0}
+
+	result, err := RenderWithOptions(template, context, RenderOptions{ExpectType: "object"})
+	require.NoError(t, err)
+	require.Equal(t, map[string]interface{}{"a": 1.0}, result)
+
+	result, err = RenderWithOptions(map[string]interface{}{"$if": "false", "then": 1.0}, context, RenderOptions{ExpectType: "null"})
+	require.NoError(t, err)
+	require.Nil(t, result)
+
+	_, err = RenderWithOptions("${x}", context, RenderOptions{ExpectType: "object"})
+	require.Error(t, err)
+	require.Contains(t, err.Error(), "rendered output is of type string, expected object")
+}
+
+func TestRenderKeyOrder(t *testing.T) {
+	template := map[string]interface{}{"z": 1.0, "\U00010000": 2.0, "\uffff": 1.0}
+	template["m"] = map[string]interface{}{
+		"$merge": []interface{}{map[string]interface{}{"y": 1.0, "a": 1.0}, map[string]interface{}{"b": 1.0}},
+	}
+	result, err := Render(template, nil)
+	require.NoError(t, err)
+	output, err := json.Marshal(result)
+	require.NoError(t, err)
+	// by code point, so characters above the base plane come last
+	require.Equal(t, `{"m":{"a":1,"b":1,"y":1},"z":1,"\uffff":1,"\U00010000":2}`, string(output))
+}
+
+func TestRenderIsDeterministic(t *testing.T) {
+	template := map[string]interface{}{
+		"z": 1.0,
+		"a": map[string]interface{}{"$eval": "x"},
+		"m": map[string]interface{}{"c": 3.0, "b": 2.0},
+	}
+	context := map[string]interface{}{"x": 2.0}
+
+	first, err := Render(template, context)
+	require.NoError(t, err)
+	second, err := Render(template, context)
+	require.NoError(t, err)
+
+	firstJSON, err := json.Marshal(first)
+	require.NoError(t, err)
+	secondJSON, err := json.Marshal(second)
+	require.NoError(t, err)
+	require.Equal(t, `{"a":2,"m":{"b":2,"c":3},"z":1}`, string(firstJSON))
+	require.Equal(t, string(firstJSON), string(secondJSON))
+}
+
+func TestRenderEach(t *testing.T) {
+	items := make([]interface{}, 10000)
+	for idx := range items {
+		items[idx] = float64(idx)
+	}
+	context := map[string]interface{}{"items": items}
+	template := map[string]interface{}{
+		"$map": map[string]interface{}{"$eval": "items"},
+		"each(x,i)": map[string]interface{}{
+			"$if":  "i % 2 == 0",
+			"then": map[string]interface{}{"$eval": "x * 2"},
+		},
+	}
+
+	count := 0
+	var first, last interface{}
+	err := RenderEach(template, context, RenderOptions{}, func(value interface{}) error {
+		if count == 0 {
+			first = value
+		}
+		last = value
+		count++
+		return nil
+	})
+	require.NoError(t, err)
+	require.Equal(t, 5000, count)
+	require.Equal(t, 0.0, first)
+	require.Equal(t, 19996.0, last)
+
+	var values []interface{}
+	err = RenderEach([]interface{}{
+		map[string]interface{}{"$eval": "a"},
+		map[string]interface{}{"$if": "false", "then": 1.0},
+		"x${a}",
+	}, map[string]interface{}{"a": 1.0}, RenderOptions{}, func(value interface{}) error {
+		values = append(values, value)
+		return nil
+	})
+	require.NoError(t, err)
+	require.Equal(t, []interface{}{1.0, "x1"}, values)
+
+	err = RenderEach(map[string]interface{}{"a": 1.0}, nil, RenderOptions{}, func(interface{}) error { return nil })
+	require.Error(t, err)
+	require.Contains(t, err.Error(), "RenderEach requires a template that renders to an array")
+
+	count = 0
+	err = RenderEach(template, context, RenderOptions{MaxElements: 10}, func(interface{}) error {
+		count++
+		return nil
+	})
+	require.Error(t, err)
+	require.Contains(t, err.Error(), "output exceeds the maximum of 10 elements")
+	require.Equal(t, 10, count)
+}
+
+func TestRenderDoesNotModifyContext(t *testing.T) {
+	newContext := func() map[string]interface{} {
+		return map[string]interface{}{
+			"items": []interface{}{3.0, 1.0, 2.0},
+			"objs": []interface{}{
+				map[string]interface{}{"a": append(make([]interface{}, 0, 4), 1.0)},
+				map[string]interface{}{"a": []interface{}{2.0}},
+			},
+			"o": map[string]interface{}{"x": 1.0},
+		}
+	}
+	template := map[string]interface{}{
+		"$let": map[string]interface{}{"y": map[string]interface{}{"$eval": "o.x"}},
+		"in": map[string]interface{}{
+			"m": map[string]interface{}{
+				"$map":      map[string]interface{}{"$eval": "items"},
+				"each(x,i)": map[string]interface{}{"$eval": "x + y + i"},
+			},
+			"s": map[string]interface{}{"$sort": map[string]interface{}{"$eval": "items"}},
+			"r": map[string]interface{}{"$reverse": map[string]interface{}{"$eval": "items"}},
+			"d": map[string]interface{}{"$mergeDeep": map[string]interface{}{"$eval": "objs"}},
+			"f": map[string]interface{}{
+				"$map":      map[string]interface{}{"$eval": "o"},
+				"each(v,k)": map[string]interface{}{"${k}": map[string]interface{}{"$eval": "v"}},
+			},
+		},
+	}
+
+	context := newContext()
+	_, err := Render(template, context)
+	require.NoError(t, err)
+	require.Equal(t, newContext(), context)
+	// appending to the first list must not have written into its spare capacity
+	a := context["objs"].([]interface{})[0].(map[string]interface{})["a"].([]interface{})
+	require.Nil(t, a[:2][1])
+}
+
+func TestRenderMapWithLargeContext(t *testing.T) {
+	items := make([]interface{}, 10000)
+	context := map[string]interface{}{"items": items}
+	for idx := range items {
+		items[idx] = float64(idx)
+		context[fmt.Sprintf("v%d", idx)] = float64(idx)
+	}
+	template := map[string]interface{}{
+		"$map": map[string]interface{}{"$eval": "items"},
+		"each(x)": map[string]interface{}{
+			"$eval": "x + v1 + v9999",
+		},
+	}
+
+	result, err := Render(template, context)
+	require.NoError(t, err)
+	require.Len(t, result, 10000)
+	require.Equal(t, 10000.0, result.([]interface{})[0])
+	require.Equal(t, 19999.0, result.([]interface{})[9999])
+}
+
+func TestRenderRepeatedExpression(t *testing.T) {
+	// expressions are parsed once per render, but evaluated in each context
+	context := map[string]interface{}{"items": []interface{}{1.0, 2.0, 3.0}}
+	template := map[string]interface{}{
+		"$map": map[string]interface{}{"$eval": "items"},
+		"each(x)": map[string]interface{}{
+			"a": map[string]interface{}{"$eval": "x * 2"},
+			"b": "${x}-${x * 2}",
+		},
+	}
+
+	for n := 0; n < 2; n++ {
+		result, err := Render(template, context)
+		require.NoError(t, err)
+		require.Equal(t, []interface{}{
+			map[string]interface{}{"a": 2.0, "b": "1-2"},
+			map[string]interface{}{"a": 4.0, "b": "2-4"},
+			map[string]interface{}{"a": 6.0, "b": "3-6"},
+		}, result)
+	}
+
+	_, err := Render(map[string]interface{}{
+		"$map":    []interface{}{1.0, 2.0},
+		"each(x)": map[string]interface{}{"$eval": "x +"},
+	}, nil)
+	require.Error(t, err)
+}
+
+func TestUserMessage(t *testing.T) {
+	_, err := Render(map[string]interface{}{"$eval": "1 + true"}, nil)
+	require.Error(t, err)
+	require.Equal(t, "Expected either number of string operands", UserMessage(err))
+	require.Contains(t, err.Error(), `in template {"$eval":"1 + true"}`)
+
+	_, err = Render(map[string]interface{}{"a": map[string]interface{}{"$eval": "2 * * 3"}}, nil)
+	require.Error(t, err)
+	require.Equal(t, "Found '*', expected !, (, +, -, [, false, identifier, null, number, string, true, {", UserMessage(err))
+	require.Contains(t, err.Error(), "at 4 -> '*' in '2 * * 3'")
+
+	_, err = Render(map[string]interface{}{"$if": "true", "then": 1.0, "els": 2.0}, nil)
+	require.Error(t, err)
+	require.Equal(t, "property 'els' is not permitted in template", UserMessage(err))
+}
+
+func TestBuiltinNames(t *testing.T) {
+	names := BuiltinNames()
+	require.Contains(t, names, "min")
+	require.Contains(t, names, "fromNow")
+	require.Contains(t, names, "uppercase")
+	require.NotContains(t, names, "now")
+	require.Len(t, names, len(builtin))
+	require.True(t, sort.StringsAreSorted(names))
+}
+
+func TestStaticResultType(t *testing.T) {
+	// an empty string means the type depends on the context
+	for expression, expected := range map[string]string{
+		"1 + 2":         "number",
+		`"a" + 'b'`:     "string",
+		"x == 1 || !y":  "boolean",
+		"null":          "null",
+		"[x, y][1:]":    "array",
+		"{a: x}":        "object",
+		"x ? 1 : 2 * y": "number",
+		"x":             "",
+		"x.y":           "",
+		"len(x)":        "",
+		`x ? 1 : "a"`:   "",
+	} {
+		result, err := i.StaticResultType(expression)
+		require.NoError(t, err)
+		require.Equal(t, expected, result, expression)
+	}
+	_, err := i.StaticResultType("1 +")
+	require.Error(t, err)
+}
+
+func TestFreeVariables(t *testing.T) {
+	names, err := i.FreeVariables(`a.b + len(c[d:]) > 0 ? {k: e, m: e} : [f?.g, "x"]`)
+	require.NoError(t, err)
+	require.Equal(t, []string{"a", "c", "d", "e", "f", "len"}, names)
+	names, err = i.FreeVariables("1 + 2")
+	require.NoError(t, err)
+	require.Empty(t, names)
+	tree, err := i.ParseExpression("x + 1")
+	require.NoError(t, err)
+	require.IsType(t, p.BinOp{}, tree)
+	_, err = i.FreeVariables("1 +")
+	require.Error(t, err)
+}
+
+func TestTokenize(t *testing.T) {
+	tokens, err := i.Tokenize("1 + foo * 'x'")
+	require.NoError(t, err)
+	require.Equal(t, []p.Token{
+		{Kind: "number", Value: "1", Start: 0, End: 1},
+		{Kind: "+", Value: "+", Start: 2, End: 3},
+		{Kind: "identifier", Value: "foo", Start: 4, End: 7},
+		{Kind: "*", Value: "*", Start: 8, End: 9},
+		{Kind: "string", Value: "'x'", Start: 10, End: 13},
+	}, tokens)
+	tokens, err = i.Tokenize("2**3")
+	require.NoError(t, err)
+	require.Len(t, tokens, 3)
+	require.Equal(t, "**", tokens[1].Kind)
+	_, err = i.Tokenize("1 # 2")
+	require.Error(t, err)
+}
+
+func TestValidate(t *testing.T) {
+	template := map[string]interface{}{
+		"a": map[string]interface{}{"$if": "x", "then": 1.0, "els": 2.0},
+		"b": []interface{}{map[string]interface{}{"$map": []interface{}{1.0, 2.0}, "each(1x)": 3.0}},
+		"c": map[string]interface{}{"$foo": 1.0},
+		"d": map[string]interface{}{"$sort": []interface{}{1.0}, "by(x)": "x", "by(y)": "y"},
+	}
+	errs := Validate(template)
+	require.Len(t, errs, 4)
+	require.Contains(t, errs[0].Error(), "property 'els' is not permitted")
+	require.Contains(t, errs[1].Error(), "$map requires a property on the form 'each(identifier)'")
+	require.Contains(t, errs[2].Error(), "is reserved")
+	require.Contains(t, errs[3].Error(), "only one by(..) is allowed")
+
+	valid := map[string]interface{}{
+		"$let": map[string]interface{}{"x": 1.0},
+		"in": map[string]interface{}{
+			"a": map[string]interface{}{
+				"$map":    []interface{}{1.0, 2.0},
+				"each(y)": map[string]interface{}{"$switch": map[string]interface{}{"y == 1": "one", "$default": "many"}},
+			},
+			"$$b": 2.0,
+			"c": map[string]interface{}{
+				"$$": map[string]interface{}{"$frobnicate": 1.0, "$if": "x", "else": 2.0},
+			},
+		},
+	}
+	require.Empty(t, Validate(valid))
+}
+
+func TestRenderInto(t *testing.T) {
+	var result struct {
+		Name  string   `json:"name"`
+		Tags  []string `json:"tags"`
+		Count int      `json:"count"`
+	}
+	template := map[string]interface{}{
+		"name":  "${name}",
+		"tags":  map[string]interface{}{"$map": []interface{}{"a", "b"}, "each(x)": "tag-${x}"},
+		"count": map[string]interface{}{"$eval": "1 + 2"},
+	}
+	err := RenderInto(template, map[string]interface{}{"name": "json-e"}, &result)
+	require.NoError(t, err)
+	require.Equal(t, "json-e", result.Name)
+	require.Equal(t, []string{"tag-a", "tag-b"}, result.Tags)
+	require.Equal(t, 3, result.Count)
+
+	err = RenderInto(map[string]interface{}{"$eval": "missing"}, nil, &result)
+	require.Error(t, err)
+	var count int
+	err = RenderInto("not a number", nil, &count)
+	require.Error(t, err)
+}
+
+func TestCompile(t *testing.T) {
+	template, err := Compile(map[string]interface{}{
+		"$map":    map[string]interface{}{"$eval": "items"},
+		"each(x)": map[string]interface{}{"a": map[string]interface{}{"$eval": "x * n"}, "b": "${x}-${x * n}"},
+	})
+	require.NoError(t, err)
+	for _, n := range []float64{1, 2, 3} {
+		result, err := template.Render(map[string]interface{}{"items": []interface{}{1.0, 2.0}, "n": n}, RenderOptions{})
+		require.NoError(t, err)
+		require.Equal(t, []interface{}{
+			map[string]interface{}{"a": n, "b": fmt.Sprintf("1-%v", n)},
+			map[string]interface{}{"a": 2 * n, "b": fmt.Sprintf("2-%v", 2*n)},
+		}, result)
+	}
+
+	// options apply to each render
+	_, err = template.Render(map[string]interface{}{"items": []interface{}{1.0, 2.0}, "n": 1.0}, RenderOptions{MaxSteps: 3})
+	require.Error(t, err)
+	result, err := template.Render(map[string]interface{}{"items": []interface{}{}, "n": 1.0}, RenderOptions{MaxSteps: 3})
+	require.NoError(t, err)
+	require.Equal(t, []interface{}{}, result)
+
+	_, err = Compile(map[string]interface{}{"$if": "x", "then": 1.0, "els": 2.0})
+	require.Error(t, err)
+
+	// expressions are parsed by Compile, even those a render would not reach
+	_, err = Compile(map[string]interface{}{"$eval": "1 +"})
+	require.Error(t, err)
+	_, err = Compile(map[string]interface{}{"$if": "true", "then": 1.0, "else": "${x +}"})
+	require.Error(t, err)
+	_, err = Compile(map[string]interface{}{"a": "$${x +} ${x}"})
+	require.NoError(t, err)
+}
+
+func benchmarkRender(b *testing.B, template interface{}, context map[string]interface{}) {
+	for n := 0; n < b.N; n++ {
+		if _, err := Render(template, context); err != nil {
+			b.Fatal(err)
+		}
+	}
+}
+
+func BenchmarkRenderArithmetic(b *testing.B) {
+	template := map[string]interface{}{"$eval": "(a + b) * c - a / b + c ** 2 % 7"}
+	context := map[string]interface{}{"a": 3.0, "b": 4.0, "c": 5.0}
+	benchmarkRender(b, template, context)
+}
+
+func BenchmarkRenderMap(b *testing.B) {
+	items := make([]interface{}, 10000)
+	for idx := range items {
+		items[idx] = float64(idx)
+	}
+	template := map[string]interface{}{
+		"$map": map[string]interface{}{"$eval": "items"},
+		"each(x)": map[string]interface{}{
+			"$if":  "x % 2 == 0",
+			"then": map[string]interface{}{"a": map[string]interface{}{"$eval": "x * 2"}},
+			"else": "${x}",
+		},
+	}
+	benchmarkRender(b, template, map[string]interface{}{"items": items})
+}
+
+func BenchmarkRenderInterpolation(b *testing.B) {
+	template := map[string]interface{}{
+		"a": map[string]interface{}{
+			"b": map[string]interface{}{
+				"c": []interface{}{"${x} and ${y.z} ${x + 1}"},
+			},
+		},
+	}
+	context := map[string]interface{}{"x": 1.0, "y": map[string]interface{}{"z": "q"}}
+	benchmarkRender(b, template, context)
+}
+
+func BenchmarkRenderManyInterpolations(b *testing.B) {
+	template := make([]interface{}, 1000)
+	for idx := range template {
+		template[idx] = fmt.Sprintf("item-%d ${x}/${y.z}", idx)
+	}
+	context := map[string]interface{}{"x": 1.0, "y": map[string]interface{}{"z": "q"}}
+	benchmarkRender(b, template, context)
+}
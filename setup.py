@@ -22,12 +22,20 @@ setup(name='json-e',
     url='https://taskcluster.github.io/json-e/',
     author_email='dustin@mozilla.com',
     packages=['jsone'],
+    entry_points={
+        'console_scripts': [
+            'json-e = jsone.__main__:main',
+        ],
+    },
     test_suite='nose.collector',
     license='MPL2',
     extras_require={
         'release': [
             'towncrier',
         ],
+        'yaml': [
+            'PyYAML',
+        ],
     },
     tests_require=[
         "freezegun",
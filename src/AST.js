@@ -24,6 +24,16 @@ class UnaryOp extends ASTNode {
     }
 }
 
+class TernaryOp extends ASTNode {
+    constructor(token, condition, thenExpr, elseExpr) {
+        super(token);
+        this.constructorName = 'TernaryOp';
+        this.condition = condition;
+        this.thenExpr = thenExpr;
+        this.elseExpr = elseExpr;
+    }
+}
+
 class FunctionCall extends ASTNode {
     constructor(token, name, args) {
         super(token);
@@ -71,6 +81,7 @@ class Object extends ASTNode {
 exports.ASTNode = ASTNode;
 exports.BinOp = BinOp;
 exports.UnaryOp = UnaryOp;
+exports.TernaryOp = TernaryOp;
 exports.Primitive = Primitive;
 exports.FunctionCall = FunctionCall;
 exports.ContextValue = ContextValue;
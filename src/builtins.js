@@ -1,5 +1,7 @@
-var {BuiltinError} = require('./error');
+var {BuiltinError, UndefinedVariableError} = require('./error');
 var fromNow = require('./from-now');
+var mergeDeep = require('./merge');
+var {isEqual, compare} = require('./interpreter');
 var {
   isString, isNumber, isBool,
   isArray, isObject, isJSON,
@@ -26,14 +28,23 @@ module.exports = (context) => {
     minArgs = false,
     variadic = null,
     needsContext = false,
+    lazy = false,
     invoke,
   }) => {
     context[name] = (...args) => {
       let ctx = args.shift();
+      if (lazy) {
+        // arguments are unevaluated thunks; the builtin checks them itself
+        return invoke(...args);
+      }
       if (!variadic && args.length < argumentTests.length) {
         throw builtinError(`builtin: ${name}`, `${args.toString()}, too few arguments`);
       }
 
+      if (!variadic && args.length > argumentTests.length) {
+        throw builtinError(`builtin: ${name}`);
+      }
+
       if (minArgs && args.length < minArgs) {
         throw builtinError(`builtin: ${name}: expected at least ${minArgs} arguments`);
       }
@@ -53,6 +64,9 @@ module.exports = (context) => {
       return invoke(...args);
     };
     context[name].jsone_builtin = true;
+    if (lazy) {
+      context[name].jsone_lazy = true;
+    }
 
     return context[name];
   };
@@ -64,8 +78,20 @@ module.exports = (context) => {
     }
     define(name, builtins, {
       minArgs: 1,
-      variadic: 'number',
-      invoke: (...args) => Math[name](...args),
+      variadic: 'number|array',
+      invoke: (...args) => {
+        // a single array argument gives the numbers to compare
+        if (args.length === 1 && isArray(args[0])) {
+          args = args[0];
+          if (args.length === 0) {
+            throw builtinError(`builtin: ${name}: expected at least 1 arguments`);
+          }
+        }
+        if (!args.every(isNumber)) {
+          throw builtinError(`builtin: ${name}`);
+        }
+        return Math[name](...args);
+      },
     });
   });
 
@@ -79,6 +105,43 @@ module.exports = (context) => {
     });
   });
 
+  define('sign', builtins, {
+    argumentTests: ['number'],
+    // Math.sign gives -0 for -0, which equals 0 but is not the same value
+    invoke: num => Math.sign(num) || 0,
+  });
+
+  define('round', builtins, {
+    minArgs: 1,
+    variadic: 'number',
+    // round half away from zero to the given number of decimal places
+    invoke: (num, digits = 0, ...rest) => {
+      if (rest.length > 0 || !Number.isInteger(digits) || digits < 0) {
+        throw builtinError('builtin: round');
+      }
+      let factor = Math.pow(10, digits);
+      let scaled = Math.abs(num) * factor;
+      if (!isFinite(scaled)) {
+        return num;
+      }
+      let rounded = Math.floor(scaled);
+      if (scaled - rounded >= 0.5) {
+        rounded += 1;
+      }
+      return Math.sign(num) * rounded / factor;
+    },
+  });
+
+  define('clamp', builtins, {
+    argumentTests: ['number', 'number', 'number'],
+    invoke: (value, lo, hi) => {
+      if (lo > hi) {
+        throw builtinError('builtin: clamp');
+      }
+      return Math.min(Math.max(value, lo), hi);
+    },
+  });
+
   // String manipulation
   define('lowercase', builtins, {
     argumentTests: ['string'],
@@ -105,11 +168,69 @@ module.exports = (context) => {
     invoke: Number,
   });
 
+  // Array manipulation
+  define('flatten', builtins, {
+    argumentTests: ['array'],
+    invoke: arr => arr.reduce((a, b) => a.concat(b), []),
+  });
+
+  let flattenDeep = a => isArray(a) ? [].concat(...a.map(flattenDeep)) : a;
+  define('flattenDeep', builtins, {
+    argumentTests: ['array'],
+    invoke: flattenDeep,
+  });
+
+  define('enumerate', builtins, {
+    argumentTests: ['array'],
+    invoke: arr => arr.map((v, i) => [i, v]),
+  });
+
+  define('zip', builtins, {
+    minArgs: 2,
+    variadic: 'array',
+    invoke: (...arrays) => {
+      let length = Math.min(...arrays.map(arr => arr.length));
+      let result = [];
+      for (let i = 0; i < length; i++) {
+        result.push(arrays.map(arr => arr[i]));
+      }
+      return result;
+    },
+  });
+
+  // Object manipulation
+  define('keys', builtins, {
+    argumentTests: ['object'],
+    invoke: obj => Object.keys(obj).sort(compare),
+  });
+
+  define('values', builtins, {
+    argumentTests: ['object'],
+    invoke: obj => Object.keys(obj).sort(compare).map(k => obj[k]),
+  });
+
+  define('merge', builtins, {
+    minArgs: 1,
+    variadic: 'object',
+    invoke: (...objects) => Object.assign({}, ...objects),
+  });
+
+  define('mergeDeep', builtins, {
+    minArgs: 1,
+    variadic: 'object',
+    invoke: (...objects) => objects.reduce(mergeDeep, {}),
+  });
+
   define('len', builtins, {
     argumentTests: ['string|array'],
     invoke: obj => Array.from(obj).length,
   });
 
+  define('size', builtins, {
+    argumentTests: ['string|array|object'],
+    invoke: obj => isObject(obj) ? Object.keys(obj).length : Array.from(obj).length,
+  });
+
   define('strip', builtins, {
     argumentTests: ['string'],
     invoke: str => str.trim(),
@@ -125,6 +246,43 @@ module.exports = (context) => {
     invoke: str => str.replace(/^\s+/, ''),
   });
 
+  define('split', builtins, {
+    argumentTests: ['string', 'string'],
+    // an empty separator splits into characters (code points, as in indexing)
+    invoke: (str, sep) => sep === '' ? Array.from(str) : str.split(sep),
+  });
+
+  define('join', builtins, {
+    argumentTests: ['array', 'string'],
+    invoke: (arr, sep) => {
+      if (!arr.every(v => isString(v) || isNumber(v))) {
+        throw builtinError('builtin: join');
+      }
+      return arr.join(sep);
+    },
+  });
+
+  define('indexOf', builtins, {
+    argumentTests: ['string|array', 'json'],
+    invoke: (haystack, needle) => {
+      if (isArray(haystack)) {
+        return haystack.findIndex(v => isEqual(v, needle));
+      }
+      if (!isString(needle)) {
+        throw builtinError('builtin: indexOf');
+      }
+      // count code points, as in indexing
+      let index = haystack.indexOf(needle);
+      return index === -1 ? -1 : Array.from(haystack.slice(0, index)).length;
+    },
+  });
+
+  define('reverse', builtins, {
+    argumentTests: ['string|array'],
+    // reverse strings by code point, so that surrogate pairs stay together
+    invoke: obj => isString(obj) ? Array.from(obj).reverse().join('') : obj.slice().reverse(),
+  });
+
   // Miscellaneous
   define('fromNow', builtins, {
     variadic: 'string',
@@ -151,7 +309,25 @@ module.exports = (context) => {
   define('defined', builtins, {
     argumentTests: ['string'],
     needsContext: true,
-    invoke: (ctx, str) => ctx.hasOwnProperty(str)
+    invoke: (ctx, str) => str in ctx
+  });
+
+  define('default', builtins, {
+    lazy: true,
+    invoke: (...args) => {
+      if (args.length !== 2) {
+        throw builtinError('builtin: default');
+      }
+      let [value, fallback] = args;
+      try {
+        return value();
+      } catch (err) {
+        if (err instanceof UndefinedVariableError) {
+          return fallback();
+        }
+        throw err;
+      }
+    },
   });
 
   return Object.assign({}, builtins, context);
@@ -17,11 +17,15 @@ class JSONTemplateError extends Error {
   }
 }
 
+// start and end are the offsets of the part of the expression that could not
+// be parsed, when known
 class SyntaxError extends JSONTemplateError {
-  constructor(message) {
+  constructor(message, {start, end} = {}) {
     super(message);
     this.message = message;
     this.name = 'SyntaxError';
+    this.start = start;
+    this.end = end;
   }
 }
 
@@ -40,6 +44,14 @@ class InterpreterError extends BaseError {
   }
 }
 
+// An InterpreterError for a variable that is not in the context
+class UndefinedVariableError extends InterpreterError {
+  constructor(message, variable) {
+    super(message);
+    this.variable = variable;
+  }
+}
+
 class TemplateError extends BaseError {
   constructor(message) {
     super(message);
@@ -47,6 +59,11 @@ class TemplateError extends BaseError {
   }
 }
 
+// A TemplateError for a render that exceeds one of its limits, or an expression
+// nested too deeply to parse
+class LimitExceededError extends TemplateError {
+}
+
 class BuiltinError extends BaseError {
   constructor(message) {
     super(message);
@@ -54,4 +71,7 @@ class BuiltinError extends BaseError {
   }
 }
 
-module.exports = {JSONTemplateError, SyntaxError, InterpreterError, TemplateError, BuiltinError};
+module.exports = {
+  JSONTemplateError, SyntaxError, InterpreterError, UndefinedVariableError, TemplateError, LimitExceededError,
+  BuiltinError,
+};
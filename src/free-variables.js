@@ -0,0 +1,49 @@
+// The names of the context values an expression tree refers to, in sorted
+// order, including the built-in functions it calls. The right side of `.` and
+// the keys of an object literal are not variables.
+let freeVariables = tree => {
+  let names = new Set();
+  let visit = node => {
+    if (node === undefined || node === null) {
+      return;
+    }
+    switch (node.constructorName) {
+      case 'ContextValue':
+        names.add(node.token.value);
+        break;
+      case 'BinOp':
+        visit(node.left);
+        if (node.token.kind !== '.' && node.token.kind !== '?.') {
+          visit(node.right);
+        }
+        break;
+      case 'UnaryOp':
+        visit(node.expr);
+        break;
+      case 'TernaryOp':
+        visit(node.condition);
+        visit(node.thenExpr);
+        visit(node.elseExpr);
+        break;
+      case 'FunctionCall':
+        visit(node.name);
+        node.args.forEach(visit);
+        break;
+      case 'List':
+        node.list.forEach(visit);
+        break;
+      case 'ValueAccess':
+        visit(node.arr);
+        visit(node.left);
+        visit(node.right);
+        break;
+      case 'Object':
+        Object.keys(node.obj).forEach(key => visit(node.obj[key]));
+        break;
+    }
+  };
+  visit(tree);
+  return [...names].sort();
+};
+
+module.exports = freeVariables;
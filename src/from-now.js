@@ -1,36 +1,54 @@
-// Regular expression matching:
-// A years B months C days D hours E minutes F seconds
-var timeExp = new RegExp([
-  '^(\\s*(-|\\+))?',
-  '(\\s*(\\d+)\\s*y((ears?)|r)?)?',
-  '(\\s*(\\d+)\\s*mo(nths?)?)?',
-  '(\\s*(\\d+)\\s*w((eeks?)|k)?)?',
-  '(\\s*(\\d+)\\s*d(ays?)?)?',
-  '(\\s*(\\d+)\\s*h((ours?)|r)?)?',
-  '(\\s*(\\d+)\\s*m(in(utes?)?)?)?',
-  '(\\s*(\\d+)\\s*s(ec(onds?)?)?)?',
-  '\\s*$',
-].join(''), 'i');
+var {TemplateError} = require('./error');
+
+// The accepted spellings of each unit, in the order the units must appear
+var units = [
+  ['years',   ['y', 'yr', 'yrs', 'year', 'years']],
+  ['months',  ['mo', 'mos', 'month', 'months']],
+  ['weeks',   ['w', 'wk', 'wks', 'week', 'weeks']],
+  ['days',    ['d', 'day', 'days']],
+  ['hours',   ['h', 'hr', 'hrs', 'hour', 'hours']],
+  ['minutes', ['m', 'min', 'mins', 'minute', 'minutes']],
+  ['seconds', ['s', 'sec', 'secs', 'second', 'seconds']],
+];
+
+// Regular expressions matching an optional sign, and a number and its unit
+var signExp = /^\s*([-+])/;
+var termExp = /^\s*(\d+)\s*([a-z]+)/i;
 
 /** Parse time string */
 var parseTime = function(str) {
-  // Parse the string
-  var match = timeExp.exec(str || '');
-  if (!match) {
-    throw new Error('String: \'' + str + '\' isn\'t a time expression');
-  }
+  var offset = {};
+  units.forEach(([unit]) => offset[unit] = 0);
+
+  var rest = str || '';
   // Negate if needed
-  var neg = match[2] === '-' ? - 1 : 1;
-  // Return parsed values
-  return {
-    years:    parseInt(match[4]   || 0, 10) * neg,
-    months:   parseInt(match[8]   || 0, 10) * neg,
-    weeks:    parseInt(match[11]  || 0, 10) * neg,
-    days:     parseInt(match[15]  || 0, 10) * neg,
-    hours:    parseInt(match[18]  || 0, 10) * neg,
-    minutes:  parseInt(match[22]  || 0, 10) * neg,
-    seconds:  parseInt(match[25]  || 0, 10) * neg,
-  };
+  var neg = 1;
+  var sign = signExp.exec(rest);
+  if (sign) {
+    neg = sign[1] === '-' ? -1 : 1;
+    rest = rest.slice(sign[0].length);
+  }
+
+  // Parse each term, each with a unit after that of the term before it
+  var next = 0;
+  while (rest.trim() !== '') {
+    var term = termExp.exec(rest);
+    if (!term) {
+      throw new TemplateError(`invalid time expression '${str}'`);
+    }
+    var spelling = term[2].toLowerCase();
+    var index = units.findIndex(([, spellings]) => spellings.includes(spelling));
+    if (index === -1) {
+      throw new TemplateError(`unknown time unit '${term[2]}' in time expression '${str}'`);
+    }
+    if (index < next) {
+      throw new TemplateError(`invalid time expression '${str}'`);
+    }
+    offset[units[index][0]] = parseInt(term[1], 10) * neg;
+    next = index + 1;
+    rest = rest.slice(term[0].length);
+  }
+  return offset;
 };
 
 // Render timespan fromNow as JSON timestamp
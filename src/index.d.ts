@@ -2,6 +2,35 @@
 // Project: https://github.com/taskcluster/json-e
 
 declare module 'json-e' {
-  function jsone(template: Record<any, any> | string, context: Record<any, any>): any;
+  namespace jsone {
+    interface RenderOptions {
+      maxElements?: number;
+      maxSteps?: number;
+      maxDepth?: number;
+      maxExpressionLength?: number;
+      maxInterpolationLength?: number;
+      timeout?: number;
+      partial?: boolean;
+      sortKeys?: boolean;
+      expectType?: 'string' | 'number' | 'boolean' | 'null' | 'array' | 'object';
+    }
+    interface ExpressionNode {
+      constructorName: string;
+      token: {kind: string, value: string, start: number, end: number};
+      [property: string]: any;
+    }
+  }
+  function jsone(template: Record<any, any> | string, context: Record<any, any>, options?: jsone.RenderOptions): any;
+  namespace jsone {
+    function compile(template: Record<any, any> | string): (context?: Record<any, any>, options?: RenderOptions) => any;
+    function builtinNames(): string[];
+    function validate(template: any): Error[];
+    function staticResultType(expression: string): 'string' | 'number' | 'boolean' | 'null' | 'array' | 'object' | null;
+    function parseExpression(expression: string): ExpressionNode;
+    function freeVariables(expression: string): string[];
+    function tokenize(expression: string): {kind: string, value: string, start: number, end: number}[];
+    class LimitExceededError extends Error {}
+    function renderEach(template: Record<any, any> | string, context: Record<any, any>, callback: (value: any) => void, options?: RenderOptions): void;
+  }
   export = jsone;
 }
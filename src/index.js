@@ -2,57 +2,123 @@
 
 const {Parser} = require('./parser');
 const Tokenizer = require("../src/tokenizer");
-const {Interpreter} = require('./interpreter');
+const {Interpreter, compare} = require('./interpreter');
 var fromNow = require('./from-now');
+var mergeDeep = require('./merge');
+var resultType = require('./result-type');
+var freeVariables = require('./free-variables');
 var stringify = require('json-stable-stringify-without-jsonify');
 var {
   isString, isNumber, isBool,
-  isArray, isObject,
-   isTruthy
+  isArray, isObject, isFunction,
+  isJSON, isTruthy
 } = require('./type-utils');
 var addBuiltins = require('./builtins');
-var {JSONTemplateError, TemplateError} = require('./error');
+var {JSONTemplateError, TemplateError, LimitExceededError, SyntaxError, UndefinedVariableError} = require('./error');
 
 let syntaxRuleError = (token) => {
-    return new SyntaxError(`Found ${token.value}, expected !=, &&, (, *, **, +, -, ., /, <, <=, ==, >, >=, [, in, ||`);
+    return new SyntaxError(`Found ${token.value}, expected !=, %, &&, (, *, **, +, -, ., /, //, <, <=, ==, >, >=, ?, ?., [, in, ||`, token);
 };
 
+// compiled regular expressions matching the allowed properties of each operator
+let allowedPropertiesRegExps = new Map();
+
 function checkUndefinedProperties(template, allowed) {
   var unknownKeys = '';
-  var combined = new RegExp(allowed.join('|') + '$');
+  var combined = allowedPropertiesRegExps.get(allowed);
+  if (!combined) {
+    combined = new RegExp(allowed.join('|') + '$');
+    allowedPropertiesRegExps.set(allowed, combined);
+  }
   for (var key of Object.keys(template).sort()) {
     if (!combined.test(key)) {
       unknownKeys += ' ' + key;
     }
   }
   if (unknownKeys) {
-    throw new TemplateError(allowed[0].replace('\\', '') + ' has undefined properties:' + unknownKeys);
+    throw new TemplateError(allowed[0].replace(/\\/g, '') + ' has undefined properties:' + unknownKeys);
   }
 }
 
+// Per-render state, such as the options given to render, is kept in the context
+// under this key. It is a symbol, so templates cannot refer to it, not even with
+// defined().
+const STATE = Symbol('state');
+
+// Record count evaluation steps, failing if that exceeds the maxSteps option or
+// the render has run past its timeout
+let countSteps = (context, count) => {
+  let state = context[STATE];
+  state.steps += count;
+  if (state.maxSteps !== undefined && state.steps > state.maxSteps) {
+    throw new LimitExceededError('evaluation budget exceeded');
+  }
+  if (state.deadline !== undefined && Date.now() > state.deadline) {
+    throw new LimitExceededError(`render exceeds the timeout of ${state.timeout}ms`);
+  }
+};
+
+let countElements = (context, count) => {
+  let state = context[STATE];
+  state.elements += count;
+  if (state.maxElements !== undefined && state.elements > state.maxElements) {
+    throw new LimitExceededError(`output exceeds the maximum of ${state.maxElements} elements`);
+  }
+  countSteps(context, count);
+};
+
+// Create the context for a new scope, in which vars shadow the variables of
+// context. The new context inherits from the old one rather than copying it, so
+// this takes constant time however large the context is.
+let childContext = (context, vars) => Object.assign(Object.create(context), vars);
+
 let flattenDeep = (a) => {
   return Array.isArray(a) ? [].concat(...a.map(flattenDeep)) : a;
 };
 
-let interpolate = (string, context) => {
+// Interpolate ${..} expressions in a string. With allowValue, a string that
+// consists of a single ${..} evaluates to the array or object it produces.
+let interpolate = (string, context, allowValue = false) => {
   let result = '';
   let remaining = string;
   let offset;
+  // the parsed expressions of this string, by offset
+  let interpolations = context[STATE].interpolations;
+  let parsed = interpolations.get(string);
+  if (parsed === undefined) {
+    parsed = new Map();
+    interpolations.set(string, parsed);
+  }
+  let maxLength = context[STATE].maxInterpolationLength;
+  let checkLength = length => {
+    if (maxLength !== undefined && length > maxLength) {
+      throw new LimitExceededError(`interpolation exceeds the maximum length of ${maxLength}`);
+    }
+  };
   while ((offset = remaining.search(/\$?\${/g)) !== -1) {
     result += remaining.slice(0, offset);
 
     if (remaining[offset+1] != '$') {
-      let v = parseUntilTerminator(remaining.slice(offset + 2), '}', context);
+      let start = string.length - remaining.length + offset + 2;
+      let v = parseUntilTerminator(string, start, '}', context, parsed);
       if (isArray(v.result) || isObject(v.result)) {
+        if (allowValue && remaining === string && offset === 0 && v.offset + 1 === string.length) {
+          return v.result;
+        }
         let input = remaining.slice(offset + 2, offset + v.offset);
         throw new TemplateError(`interpolation of '${input}' produced an array or object`);
       }
+      if (isFunction(v.result)) {
+        let input = remaining.slice(offset + 2, offset + v.offset);
+        throw new TemplateError(`interpolation of '${input}' produced a function`);
+      }
 
       // if it is null, result should just be appended with empty string
       if (v.result === null) {
         result += '';
       } else {
         result += v.result.toString();
+        checkLength(result.length);
       }
 
       remaining = remaining.slice(offset + v.offset + 1);
@@ -61,6 +127,7 @@ let interpolate = (string, context) => {
       remaining = remaining.slice(offset + 3);
     }
   }
+  checkLength(result.length + remaining.length);
   result += remaining;
   return result;
 };
@@ -68,10 +135,65 @@ let interpolate = (string, context) => {
 // Object used to indicate deleteMarker
 let deleteMarker = {};
 
+// The properties accepted by each operator, as regular expressions; the first
+// is the operator itself
+const EACH_RE = 'each\\(([a-zA-Z_][a-zA-Z0-9_]*)(,\\s*([a-zA-Z_][a-zA-Z0-9_]*))?\\)';
+const BY_RE = 'by\\(([a-zA-Z_][a-zA-Z0-9_]*)\\)';
+let operatorProperties = {
+  $eval: ['\\$eval'],
+  $flatten: ['\\$flatten'],
+  $flattenDeep: ['\\$flattenDeep'],
+  $fromNow: ['\\$fromNow', 'from'],
+  $if: ['\\$if', 'then', 'else'],
+  $json: ['\\$json'],
+  $let: ['\\$let', 'in'],
+  $map: ['\\$map', EACH_RE],
+  $filter: ['\\$filter', EACH_RE],
+  $find: ['\\$find', EACH_RE],
+  $match: ['\\$match'],
+  $switch: ['\\$switch'],
+  $merge: ['\\$merge'],
+  $mergeDeep: ['\\$mergeDeep'],
+  $reverse: ['\\$reverse'],
+  $sort: ['\\$sort', BY_RE, 'reverse'],
+  $$: ['\\$\\$'],
+};
+
 let operators = {};
 
+// Sort keys are all numbers, all strings or all arrays of keys, which are
+// compared lexicographically; the keys at each position of the arrays must be
+// all numbers or all strings.
+let sortableKeys = keys => {
+  if (keys.length > 0 && keys.every(isArray)) {
+    let length = keys.reduce((max, k) => Math.max(max, k.length), 0);
+    for (let i = 0; i < length; i++) {
+      let column = keys.filter(k => i < k.length).map(k => k[i]);
+      if (column.some(isArray) || !sortableKeys(column)) {
+        return false;
+      }
+    }
+    return true;
+  }
+  return keys.every(k => isNumber(k)) || keys.every(k => isString(k));
+};
+
+let compareSortKeys = (a, b) => {
+  if (isArray(a)) {
+    let n = Math.min(a.length, b.length);
+    for (let i = 0; i < n; i++) {
+      let c = compare(a[i], b[i]);
+      if (c !== 0) {
+        return c;
+      }
+    }
+    return a.length - b.length;
+  }
+  return compare(a, b);
+};
+
 operators.$eval = (template, context) => {
-  checkUndefinedProperties(template, ['\\$eval']);
+  checkUndefinedProperties(template, operatorProperties.$eval);
 
   if (!isString(template['$eval'])) {
     throw new TemplateError('$eval must be given a string expression');
@@ -81,7 +203,7 @@ operators.$eval = (template, context) => {
 };
 
 operators.$flatten = (template, context) => {
-  checkUndefinedProperties(template, ['\\$flatten']);
+  checkUndefinedProperties(template, operatorProperties.$flatten);
 
   let value = render(template['$flatten'], context);
 
@@ -93,7 +215,7 @@ operators.$flatten = (template, context) => {
 };
 
 operators.$flattenDeep = (template, context) => {
-  checkUndefinedProperties(template, ['\\$flattenDeep']);
+  checkUndefinedProperties(template, operatorProperties.$flattenDeep);
 
   let value = render(template['$flattenDeep'], context);
 
@@ -105,7 +227,7 @@ operators.$flattenDeep = (template, context) => {
 };
 
 operators.$fromNow = (template, context) => {
-  checkUndefinedProperties(template, ['\\$fromNow', 'from']);
+  checkUndefinedProperties(template, operatorProperties.$fromNow);
 
   let value = render(template['$fromNow'], context);
   let reference = context.now;
@@ -119,7 +241,7 @@ operators.$fromNow = (template, context) => {
 };
 
 operators.$if = (template, context) => {
-  checkUndefinedProperties(template, ['\\$if', 'then', 'else']);
+  checkUndefinedProperties(template, operatorProperties.$if);
 
   if (!isString(template['$if'])) {
     throw new TemplateError('$if can evaluate string expressions only');
@@ -136,32 +258,54 @@ operators.$if = (template, context) => {
 };
 
 operators.$json = (template, context) => {
-  checkUndefinedProperties(template, ['\\$json']);
+  checkUndefinedProperties(template, operatorProperties.$json);
 
-  return stringify(render(template['$json'], context));
+  let value = render(template['$json'], context);
+  if (!isJSON(value)) {
+    throw new TemplateError('$json can only stringify JSON types');
+  }
+  // keys are sorted by code point, as in Python and Go
+  return stringify(value, (a, b) => compare(a.key, b.key));
 };
 
 operators.$let = (template, context) => {
-  checkUndefinedProperties(template, ['\\$let', 'in']);
+  checkUndefinedProperties(template, operatorProperties.$let);
 
-  if (!isObject(template['$let'])) {
-    throw new TemplateError('$let value must be an object');
-  }
   let variables = {};
+  let addVariables = (result, unique) => {
+    Object.keys(result).forEach(key => {
+      if (!/^[a-zA-Z_][a-zA-Z0-9_]*$/.test(key)) {
+        throw new TemplateError('top level keys of $let must follow /[a-zA-Z_][a-zA-Z0-9_]*/');
+      }
+      if (unique && variables.hasOwnProperty(key)) {
+        throw new TemplateError(`$let binding "${key}" is defined more than once`);
+      }
+      variables[key] = result[key];
+    });
+  };
 
-  let initialResult = render(template['$let'], context);
-  if (!isObject(initialResult)) {
-    throw new TemplateError('$let value must be an object');
-  }
-  Object.keys(initialResult).forEach(key => {
-    if (!/^[a-zA-Z_][a-zA-Z0-9_]*$/.test(key)) {
-      throw new TemplateError('top level keys of $let must follow /[a-zA-Z_][a-zA-Z0-9_]*/');
-    }else{
-      variables[key] = initialResult[key];
+  if (isArray(template['$let'])) {
+    // an array of binding objects, each rendered in the scope of those before it
+    for (let binding of template['$let']) {
+      let result = render(binding, childContext(context, variables));
+      if (!isObject(result)) {
+        throw new TemplateError('$let array elements must evaluate to objects');
+      }
+      addVariables(result, true);
+    }
+  } else {
+    if (!isObject(template['$let'])) {
+      throw new TemplateError('$let value must be an object');
     }
-  });
 
-  var child_context = Object.assign(context, variables);
+    let initialResult = render(template['$let'], context);
+    if (!isObject(initialResult)) {
+      throw new TemplateError('$let value must be an object');
+    }
+    addVariables(initialResult, false);
+  }
+
+  var child_context = childContext(context, variables);
 
   if (template.in == undefined) {
     throw new TemplateError('$let operator requires an `in` clause');
@@ -170,53 +314,121 @@ operators.$let = (template, context) => {
   return render(template.in, child_context);
 };
 
-operators.$map = (template, context) => {
-  const EACH_RE = 'each\\(([a-zA-Z_][a-zA-Z0-9_]*)(,\\s*([a-zA-Z_][a-zA-Z0-9_]*))?\\)';
-  checkUndefinedProperties(template, ['\\$map', EACH_RE]);
-  let value = render(template['$map'], context);
+// Check a $map template, or the like for another operator taking each(..),
+// and render its value, returning the parts needed to render each element
+let parseMap = (template, context, operator = '$map') => {
+  checkUndefinedProperties(template, operatorProperties[operator]);
+  let value = render(template[operator], context);
   if (!isArray(value) && !isObject(value)) {
-    throw new TemplateError('$map value must evaluate to an array or object');
+    throw new TemplateError(`${operator} value must evaluate to an array or object`);
   }
 
   if (Object.keys(template).length !== 2) {
-    throw new TemplateError('$map must have exactly two properties');
+    throw new TemplateError(`${operator} must have exactly two properties`);
   }
 
-  let eachKey = Object.keys(template).filter(k => k !== '$map')[0];
+  let eachKey = Object.keys(template).filter(k => k !== operator)[0];
   let match = /^each\(([a-zA-Z_][a-zA-Z0-9_]*)(,\s*([a-zA-Z_][a-zA-Z0-9_]*))?\)$/.exec(eachKey);
   if (!match) {
-    throw new TemplateError('$map requires each(identifier) syntax');
+    throw new TemplateError(`${operator} requires each(identifier) syntax`);
   }
 
-  let x = match[1];
-  let i = match[3];
-  let each = template[eachKey];
+  return {value, x: match[1], i: match[3], each: template[eachKey]};
+};
+
+// Render each element of an array $map, passing the results to emit
+let mapArray = ({value, x, i, each}, context, emit) => {
+  value.forEach((v, idx) => {
+    let args = typeof i !== 'undefined' ? {[x]: v, [i]: idx} : {[x]: v};
+    let result = render(each, childContext(context, args));
+    if (result !== deleteMarker) {
+      emit(result);
+    }
+  });
+};
 
-  let object = isObject(value);
+operators.$map = (template, context) => {
+  let map = parseMap(template, context);
+  let {x, i, each} = map;
+  let value = map.value;
 
-  if (object) {
+  if (isObject(value)) {
     value = Object.keys(value).map(key => ({key, val: value[key]}));
     let eachValue;
     value = value.map(v => {
       let args = typeof i !== 'undefined' ? {[x]: v.val, [i]: v.key} : {[x]: v};
-      eachValue = render(each, Object.assign({}, context, args));
+      eachValue = render(each, childContext(context, args));
       if (!isObject(eachValue)) {
         throw new TemplateError(`$map on objects expects each(${x}) to evaluate to an object`);
       }
       return eachValue;
     }).filter(v => v !== deleteMarker);
-    //return value.reduce((a, o) => Object.assign(a, o), {});
-    return Object.assign({}, ...value);
+    let result = value.reduce((result, o) => {
+      for (let key of Object.keys(o)) {
+        if (result.hasOwnProperty(key)) {
+          throw new TemplateError(`$map on objects produced duplicate key "${key}"`);
+        }
+        result[key] = o[key];
+      }
+      return result;
+    }, {});
+    countElements(context, Object.keys(result).length);
+    return result;
   } else {
-    return value.map((v, idx) => {
-      let args = typeof i !== 'undefined' ? {[x]: v, [i]: idx} : {[x]: v};
-      return render(each, Object.assign({}, context, args));
-    }).filter(v => v !== deleteMarker);
+    let result = [];
+    mapArray(map, context, v => result.push(v));
+    countElements(context, result.length);
+    return result;
+  }
+};
+
+// Check a $filter or $find template and render its value, returning the value
+// and a function telling whether the each(..) expression is truthy for an
+// element and its index or key
+let parseCondition = (operator, template, context) => {
+  let {value, x, i, each} = parseMap(template, context, operator);
+  if (!isString(each)) {
+    throw new TemplateError(`${operator} each(..) can evaluate string expressions only`);
+  }
+
+  // like $map, each(x) over an object gives x the entries as {key, val}
+  let entries = isObject(value) && typeof i === 'undefined';
+  let scope = childContext(context, {});
+  let matches = (v, key) => {
+    scope[x] = entries ? {key, val: v} : v;
+    if (typeof i !== 'undefined') {
+      scope[i] = key;
+    }
+    return isTruthy(parse(each, scope));
+  };
+  return {value, matches};
+};
+
+operators.$filter = (template, context) => {
+  let {value, matches} = parseCondition('$filter', template, context);
+  if (isObject(value)) {
+    let result = {};
+    for (let key of Object.keys(value)) {
+      if (matches(value[key], key)) {
+        result[key] = value[key];
+      }
+    }
+    return result;
   }
+  return value.filter((v, idx) => matches(v, idx));
+};
+
+operators.$find = (template, context) => {
+  let {value, matches} = parseCondition('$find', template, context);
+  if (!isArray(value)) {
+    throw new TemplateError('$find value must evaluate to an array');
+  }
+  let idx = value.findIndex((v, idx) => matches(v, idx));
+  return idx === -1 ? deleteMarker : value[idx];
 };
 
 operators.$match = (template, context) => {
-  checkUndefinedProperties(template, ['\\$match']);
+  checkUndefinedProperties(template, operatorProperties.$match);
 
   if (!isObject(template['$match'])) {
     throw new TemplateError('$match can evaluate objects only');
@@ -235,7 +447,7 @@ operators.$match = (template, context) => {
 };
 
 operators.$switch = (template, context) => {
-  checkUndefinedProperties(template, ['\\$switch']);
+  checkUndefinedProperties(template, operatorProperties.$switch);
 
   if (!isObject(template['$switch'])) {
     throw new TemplateError('$switch can evaluate objects only');
@@ -245,6 +457,9 @@ operators.$switch = (template, context) => {
   const conditions = template['$switch'];
 
   for (let condition of Object.keys(conditions)) {
+    if (condition === '$default') {
+      continue;
+    }
     if (isTruthy(parse(condition, context))) {
       result.push(render(conditions[condition], context));
     }
@@ -254,11 +469,15 @@ operators.$switch = (template, context) => {
     throw new TemplateError('$switch can only have one truthy condition');
   }
 
+  if (result.length === 0 && '$default' in conditions) {
+    result.push(render(conditions['$default'], context));
+  }
+
   return result.length > 0 ? result[0] : deleteMarker;
 };
 
 operators.$merge = (template, context) => {
-  checkUndefinedProperties(template, ['\\$merge']);
+  checkUndefinedProperties(template, operatorProperties.$merge);
 
   let value = render(template['$merge'], context);
 
@@ -270,7 +489,7 @@ operators.$merge = (template, context) => {
 };
 
 operators.$mergeDeep = (template, context) => {
-  checkUndefinedProperties(template, ['\\$mergeDeep']);
+  checkUndefinedProperties(template, operatorProperties.$mergeDeep);
 
   let value = render(template['$mergeDeep'], context);
 
@@ -281,55 +500,49 @@ operators.$mergeDeep = (template, context) => {
   if (value.length === 0) {
     return {};
   }
-  // merge two values, preferring the right but concatenating lists and
-  // recursively merging objects
-  let merge = (l, r) => {
-    if (isArray(l) && isArray(r)) {
-      return l.concat(r);
-    }
-    if (isObject(l) && isObject(r)) {
-      let res = Object.assign({}, l);
-      for (let p in r) { // eslint-disable-line taskcluster/no-for-in
-        if (p in l) {
-          res[p] = merge(l[p], r[p]);
-        } else {
-          res[p] = r[p];
-        }
-      }
-      return res;
-    }
-    return r;
-  };
   // start with the first element of the list
-  return value.reduce(merge, value.shift());
+  return value.slice(1).reduce(mergeDeep, value[0]);
 };
 
 operators.$reverse = (template, context) => {
-  checkUndefinedProperties(template, ['\\$reverse']);
+  checkUndefinedProperties(template, operatorProperties.$reverse);
 
   let value = render(template['$reverse'], context);
 
   if (!isArray(value)) {
     throw new TemplateError('$reverse value must evaluate to an array of objects');
   }
-  return value.reverse();
+  return value.slice().reverse();
 };
 
 operators.$sort = (template, context) => {
-  const BY_RE = 'by\\(([a-zA-Z_][a-zA-Z0-9_]*)\\)';
-  checkUndefinedProperties(template, ['\\$sort', BY_RE]);
+  checkUndefinedProperties(template, operatorProperties.$sort);
   let value = render(template['$sort'], context);
   if (!isArray(value)) {
     throw new TemplateError('$sorted values to be sorted must have the same type');
   }
 
-  let byKey = Object.keys(template).filter(k => k !== '$sort')[0];
-  let match = /^by\(([a-zA-Z_][a-zA-Z0-9_]*)\)$/.exec(byKey);
+  let reverse = false;
+  if (template.hasOwnProperty('reverse')) {
+    reverse = render(template['reverse'], context);
+    if (!isBool(reverse)) {
+      throw new TemplateError('$sort reverse must be a boolean');
+    }
+  }
+
+  let byKeys = Object.keys(template).filter(k => k !== '$sort' && k !== 'reverse');
+  if (byKeys.length > 1) {
+    throw new TemplateError('only one by(..) is allowed');
+  }
+  let match = /^by\(([a-zA-Z_][a-zA-Z0-9_]*)\)$/.exec(byKeys[0]);
   let by;
   if (match) {
-    let contextClone = Object.assign({}, context);
+    let contextClone = childContext(context, {});
     let x = match[1];
-    let byExpr = template[byKey];
+    let byExpr = template[byKeys[0]];
+    if (!isString(byExpr)) {
+      throw new TemplateError('$sort by(..) can evaluate string expressions only');
+    }
     by = value => {
       contextClone[x] = value;
       return parse(byExpr, contextClone);
@@ -342,39 +555,124 @@ operators.$sort = (template, context) => {
     by = value => value;
   }
 
-  // tag each value with its `by` value (schwartzian tranform)
-  let tagged = value.map(e => [by(e), e]);
+  // tag each value with its `by` value (schwartzian tranform), and its index
+  // so that the sort is stable for elements with equal `by` values
+  let tagged = value.map((e, i) => [by(e), e, i]);
 
   // check types of the `by` values
-  if (tagged.length > 0) {
-    let eltType = typeof tagged[0][0];
-    if (eltType !== 'number' && eltType !== 'string' ||
-        tagged.some(e => eltType !== typeof e[0])) {
-      throw new TemplateError('$sorted values to be sorted must have the same type');
-    }
+  if (!sortableKeys(tagged.map(e => e[0]))) {
+    throw new TemplateError('$sorted values to be sorted must have the same type');
   }
 
-  // finish the schwartzian transform
+  // finish the schwartzian transform; a reversed sort keeps equal elements in
+  // their original order, too
+  let order = reverse ? -1 : 1;
   return tagged
-    .sort((a, b) => {
-      a = a[0];
-      b = b[0];
-      if (a < b) { return -1; }
-      if (a > b) { return 1; }
-      return 0;
-    })
+    .sort((a, b) => order * compareSortKeys(a[0], b[0]) || a[2] - b[2])
     .map(e => e[1]);
 };
 
+// {$$: value} is value itself, with nothing inside it rendered
+operators.$$ = (template, context) => {
+  checkUndefinedProperties(template, operatorProperties.$$);
+
+  return template['$$'];
+};
+
+let keyLocation = key => /^[a-zA-Z][a-zA-Z0-9]*$/.test(key) ? `.${key}` : `[${JSON.stringify(key)}]`;
+
+// Whether rendering value as a template gives value itself
+let isInert = value => {
+  if (isString(value)) {
+    return !value.includes('${');
+  }
+  if (isArray(value)) {
+    return value.every(isInert);
+  }
+  if (isObject(value)) {
+    return Object.keys(value).every(k => isInertKey(k) && isInert(value[k]));
+  }
+  return true;
+};
+
+let isInertKey = key => !key.startsWith('$') && !key.includes('${');
+
+// Render a string or operator template with renderValue in a partial render:
+// one that refers to a variable not in the context is left as it is, to be
+// rendered again with more context, and a value that rendering again would
+// change is escaped with $$. Everything within is rendered as usual, as a
+// variable bound by an operator would be lost if it was left for later.
+let renderPartially = (template, context, renderValue) => {
+  let state = context[STATE];
+  state.partial = false;
+  try {
+    let value = renderValue();
+    return value === deleteMarker || isInert(value) ? value : {$$: value};
+  } catch (err) {
+    if (err instanceof UndefinedVariableError) {
+      return template;
+    }
+    throw err;
+  } finally {
+    state.partial = true;
+  }
+};
+
+let renderKey = (key, context) => {
+  if (key.startsWith('$$')) {
+    return key.substr(1);
+  }
+  if (/^\$[a-zA-Z_][a-zA-Z0-9_]*$/.test(key)) {
+    throw new TemplateError(`${key} is reserved; use $${key}`);
+  }
+  return interpolate(key, context);
+};
+
+// In a partial render, the rendered key of an object entry, or undefined if the
+// entry is left as it is because its key refers to a variable not in the
+// context or would be rendered differently again
+let renderPartialKey = (key, context) => {
+  try {
+    let rendered = renderKey(key, context);
+    return isInertKey(rendered) ? rendered : undefined;
+  } catch (err) {
+    if (err instanceof UndefinedVariableError) {
+      return undefined;
+    }
+    throw err;
+  }
+};
+
+// Render an array or object one level deeper, failing if that exceeds the
+// maxDepth option
 let render = (template, context) => {
+  if (!isArray(template) && !isObject(template)) {
+    return renderInner(template, context);
+  }
+  let state = context[STATE];
+  if (state.maxDepth !== undefined && state.depth >= state.maxDepth) {
+    throw new LimitExceededError(`template nesting exceeds the maximum depth of ${state.maxDepth}`);
+  }
+  state.depth++;
+  try {
+    return renderInner(template, context);
+  } finally {
+    state.depth--;
+  }
+};
+
+let renderInner = (template, context) => {
   if (isNumber(template) || isBool(template) || template === null) {
     return template;
   }
   if (isString(template)) {
-    return interpolate(template, context);
+    if (context[STATE].partial) {
+      return renderPartially(template, context, () => interpolate(template, context, true));
+    }
+    return interpolate(template, context, true);
   }
   if (isArray(template)) {
-    return template.map((v, i) => {
+    let result = template.map((v, i) => {
       try {
         return render(v, context);
       } catch (err) {
@@ -384,6 +682,8 @@ let render = (template, context) => {
         throw err;
       }
     }).filter((v) => v !== deleteMarker);
+    countElements(context, result.length);
+    return result;
   }
 
   let matches = Object.keys(operators).filter(c => template.hasOwnProperty(c));
@@ -391,46 +691,120 @@ let render = (template, context) => {
     throw new TemplateError('only one operator allowed');
   }
   if (matches.length === 1) {
+    if (context[STATE].partial) {
+      return renderPartially(template, context, () => operators[matches[0]](template, context));
+    }
     return operators[matches[0]](template, context);
   }
 
   // clone object
   let result = {};
+  let partial = context[STATE].partial;
   for (let key of Object.keys(template)) {
     let value;
     try {
       value = render(template[key], context);
     } catch (err) {
       if (err instanceof JSONTemplateError) {
-        if (/^[a-zA-Z][a-zA-Z0-9]*$/.test(key)) {
-          err.add_location(`.${key}`);
-        } else {
-          err.add_location(`[${JSON.stringify(key)}]`);
-        }
+        err.add_location(keyLocation(key));
       }
       throw err;
     }
     if (value !== deleteMarker) {
-      if (key.startsWith('$$')) {
-        key = key.substr(1);
-      } else if (/^\$[a-zA-Z][a-zA-Z0-9]*$/.test(key)) {
-        throw new TemplateError('$<identifier> is reserved; use $$<identifier>');
+      if (partial) {
+        let renderedKey = renderPartialKey(key, context);
+        if (renderedKey === undefined) {
+          result[key] = template[key];
+          continue;
+        }
+        key = renderedKey;
       } else {
-        key = interpolate(key, context);
+        key = renderKey(key, context);
       }
 
+      // keys written out in the template are distinct, so a duplicate comes
+      // from interpolation, and which value should win is not obvious
+      if (result.hasOwnProperty(key)) {
+        throw new TemplateError(`interpolated keys produced duplicate key "${key}"`);
+      }
       result[key] = value;
     }
   }
+  countElements(context, Object.keys(result).length);
   return result;
 };
 
+// Check the structure of a template without rendering it, returning an array of
+// every problem found instead of stopping at the first. Unknown $-operators,
+// properties an operator does not accept and malformed each(..) and by(..) keys
+// are reported.
+let validate = template => {
+  let errors = [];
+  let addErrors = (inner, location) => inner.forEach(err => {
+    if (location) {
+      err.add_location(location);
+    }
+    errors.push(err);
+  });
+
+  if (isArray(template)) {
+    template.forEach((v, i) => addErrors(validate(v), `[${i}]`));
+    return errors;
+  }
+  if (!isObject(template)) {
+    return errors;
+  }
+
+  let matches = Object.keys(operators).filter(c => template.hasOwnProperty(c));
+  if (matches.length > 1) {
+    errors.push(new TemplateError('only one operator allowed'));
+  } else if (matches.length === 1) {
+    let operator = matches[0];
+    try {
+      checkUndefinedProperties(template, operatorProperties[operator]);
+      if (['$map', '$filter', '$find'].includes(operator) && Object.keys(template).length !== 2) {
+        throw new TemplateError(`${operator} must have exactly two properties`);
+      }
+      if (operator === '$let' && !template.hasOwnProperty('in')) {
+        throw new TemplateError('$let operator requires an `in` clause');
+      }
+      if (operator === '$sort' && Object.keys(template).filter(k => k !== '$sort' && k !== 'reverse').length > 1) {
+        throw new TemplateError('only one by(..) is allowed');
+      }
+    } catch (err) {
+      errors.push(err);
+    }
+  }
+
+  for (let key of Object.keys(template)) {
+    let value = template[key];
+    if (key === '$$') {
+      continue;
+    }
+    if (matches.length > 0) {
+      // the keys of $match and $switch are conditions, their values templates
+      if ((key === '$match' || key === '$switch') && isObject(value)) {
+        Object.keys(value).forEach(k => addErrors(validate(value[k])));
+      } else {
+        addErrors(validate(value));
+      }
+      continue;
+    }
+    if (!key.startsWith('$$') && /^\$[a-zA-Z_][a-zA-Z0-9_]*$/.test(key)) {
+      errors.push(new TemplateError(`${key} is reserved; use $${key}`));
+    }
+    addErrors(validate(value), keyLocation(key));
+  }
+  return errors;
+};
+
 let tokenizer = new Tokenizer({
     ignore: '\\s+', // ignore all whitespace including \n
     patterns: {
         number: '[0-9]+(?:\\.[0-9]+)?',
         identifier: '[a-zA-Z_][a-zA-Z_0-9]*',
-        string: '\'[^\']*\'|"[^"]*"',
+        // a backslash escapes the character after it, even a quote
+        string: '\'[^\'\\\\]*(?:\\\\[^][^\'\\\\]*)*\'|"[^"\\\\]*(?:\\\\[^][^"\\\\]*)*"',
         // avoid matching these as prefixes of identifiers e.g., `insinutations`
         true: 'true(?![a-zA-Z_0-9])',
         false: 'false(?![a-zA-Z_0-9])',
@@ -438,52 +812,330 @@ let tokenizer = new Tokenizer({
         null: 'null(?![a-zA-Z_0-9])',
     },
     tokens: [
-        '**', ...'+-*/[].(){}:,'.split(''),
+        '**', '//', '?.', ...'+-*/%[].(){}:,?'.split(''),
         '>=', '<=', '<', '>', '==', '!=', '!', '&&', '||',
         'true', 'false', 'in', 'null', 'number',
         'identifier', 'string',
     ]
 });
 
-let parse = (source, context) => {
+let parseExpression = source => {
     let parser = new Parser(tokenizer, source);
+    if (parser.current_token == null) {
+        throw new SyntaxError('empty expression', {start: 0, end: source.length});
+    }
     let tree = parser.parse();
     if (parser.current_token != null) {
         throw syntaxRuleError(parser.current_token);
     }
-    let interpreter = new Interpreter(context);
+    return tree;
+};
+
+// The tree of an expression, keeping the trees in parsed by source
+let parseCached = (source, parsed) => {
+    let tree = parsed.get(source);
+    if (tree === undefined) {
+        tree = parseExpression(source);
+        parsed.set(source, tree);
+    }
+    return tree;
+};
+
+// Expressions are parsed once per render, keeping their trees in the render
+// state; only the evaluation depends on the context.
+let checkExpressionLength = (length, context) => {
+    let limit = context[STATE].maxExpressionLength;
+    if (limit !== undefined && length > limit) {
+        throw new LimitExceededError(`expression exceeds the maximum length of ${limit}`);
+    }
+};
+
+let parse = (source, context) => {
+    checkExpressionLength(source.length, context);
+    let tree = parseCached(source, context[STATE].expressions);
+    let interpreter = new Interpreter(context, context[STATE].step);
 
     return interpreter.interpret(tree);
 };
 
-let parseUntilTerminator = (source, terminator, context) => {
-    let parser = new Parser(tokenizer, source);
-    let tree = parser.parse();
-    let next = parser.current_token;
-    if (!next) {
-        // string ended without the terminator
-        let errorLocation = source.length;
-        throw new SyntaxError(`Found end of string, expected ${terminator}`,
-            {start: errorLocation, end: errorLocation});
-    } else if (next.kind !== terminator) {
-        throw syntaxRuleError(next);
+// Parse the expression at start in string, up to the terminator, giving its tree
+// and the offset of the terminator from start - 2. The entries are kept in
+// parsed, by start.
+let parseInterpolation = (string, start, terminator, parsed) => {
+    let entry = parsed.get(start);
+    if (entry === undefined) {
+        let source = string.slice(start);
+        let parser = new Parser(tokenizer, source);
+        if (parser.current_token != null && parser.current_token.kind === terminator) {
+            throw new SyntaxError('empty expression', parser.current_token);
+        }
+        let tree = parser.parse();
+        let next = parser.current_token;
+        if (!next) {
+            // string ended without the terminator
+            let errorLocation = source.length;
+            throw new SyntaxError(`Found end of string, expected ${terminator}`,
+                {start: errorLocation, end: errorLocation});
+        } else if (next.kind !== terminator) {
+            throw syntaxRuleError(next);
+        }
+        entry = {tree, offset: next.start + 2};
+        parsed.set(start, entry);
+    }
+    return entry;
+};
+
+let parseUntilTerminator = (string, start, terminator, context, parsed) => {
+    let entry = parseInterpolation(string, start, terminator, parsed);
+    checkExpressionLength(entry.offset - 2, context);
+    let interpreter = new Interpreter(context, context[STATE].step);
+    let result = interpreter.interpret(entry.tree);
+
+    return {result, offset: entry.offset};
+};
+
+// Parse every expression and interpolation in a template into parse caches like
+// those of a render, throwing the first syntax error, so that it is found
+// without rendering the template
+let parseAll = (template, parsed) => {
+  let parseTemplate = (value, location) => {
+    try {
+      parseValue(value);
+    } catch (err) {
+      if (location && err instanceof JSONTemplateError) {
+        err.add_location(location);
+      }
+      throw err;
+    }
+  };
+
+  let parseStrings = string => {
+    let trees = parsed.interpolations.get(string);
+    if (trees === undefined) {
+      trees = new Map();
+      parsed.interpolations.set(string, trees);
+    }
+    let pattern = /\$?\${/g;
+    let match;
+    while ((match = pattern.exec(string)) !== null) {
+      if (match[0] !== '$${') {
+        let start = pattern.lastIndex;
+        pattern.lastIndex = start + parseInterpolation(string, start, '}', trees).offset - 1;
+      }
+    }
+  };
+
+  let parseValue = value => {
+    if (isString(value)) {
+      parseStrings(value);
+    } else if (isArray(value)) {
+      value.forEach((v, i) => parseTemplate(v, `[${i}]`));
+    } else if (isObject(value)) {
+      let matches = Object.keys(operators).filter(c => value.hasOwnProperty(c));
+      if (matches.length === 0) {
+        for (let key of Object.keys(value)) {
+          if (!key.startsWith('$$')) {
+            parseStrings(key);
+          }
+          parseTemplate(value[key], keyLocation(key));
+        }
+        return;
+      }
+      let operator = matches[0];
+      if (operator === '$$') {
+        return;
+      }
+      for (let key of Object.keys(value)) {
+        let v = value[key];
+        if (key === '$eval' || key === '$if' || key.startsWith('by(') ||
+            key.startsWith('each(') && (operator === '$filter' || operator === '$find')) {
+          if (isString(v)) {
+            parseCached(v, parsed.expressions);
+          }
+        } else if ((key === '$match' || key === '$switch') && isObject(v)) {
+          for (let condition of Object.keys(v)) {
+            if (condition !== '$default' || key === '$match') {
+              parseCached(condition, parsed.expressions);
+            }
+            parseTemplate(v[condition]);
+          }
+        } else {
+          parseTemplate(v);
+        }
+      }
     }
-    let interpreter = new Interpreter(context);
-    let result = interpreter.interpret(tree);
+  };
 
-    return {result, offset: next.start + 2};
+  parseTemplate(template);
 };
 
-module.exports = (template, context = {}) => {
+// the builtins do not depend on the context, so they are only defined once
+let builtins = addBuiltins({});
+
+// The parsed trees of expressions, by source, and of interpolations, by string
+// and offset; these are kept for one render, or for every render of a compiled
+// template
+let parseCaches = () => ({expressions: new Map(), interpolations: new Map()});
+
+let buildContext = (context, options, parsed = parseCaches()) => {
   let test = Object.keys(context).every(v => /^[a-zA-Z_][a-zA-Z0-9_]*$/.test(v));
   if (!test) {
     throw new TemplateError('top level keys of context must follow /[a-zA-Z_][a-zA-Z0-9_]*/');
   }
-  context = addBuiltins(Object.assign({}, {now: fromNow('0 seconds')}, context));
+  // the outermost context has no prototype, so only its own variables are found
+  context = Object.assign(Object.create(null), builtins, {now: fromNow('0 seconds')}, context);
+  let state = context[STATE] = {
+    maxElements: options.maxElements,
+    elements: 0,
+    maxSteps: options.maxSteps,
+    steps: 0,
+    maxDepth: options.maxDepth,
+    // the number of arrays and objects being rendered within one another
+    depth: 0,
+    maxExpressionLength: options.maxExpressionLength,
+    maxInterpolationLength: options.maxInterpolationLength,
+    timeout: options.timeout,
+    // the time by which the render must finish, from Date.now()
+    deadline: options.timeout === undefined ? undefined : Date.now() + options.timeout,
+    // whether strings and operators that refer to variables not in the context
+    // are left as they are; off within an operator
+    partial: Boolean(options.partial),
+    // counts each expression node evaluated, only when there is a budget
+    step: undefined,
+    expressions: parsed.expressions,
+    interpolations: parsed.interpolations,
+  };
+  if (options.maxSteps !== undefined || options.timeout !== undefined) {
+    state.step = () => countSteps(context, 1);
+  }
+  return context;
+};
+
+// The name of the type of a JSON value, as given by the typeof built-in
+let typeName = value => {
+  if (value === null) {
+    return 'null';
+  }
+  return isArray(value) ? 'array' : typeof value;
+};
+
+// A copy of a JSON value with the keys of each object sorted by code point
+let sortKeys = value => {
+  if (isArray(value)) {
+    return value.map(sortKeys);
+  }
+  if (isObject(value)) {
+    let sorted = {};
+    Object.keys(value).sort(compare).forEach(key => sorted[key] = sortKeys(value[key]));
+    return sorted;
+  }
+  return value;
+};
+
+let renderTemplate = (template, context, options, parsed) => {
+  context = buildContext(context, options, parsed);
   let result = render(template, context);
   if (result === deleteMarker) {
-    return null;
+    result = null;
+  }
+  if (!isJSON(result)) {
+    throw new TemplateError('rendered output can only contain JSON types');
+  }
+  if (options.expectType !== undefined && typeName(result) !== options.expectType) {
+    throw new TemplateError(`rendered output is of type ${typeName(result)}, expected ${options.expectType}`);
+  }
+  if (options.sortKeys) {
+    result = sortKeys(result);
   }
 
   return result;
 };
+
+module.exports = (template, context = {}, options = {}) => renderTemplate(template, context, options);
+
+// Prepare a template for rendering many times, returning a function of the
+// context and options. The first problem validate finds is thrown, as is the
+// first syntax error, since every expression is parsed here and the trees kept
+// for the renders.
+module.exports.compile = template => {
+  let errors = validate(template);
+  if (errors.length > 0) {
+    throw errors[0];
+  }
+  let parsed = parseCaches();
+  parseAll(template, parsed);
+  return (context = {}, options = {}) => renderTemplate(template, context, options, parsed);
+};
+
+module.exports.validate = validate;
+
+// The error for a render that exceeds one of the limits in its options, or an
+// expression nested too deeply to parse
+module.exports.LimitExceededError = LimitExceededError;
+
+// The type of the value an expression evaluates to, as named by the typeof
+// built-in, when that does not depend on the context; otherwise null
+module.exports.staticResultType = expression => resultType(parseExpression(expression));
+
+// The tree of an expression, for analyzing it without evaluating it
+module.exports.parseExpression = parseExpression;
+
+// The names of the context values an expression refers to, in sorted order
+module.exports.freeVariables = expression => freeVariables(parseExpression(expression));
+
+// The tokens of an expression, each with its kind, value and start and end
+// offsets, for debugging; expressions are not evaluated from these
+module.exports.tokenize = expression => tokenizer.tokenize(expression);
+
+// The names of the built-in functions, in sorted order
+module.exports.builtinNames = () => Object.keys(builtins).sort();
+
+// Render a template that produces an array, passing each element to callback as
+// it is rendered instead of building the whole array. Array templates and $map
+// over an array are rendered one element at a time.
+module.exports.renderEach = (template, context = {}, callback, options = {}) => {
+  context = buildContext(context, options);
+  let emit = value => {
+    if (!isJSON(value)) {
+      throw new TemplateError('rendered output can only contain JSON types');
+    }
+    countElements(context, 1);
+    callback(value);
+  };
+
+  if (isArray(template)) {
+    template.forEach((v, i) => {
+      let value;
+      try {
+        value = render(v, context);
+      } catch (err) {
+        if (err instanceof JSONTemplateError) {
+          err.add_location(`[${i}]`);
+        }
+        throw err;
+      }
+      if (value !== deleteMarker) {
+        emit(value);
+      }
+    });
+    return;
+  }
+
+  let result;
+  if (isObject(template) && template.hasOwnProperty('$map')) {
+    let map = parseMap(template, context);
+    if (isArray(map.value)) {
+      mapArray(map, context, emit);
+      return;
+    }
+  } else {
+    result = render(template, context);
+  }
+  if (!isArray(result)) {
+    throw new TemplateError('renderEach requires a template that renders to an array');
+  }
+  if (!isJSON(result)) {
+    throw new TemplateError('rendered output can only contain JSON types');
+  }
+  result.forEach(v => callback(v));
+};
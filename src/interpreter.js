@@ -1,28 +1,43 @@
-const {isFunction, isObject, isString, isArray, isNumber, isInteger, isTruthy} = require("../src/type-utils");
-const {InterpreterError} = require('./error');
+const {isFunction, isObject, isString, isArray, isNumber, isBool, isInteger, isTruthy} = require("../src/type-utils");
+const {InterpreterError, UndefinedVariableError} = require('./error');
+const {parseString} = require('./parser');
 
 let expectationError = (operator, expectation) => new InterpreterError(`${operator} expects ${expectation}`);
 
+// the value of a link in an optional chain whose object was null, so that the
+// rest of the chain is skipped, as in `x?.a.b` for a null x
+const SKIPPED = Symbol('skipped');
+
 class Interpreter {
-    constructor(context) {
+    // step, if given, is called before each node of the tree is evaluated, and
+    // may throw to stop the evaluation
+    constructor(context, step) {
         this.context = context;
+        this.step = step;
     }
 
     visit(node) {
+        let value = this.visitLink(node);
+        return value === SKIPPED ? null : value;
+    }
+
+    // visit the object of a property access, index or call, which may be SKIPPED
+    visitLink(node) {
+        if (this.step) {
+            this.step();
+        }
         let funcName = "visit_" + node.constructorName;
         return this[funcName](node);
     }
 
     visit_ASTNode(node) {
-        let str;
         switch (node.token.kind) {
             case("number"):
                 return +node.token.value;
             case("null"):
                 return null;
             case("string"):
-                str = node.token.value.slice(1, -1);
-                return str;
+                return parseString(node.token.value);
             case("true"):
                 return true;
             case("false"):
@@ -50,14 +65,27 @@ class Interpreter {
         }
     }
 
+    visit_TernaryOp(node) {
+        if (isTruthy(this.visit(node.condition))) {
+            return this.visit(node.thenExpr);
+        }
+        return this.visit(node.elseExpr);
+    }
+
     visit_BinOp(node) {
-        let left = this.visit(node.left);
+        let chained = node.token.kind === "." || node.token.kind === "?.";
+        let left = chained ? this.visitLink(node.left) : this.visit(node.left);
+        if (left === SKIPPED) {
+            return SKIPPED;
+        }
         let right;
         switch (node.token.kind) {
             case ("||"):
-                return isTruthy(left) || isTruthy(this.visit(node.right));
+                testLogicalOperand("||", left);
+                return left || testLogicalOperand("||", this.visit(node.right));
             case ("&&"):
-                return isTruthy(left) && isTruthy(this.visit(node.right));
+                testLogicalOperand("&&", left);
+                return left && testLogicalOperand("&&", this.visit(node.right));
             default:
                 right = this.visit(node.right);
         }
@@ -72,30 +100,48 @@ class Interpreter {
             case ("/"):
                 testMathOperands("/", left, right);
                 return left / right;
+            case ("//"):
+                testMathOperands("//", left, right);
+                if (right === 0) {
+                    throw new InterpreterError('infix: // expects a non-zero divisor');
+                }
+                // floor division, avoiding a negative zero result
+                return Math.floor(left / right) + 0;
             case ("*"):
                 testMathOperands("*", left, right);
                 return left * right;
+            case ("%"):
+                testMathOperands("%", left, right);
+                if (right === 0) {
+                    throw new InterpreterError('infix: % expects a non-zero divisor');
+                }
+                return left % right;
             case (">"):
                 testComparisonOperands(">", left, right);
-                return left > right;
+                return compare(left, right) > 0;
             case ("<"):
                 testComparisonOperands("<", left, right);
-                return left < right;
+                return compare(left, right) < 0;
             case (">="):
                 testComparisonOperands(">=", left, right);
-                return left >= right;
+                return compare(left, right) >= 0;
             case ("<="):
                 testComparisonOperands("<=", left, right);
-                return left <= right;
+                return compare(left, right) <= 0;
             case ("!="):
                 testComparisonOperands("!=", left, right);
                 return !isEqual(left, right);
             case ("=="):
                 testComparisonOperands("==", left, right);
                 return isEqual(left, right);
-            case ("**"):
+            case ("**"): {
                 testMathOperands("**", left, right);
-                return Math.pow(right, left);
+                const result = Math.pow(right, left);
+                if (isNaN(result)) {
+                    throw new InterpreterError('infix: ** result is not a number');
+                }
+                return result;
+            }
             case ("."): {
                 if (isObject(left)) {
                     if (left.hasOwnProperty(right)) {
@@ -105,19 +151,30 @@ class Interpreter {
                 }
                 throw expectationError('infix: .', 'objects');
             }
+            case ("?."): {
+                // like `.`, but null for a missing property; a null object skips
+                // the rest of the chain
+                if (left === null) {
+                    return SKIPPED;
+                }
+                if (isObject(left)) {
+                    return left.hasOwnProperty(right) ? left[right] : null;
+                }
+                throw expectationError('infix: ?.', 'objects or null');
+            }
             case ("in"): {
                 if (isObject(right)) {
                     if (!isString(left)) {
-                        throw expectationError('Infix: in-object', 'string on left side');
+                        throw expectationError('infix: in-object', 'string on left side');
                     }
                     right = Object.keys(right);
                 } else if (isString(right)) {
                     if (!isString(left)) {
-                        throw expectationError('Infix: in-string', 'string on left side');
+                        throw expectationError('infix: in-string', 'string on left side');
                     }
                     return right.indexOf(left) !== -1;
                 } else if (!isArray(right)) {
-                    throw expectationError('Infix: in', 'Array, string, or object on right side');
+                    throw expectationError('infix: in', 'array, string, or object on right side');
                 }
                 return right.some(r => isEqual(left, r));
             }
@@ -137,8 +194,16 @@ class Interpreter {
     }
 
     visit_ValueAccess(node) {
-        let array = this.visit(node.arr);
+        let array = this.visitLink(node.arr);
+        if (array === SKIPPED) {
+            return SKIPPED;
+        }
         let left = 0, right = null;
+        // index strings by code point, so that characters outside the BMP are not split
+        let isStr = isString(array);
+        if (isStr) {
+            array = Array.from(array);
+        }
 
         if (node.left) {
             left = this.visit(node.left);
@@ -149,7 +214,7 @@ class Interpreter {
         if (left < 0) {
             left = array.length + left
         }
-        if (isArray(array) || isString(array)) {
+        if (isArray(array)) {
             if (node.isInterval) {
                 right = right === null ? array.length : right;
                 if (right < 0) {
@@ -163,12 +228,13 @@ class Interpreter {
                 if (!isInteger(left) || !isInteger(right)) {
                     throw new InterpreterError('cannot perform interval access with non-integers');
                 }
-                return array.slice(left, right)
+                let slice = array.slice(left, right);
+                return isStr ? slice.join('') : slice;
             }
             if (!isInteger(left)) {
                 throw new InterpreterError('should only use integers to access arrays or strings');
             }
-            if (left >= array.length) {
+            if (left < 0 || left >= array.length) {
                 throw new InterpreterError('index out of bounds');
             }
             return array[left]
@@ -183,26 +249,33 @@ class Interpreter {
 
         if (array.hasOwnProperty(left)) {
             return array[left];
-        } else {
-            return null;
         }
+        throw new InterpreterError(`object has no property "${left}"`);
     }
 
     visit_ContextValue(node) {
-        if (this.context.hasOwnProperty(node.token.value)) {
+        // contexts inherit the variables of enclosing scopes
+        if (node.token.value in this.context) {
             let contextValue = this.context[node.token.value];
             return contextValue
         }
-        throw new InterpreterError(`unknown context value ${node.token.value}`);
+        throw new UndefinedVariableError(`unknown context value ${node.token.value}`, node.token.value);
     }
 
     visit_FunctionCall(node) {
         let args = [];
 
-        let funcName = this.visit(node.name);
+        let funcName = this.visitLink(node.name);
+        if (funcName === SKIPPED) {
+            return SKIPPED;
+        }
         if (isFunction(funcName)) {
             node.args.forEach(function (item) {
-                args.push(this.visit(item))
+                if (funcName.hasOwnProperty("jsone_lazy")) {
+                    args.push(() => this.visit(item))
+                } else {
+                    args.push(this.visit(item))
+                }
             }, this);
             if (funcName.hasOwnProperty("jsone_builtin")) {
                 args.unshift(this.context);
@@ -228,6 +301,25 @@ class Interpreter {
     }
 }
 
+// Strings are ordered by code point, as in Python and Go, rather than by UTF-16
+// code unit. Moving surrogates above the other code units is enough for that.
+let codePointOrder = c => c >= 0xd800 && c < 0xe000 ? c + 0x2000 : c >= 0xe000 ? c - 0x800 : c;
+
+// Compare two numbers or two strings, returning a negative number, zero or a
+// positive number
+let compare = (a, b) => {
+    if (isString(a) && a !== b) {
+        let n = Math.min(a.length, b.length);
+        for (let i = 0; i < n; i++) {
+            if (a[i] !== b[i]) {
+                return codePointOrder(a.charCodeAt(i)) - codePointOrder(b.charCodeAt(i));
+            }
+        }
+        return a.length - b.length;
+    }
+    return a < b ? -1 : a > b ? 1 : 0;
+};
+
 let isEqual = (a, b) => {
     if (isArray(a) && isArray(b) && a.length === b.length) {
         for (let i = 0; i < a.length; i++) {
@@ -255,11 +347,21 @@ let isEqual = (a, b) => {
     return a === b;
 };
 
+// && and || only take booleans, so that a value is not mistaken for its
+// truthiness; the operand is returned when it is one
+let testLogicalOperand = (operator, operand) => {
+    if (!isBool(operand)) {
+        throw expectationError(`infix: ${operator}`,
+            `boolean ${operator} boolean; compare other values, as in x != null`);
+    }
+    return operand;
+};
+
 let testMathOperands = (operator, left, right) => {
     if (operator === '+' && !(isNumber(left) && isNumber(right) || isString(left) && isString(right))) {
         throw expectationError('infix: +', 'numbers/strings + numbers/strings');
     }
-    if (['-', '*', '/', '**'].some(v => v === operator) && !(isNumber(left) && isNumber(right))) {
+    if (['-', '*', '/', '//', '%', '**'].some(v => v === operator) && !(isNumber(left) && isNumber(right))) {
         throw expectationError(`infix: ${operator}`, `number ${operator} number`);
     }
     return
@@ -281,3 +383,7 @@ let testComparisonOperands = (operator, left, right) => {
 
 exports
     .Interpreter = Interpreter;
+exports
+    .isEqual = isEqual;
+exports
+    .compare = compare;
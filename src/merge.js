@@ -0,0 +1,23 @@
+var {isArray, isObject} = require('./type-utils');
+
+// merge two values, preferring the right but concatenating lists and
+// recursively merging objects
+let mergeDeep = (l, r) => {
+  if (isArray(l) && isArray(r)) {
+    return l.concat(r);
+  }
+  if (isObject(l) && isObject(r)) {
+    let res = Object.assign({}, l);
+    for (let p in r) { // eslint-disable-line taskcluster/no-for-in
+      if (p in l) {
+        res[p] = mergeDeep(l[p], r[p]);
+      } else {
+        res[p] = r[p];
+      }
+    }
+    return res;
+  }
+  return r;
+};
+
+module.exports = mergeDeep;
@@ -1,11 +1,16 @@
-const {UnaryOp, BinOp, Primitive, ContextValue, FunctionCall, ValueAccess, List, Object} = require("../src/AST");
-const {SyntaxError} = require('./error');
+const {UnaryOp, BinOp, TernaryOp, Primitive, ContextValue, FunctionCall, ValueAccess, List, Object} = require("../src/AST");
+const {SyntaxError, LimitExceededError} = require('./error');
 
 let syntaxRuleError = (token, expects) => {
     expects.sort();
     return new SyntaxError(`Found ${token.value}, expected ${expects.join(', ')}`, token);
 };
 
+// The deepest an expression may nest, counting parentheses, brackets, braces,
+// unary operators and the right-associative `**`; expressions are parsed and
+// evaluated recursively, so this keeps that within the stack
+const MAX_NESTING = 64;
+
 class Parser {
     constructor(tokenizer, source, offset = 0) {
         this._source = source;
@@ -13,14 +18,15 @@ class Parser {
         this.current_token = this._tokenizer.next(this._source, offset);
         this.unaryOpTokens = ["-", "+", "!"];
         this.primitivesTokens = ["number", "null", "true", "false", "string"];
-        this.operations = [["||"], ["&&"], ["in"], ["==", "!="], ["<", ">", "<=", ">="], ["+", "-"], ["*", "/"], ["**"]];
+        this.operations = [["||"], ["&&"], ["in"], ["==", "!="], ["<", ">", "<=", ">="], ["+", "-"], ["*", "/", "//", "%"], ["**"]];
         this.expectedTokens = ["!", "(", "+", "-", "[", "false", "identifier", "null", "number", "string", "true", "{"];
+        this.depth = 0;
 
     }
 
     takeToken(...kinds) {
         if (this.current_token == null) {
-            throw new SyntaxError('Unexpected end of input');
+            throw new SyntaxError('Unexpected end of input', {start: this._source.length, end: this._source.length});
         }
 
         if (kinds.length > 0 && kinds.indexOf(this.current_token.kind) === -1) {
@@ -33,47 +39,98 @@ class Parser {
         }
     }
 
+    // Go count levels deeper into the expression, or back when negative
+    nest(count) {
+        this.depth += count;
+        if (this.depth > MAX_NESTING) {
+            throw new LimitExceededError(`expression is nested more than ${MAX_NESTING} levels deep`);
+        }
+    }
+
     parse(level = 0) {
-        //expr : logicalAnd (OR logicalAnd)*
+        //expr : logicalOr (QUESTION expr COLON expr)?
+        //logicalOr : logicalAnd (OR logicalAnd)*
         //logicalAnd : inStatement (AND inStatement)*
         //inStatement : equality (IN equality)*
         //equality : comparison (EQUALITY | INEQUALITY  comparison)*
         //comparison : addition (LESS | GREATER | LESSEQUAL | GREATEREQUAL addition)*
         //addition : multiplication (PLUS | MINUS multiplication)* "
-        //multiplication : exponentiation (MUL | DIV exponentiation)*
+        //multiplication : exponentiation (MUL | DIV | FLOORDIV | MOD exponentiation)*
         //exponentiation : propertyAccessOrFunc (EXP exponentiation)*
         let node;
+        // a whole expression, as within parentheses, is a level deeper, as is
+        // each operand of `**`, which nests to the right
+        let chain = level == 0 ? 1 : 0;
+        this.nest(chain);
         if (level == this.operations.length - 1) {
             node = this.parsePropertyAccessOrFunc();
             let token = this.current_token;
 
             for (; token != null && this.operations[level].indexOf(token.kind) !== -1; token = this.current_token) {
+                this.checkOperand(node, token);
                 this.takeToken(token.kind);
-                node = new BinOp(token, this.parse(level), node);
+                this.nest(1);
+                chain++;
+                let right = this.parse(level);
+                this.checkOperand(right, this.current_token);
+                node = new BinOp(token, right, node);
             }
         } else {
             node = this.parse(level + 1);
             let token = this.current_token;
 
             for (; token != null && this.operations[level].indexOf(token.kind) !== -1; token = this.current_token) {
+                this.checkOperand(node, token);
                 this.takeToken(token.kind);
-                node = new BinOp(token, node, this.parse(level + 1));
+                let right = this.parse(level + 1);
+                this.checkOperand(right, this.current_token);
+                node = new BinOp(token, node, right);
             }
         }
 
+        if (level == 0 && this.current_token != null && this.current_token.kind == "?") {
+            this.checkOperand(node, this.current_token);
+            node = this.parseTernary(node);
+        }
+
+        this.nest(-chain);
         return node
     }
 
+    // A binary operator needs an operand on either side, so `2 * *3` is not
+    // parsed as a multiplication with a missing operand
+    checkOperand(node, token) {
+        if (node == null) {
+            throw syntaxRuleError(token, this.expectedTokens);
+        }
+    }
+
+    parseTernary(condition) {
+        //    ternary : logicalOr QUESTION expr COLON expr
+        let token = this.current_token;
+        this.takeToken("?");
+        let thenExpr = this.parse();
+        if (thenExpr == null) {
+            throw syntaxRuleError(this.current_token, this.expectedTokens);
+        }
+        this.takeToken(":");
+        let elseExpr = this.parse();
+        if (elseExpr == null) {
+            throw syntaxRuleError(this.current_token, this.expectedTokens);
+        }
+        return new TernaryOp(token, condition, thenExpr, elseExpr);
+    }
+
     parsePropertyAccessOrFunc() {
         let node = this.parseUnit();
-        let operators = ["[", "(", "."];
+        let operators = ["[", "(", ".", "?."];
         let rightPart;
         for (let token = this.current_token; token != null && operators.indexOf(token.kind) !== -1; token = this.current_token) {
             if (token.kind == "[") {
                 node = this.parseAccessWithBrackets(node)
-            } else if (token.kind == ".") {
+            } else if (token.kind == "." || token.kind == "?.") {
                 token = this.current_token;
-                this.takeToken(".");
+                this.takeToken(token.kind);
                 rightPart = new Primitive(this.current_token);
                 this.takeToken("identifier");
                 node = new BinOp(token, node, rightPart)
@@ -86,18 +143,26 @@ class Parser {
 
 
     parseUnit() {
-        // unit : unaryOp unit | primitives | contextValue | LPAREN expr RPAREN | list | object
+        // unit : unaryOp exponentiation | primitives | contextValue | LPAREN expr RPAREN | list | object
         let token = this.current_token;
         let node;
-        let isUnaryOpToken = this.unaryOpTokens.indexOf(token.kind) !== -1;
-        let isPrimitivesToken = this.primitivesTokens.indexOf(token.kind) !== -1;
         if (this.current_token == null) {
-            throw new SyntaxError('Unexpected end of input');
+            throw new SyntaxError('Unexpected end of input', {start: this._source.length, end: this._source.length});
         }
+        let isUnaryOpToken = this.unaryOpTokens.indexOf(token.kind) !== -1;
+        let isPrimitivesToken = this.primitivesTokens.indexOf(token.kind) !== -1;
         if (isUnaryOpToken) {
             this.takeToken(token.kind);
-            node = new UnaryOp(token, this.parseUnit());
+            // the operand is an exponentiation, so `-2 ** 2` is `-(2 ** 2)`
+            this.nest(1);
+            let operand = this.parse(this.operations.length - 1);
+            this.checkOperand(operand, this.current_token);
+            this.nest(-1);
+            node = new UnaryOp(token, operand);
         } else if (isPrimitivesToken) {
+            if (token.kind == "string") {
+                checkString(token);
+            }
             this.takeToken(token.kind);
             node = new Primitive(token);
         } else if (token.kind == "identifier") {
@@ -125,7 +190,7 @@ class Parser {
         let args = [];
         this.takeToken("(");
 
-        if (this.current_token.kind != ")") {
+        if (this.current_token == null || this.current_token.kind != ")") {
             node = this.parse();
             args.push(node);
 
@@ -146,21 +211,24 @@ class Parser {
     }
 
     parseList() {
-        //    list : LSQAREBRAKET (expr ( COMMA expr)*)? RSQAREBRAKET)
+        //    list : LSQAREBRAKET (expr ( COMMA expr)* COMMA?)? RSQAREBRAKET)
         let node;
         let arr = [];
         let token = this.current_token;
         this.takeToken("[");
 
-        if (this.current_token.kind != "]") {
+        if (this.current_token == null || this.current_token.kind != "]") {
             node = this.parse();
             arr.push(node);
 
-            while (this.current_token.kind == ",") {
+            while (this.current_token != null && this.current_token.kind == ",") {
                 if (arr[arr.length - 1] == null) {
                     throw syntaxRuleError(this.current_token, this.expectedTokens);
                 }
                 this.takeToken(",");
+                if (this.current_token != null && this.current_token.kind == "]") {
+                    break;
+                }
                 node = this.parse();
                 arr.push(node)
             }
@@ -177,22 +245,22 @@ class Parser {
         let isInterval = false;
 
         this.takeToken("[");
-        if (this.current_token.kind == "]") {
+        if (this.current_token != null && this.current_token.kind == "]") {
             throw syntaxRuleError(this.current_token, this.expectedTokens);
         }
 
-        if (this.current_token.kind != ":") {
+        if (this.current_token == null || this.current_token.kind != ":") {
             leftArg = this.parse();
         }
-        if (this.current_token.kind == ":") {
+        if (this.current_token != null && this.current_token.kind == ":") {
             isInterval = true;
             this.takeToken(":");
         }
-        if (this.current_token.kind != "]") {
+        if (this.current_token == null || this.current_token.kind != "]") {
             rightArg = this.parse();
         }
 
-        if (isInterval && rightArg == null && this.current_token.kind != "]") {
+        if (isInterval && rightArg == null && this.current_token != null && this.current_token.kind != "]") {
             throw syntaxRuleError(this.current_token, this.expectedTokens);
         }
         this.takeToken("]");
@@ -213,6 +281,7 @@ class Parser {
         while (token != null && (token.kind == "string" || token.kind == "identifier")) {
             key = token.value;
             if (token.kind == "string") {
+                checkString(token);
                 key = parseString(key);
             }
             this.takeToken(token.kind);
@@ -237,11 +306,37 @@ class Parser {
 
 }
 
+// The characters of a string literal's escapes, other than \uXXXX
+const escapes = {n: '\n', t: '\t', '\\': '\\', "'": "'", '"': '"'};
+
+// Throw a SyntaxError if a string literal has a \uXXXX escape for a surrogate
+// that is not part of a pair, as not every language can represent one in a
+// string
+let checkString = (token) => {
+    let escape = /\\(u[dD][89abAB][0-9a-fA-F]{2}\\u[dD][c-fC-F][0-9a-fA-F]{2}|u[0-9a-fA-F]{4}|[^])/g;
+    let body = token.value.slice(1, -1);
+    for (let match; (match = escape.exec(body)) !== null;) {
+        let code = parseInt(match[1].slice(1), 16);
+        if (match[1].length === 5 && code >= 0xD800 && code <= 0xDFFF) {
+            throw new SyntaxError(`string contains a lone surrogate ${match[0]}`, token);
+        }
+    }
+};
+
+// Remove the quotes of a string literal and expand its escapes; a backslash
+// that does not begin an escape is kept as it is
 let
     parseString = (str) => {
-        return str.slice(1, -1);
+        return str.slice(1, -1).replace(/\\(u[0-9a-fA-F]{4}|[^])/g, (escape, c) => {
+            if (c.length === 5) {
+                return String.fromCharCode(parseInt(c.slice(1), 16));
+            }
+            return escapes.hasOwnProperty(c) ? escapes[c] : escape;
+        });
     };
 
 
 exports
     .Parser = Parser;
+exports
+    .parseString = parseString;
@@ -0,0 +1,56 @@
+// The operators whose result, if any, always has the same type
+const operatorTypes = {
+  '-': 'number', '*': 'number', '/': 'number', '//': 'number', '%': 'number', '**': 'number',
+  '<': 'boolean', '>': 'boolean', '<=': 'boolean', '>=': 'boolean', '==': 'boolean', '!=': 'boolean',
+  '&&': 'boolean', '||': 'boolean', 'in': 'boolean',
+};
+
+const literalTypes = {number: 'number', string: 'string', true: 'boolean', false: 'boolean', null: 'null'};
+
+// The type of the value the expression tree evaluates to, as named by the
+// typeof built-in, or null if that depends on the context. An expression that
+// fails when it is evaluated has no value, so it is not considered here.
+let resultType = node => {
+  switch (node.constructorName) {
+    case 'ASTNode':
+      return literalTypes[node.token.kind] || null;
+    case 'UnaryOp':
+      return node.token.kind === '!' ? 'boolean' : 'number';
+    case 'BinOp': {
+      if (node.token.kind === '+') {
+        // + adds numbers or concatenates strings, so one side is enough
+        let left = resultType(node.left), right = resultType(node.right);
+        if (left === 'number' || right === 'number') {
+          return 'number';
+        }
+        if (left === 'string' || right === 'string') {
+          return 'string';
+        }
+        return null;
+      }
+      return operatorTypes[node.token.kind] || null;
+    }
+    case 'TernaryOp': {
+      let thenType = resultType(node.thenExpr);
+      return thenType === resultType(node.elseExpr) ? thenType : null;
+    }
+    case 'List':
+      return 'array';
+    case 'Object':
+      return 'object';
+    case 'ValueAccess': {
+      // indexing or slicing a string gives a string, and slicing an array gives
+      // an array
+      let arrayType = resultType(node.arr);
+      if (arrayType === 'string' || arrayType === 'array' && node.isInterval) {
+        return arrayType;
+      }
+      return null;
+    }
+    default:
+      // context values, function calls and property access
+      return null;
+  }
+};
+
+module.exports = resultType;
@@ -28,6 +28,12 @@ let indexOfNotUndefined = (a, start = 0) => {
   return -1;
 };
 
+// Snippets of source in error messages are elided beyond this length, so that
+// a very long expression does not produce an even longer message
+const maxSnippetLength = 50;
+
+let snippet = (s) => s.length > maxSnippetLength ? s.slice(0, maxSnippetLength) + '...' : s;
+
 /**
  * Custom implementation of `assert` to avoid pulling in a
  * polyfill on browsers
@@ -55,25 +61,27 @@ class Tokenizer {
     assert(options.tokens instanceof Array);
     options.tokens.forEach(tokenName => assert(typeof tokenName === 'string'));
 
-    // Build regular expression
+    // Build regular expression, which is sticky so that it only matches at
+    // lastIndex, without slicing the source for each token
     this._tokens = options.tokens;
     this._hasIgnore = options.ignore ? 1 : 0;
-    this._regex = new RegExp('^(?:' + [
+    this._regex = new RegExp('(?:' + [
       this._hasIgnore ? `(${options.ignore})` : null,
       ...this._tokens.map(tokenName => {
         return `(${options.patterns[tokenName] || escapeRegex(tokenName)})`;
       }),
-    ].filter(e => e !== null).join('|') + ')');
+    ].filter(e => e !== null).join('|') + ')', 'y');
   }
 
   next(source, offset = 0) {
     let m, i;
     do {
-      m = this._regex.exec(source.slice(offset));
+      this._regex.lastIndex = offset;
+      m = this._regex.exec(source);
       if (m === null) {
         // If not at end of input throw an error
-        if (source.slice(offset) !== '') {
-          throw new SyntaxError(`Unexpected input for '${source}' at '${source.slice(offset)}'`,
+        if (offset < source.length) {
+          throw new SyntaxError(`Unexpected input for '${snippet(source)}' at '${snippet(source.slice(offset))}'`,
             {start: offset, end: source.length});
         }
         return null;
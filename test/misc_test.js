@@ -8,6 +8,422 @@ suite('misc', function() {
     assume(jsone({$eval: 'my_builtin(3, 4)'}, {my_builtin})).eql(5);
   });
 
+  test('custom function used in an expression', function() {
+    let double = x => 2 * x;
+
+    assume(jsone({$eval: 'double(x) + 1'}, {double, x: 4})).eql(9);
+  });
+
+  test('custom functions in a namespace object', function() {
+    let math = {sqrt: Math.sqrt, abs: Math.abs};
+
+    assume(jsone({$eval: 'math.sqrt(16) + math.abs(-1)'}, {math})).eql(5);
+  });
+
+  test('custom function overrides builtin', function() {
+    let min = (x, y) => x + y;
+
+    assume(jsone({$eval: 'min(3, 4)'}, {min})).eql(7);
+  });
+
+  test('errors thrown by custom functions propagate', function() {
+    let secrets = {'my/path': 's3cret'};
+    let secret = path => {
+      if (!secrets.hasOwnProperty(path)) {
+        throw new Error(`no secret at ${path}`);
+      }
+      return secrets[path];
+    };
+
+    assume(jsone({token: '${secret("my/" + "path")}'}, {secret})).eql({token: 's3cret'});
+    assume(() => jsone({$eval: 'secret("other")'}, {secret})).throws('no secret at other');
+  });
+
+  test('custom functions in untaken branches are not called', function() {
+    let calls = 0;
+    let expensive = () => ++calls;
+    let template = {
+      and: {$eval: 'false && expensive()'},
+      or: {$eval: 'true || expensive()'},
+      ternary: {$eval: 'true ? 0 : expensive()'},
+      if: {$if: 'false', then: {$eval: 'expensive()'}, else: '${expensive()}'},
+    };
+
+    assume(jsone(template, {expensive})).eql({and: false, or: true, ternary: 0, if: '1'});
+    assume(calls).eql(1);
+  });
+
+  test('maxElements limits the size of the output', function() {
+    let template = {$map: {$eval: 'items'}, 'each(x)': {$map: {$eval: 'items'}, 'each(y)': {$eval: 'x * y'}}};
+    let items = [...Array(100).keys()];
+
+    assume(jsone(template, {items}).length).eql(100);
+    assume(() => jsone(template, {items}, {maxElements: 1000}))
+      .throws(/output exceeds the maximum of 1000 elements/);
+    assume(jsone(template, {items}, {maxElements: 100 * 100 + 100}).length).eql(100);
+  });
+
+  test('maxSteps limits the evaluation steps', function() {
+    let template = {$map: {$eval: 'items'}, 'each(x)': {$map: {$eval: 'items'}, 'each(y)': {$eval: 'x * y'}}};
+    let items = [...Array(100).keys()];
+
+    assume(jsone(template, {items}, {maxSteps: 100000}).length).eql(100);
+    assume(() => jsone(template, {items}, {maxSteps: 10000}))
+      .throws(/evaluation budget exceeded/);
+    // each node of an expression is a step
+    assume(jsone({$eval: '1 + 2 * 3'}, {}, {maxSteps: 5})).eql(7);
+    assume(() => jsone('${1 + 2 * 3}', {}, {maxSteps: 4}))
+      .throws(/evaluation budget exceeded/);
+  });
+
+  test('maxDepth limits the nesting of the template', function() {
+    // an object, an array, and an operator
+    let template = {a: [{$eval: '1'}]};
+    assume(jsone(template, {}, {maxDepth: 3})).eql({a: [1]});
+    assume(() => jsone(template, {}, {maxDepth: 2}))
+      .throws('template nesting exceeds the maximum depth of 2');
+
+    let deep = 1;
+    for (let i = 0; i < 100000; i++) {
+      deep = [deep];
+    }
+    assume(() => jsone(deep, {}, {maxDepth: 100}))
+      .throws('template nesting exceeds the maximum depth of 100');
+  });
+
+  test('deeply nested expressions exceed a limit', function() {
+    let template = {$eval: '('.repeat(5000) + '1' + ')'.repeat(5000)};
+    assume(() => jsone(template, {}, {maxDepth: 10, maxSteps: 100}))
+      .throws('expression is nested more than 64 levels deep');
+    assume(() => jsone(template, {})).throws(jsone.LimitExceededError);
+  });
+
+  test('maxExpressionLength limits the length of expressions', function() {
+    assume(jsone({a: {$eval: 'x + 1'}, b: '${x + 1}'}, {x: 1}, {maxExpressionLength: 5})).eql({a: 2, b: '2'});
+    assume(() => jsone({$eval: 'x + 10'}, {x: 1}, {maxExpressionLength: 5}))
+      .throws('expression exceeds the maximum length of 5');
+    assume(() => jsone('${x + 10}', {x: 1}, {maxExpressionLength: 5}))
+      .throws('expression exceeds the maximum length of 5');
+  });
+
+  test('maxInterpolationLength limits the length of interpolated strings', function() {
+    assume(jsone('<${x}${x}>', {x: 'abcd'}, {maxInterpolationLength: 10})).eql('<abcdabcd>');
+    assume(() => jsone('<${x}${x}>!', {x: 'abcd'}, {maxInterpolationLength: 10}))
+      .throws('interpolation exceeds the maximum length of 10');
+    assume(() => jsone('${x}${x}${x}', {x: 'abcd'}, {maxInterpolationLength: 10}))
+      .throws(jsone.LimitExceededError);
+  });
+
+  test('timeout limits the time a render takes', function() {
+    let template = {$map: {$eval: 'items'}, 'each(x)': {$eval: 'x * 2'}};
+    let items = [...Array(10).keys()];
+    assume(jsone(template, {items}, {timeout: 10000})).eql(items.map(x => x * 2));
+    assume(() => jsone(template, {items}, {timeout: -1})).throws('render exceeds the timeout of -1ms');
+  });
+
+  test('keys keep the template order unless sortKeys is set', function() {
+    let template = {
+      z: 1,
+      m: {$merge: [{y: 1, a: 1}, {b: 1}]},
+      a: {$mergeDeep: [{d: {c: 1, b: 1}}, {d: {a: 1}}]},
+    };
+    let result = jsone(template, {});
+    assume(Object.keys(result)).eql(['z', 'm', 'a']);
+    assume(Object.keys(result.m)).eql(['y', 'a', 'b']);
+    assume(Object.keys(result.a.d)).eql(['c', 'b', 'a']);
+
+    result = jsone(template, {}, {sortKeys: true});
+    assume(Object.keys(result)).eql(['a', 'm', 'z']);
+    assume(Object.keys(result.m)).eql(['a', 'b', 'y']);
+    assume(Object.keys(result.a.d)).eql(['a', 'b', 'c']);
+    // by code point, so characters above the base plane come last
+    assume(Object.keys(jsone({'\u{10000}': 2, '\uffff': 1}, {}, {sortKeys: true}))).eql(['\uffff', '\u{10000}']);
+  });
+
+  test('partial leaves what refers to undefined variables for a later render', function() {
+    let template = {
+      greeting: 'hello ${name}',
+      count: {$eval: 'x + 1'},
+      items: ['${y}', {$if: 'x > 0', then: 'positive'}],
+      '${key}': 'keyed',
+      escaped: {$eval: 'dollar'},
+      scoped: {$let: {a: 1}, in: '${a}${name}'},
+    };
+    let first = jsone(template, {x: 1, dollar: '${y}'}, {partial: true});
+    assume(first).eql({
+      greeting: 'hello ${name}',
+      count: 2,
+      items: ['${y}', 'positive'],
+      '${key}': 'keyed',
+      escaped: {$$: '${y}'},
+      scoped: {$let: {a: 1}, in: '${a}${name}'},
+    });
+    assume(jsone(first, {name: 'pat', y: 'why', key: 'k'})).eql({
+      greeting: 'hello pat',
+      count: 2,
+      items: ['why', 'positive'],
+      k: 'keyed',
+      escaped: '${y}',
+      scoped: '1pat',
+    });
+    // other errors are still errors, and without partial so are undefined variables
+    assume(() => jsone({a: '${1 + true}'}, {}, {partial: true})).throws(/infix: \+ expects/);
+    assume(() => jsone('${x}', {})).throws(/unknown context value x/);
+  });
+
+  test('expectType checks the type of the output', function() {
+    assume(jsone({a: {$eval: 'x'}}, {x: 1}, {expectType: 'object'})).eql({a: 1});
+    assume(jsone({$eval: '[x]'}, {x: 1}, {expectType: 'array'})).eql([1]);
+    assume(jsone({$if: 'false', then: 1}, {}, {expectType: 'null'})).eql(null);
+    assume(() => jsone('${x}', {x: 1}, {expectType: 'object'}))
+      .throws(/rendered output is of type string, expected object/);
+    assume(() => jsone({$eval: '[x]'}, {x: 1}, {expectType: 'object'}))
+      .throws(/rendered output is of type array, expected object/);
+  });
+
+  test('object keys keep the order of the template', function() {
+    let template = {z: 1, a: {$eval: 'x'}, m: {c: 3, b: 2}};
+    let first = jsone(template, {x: 2});
+
+    assume(Object.keys(first)).eql(['z', 'a', 'm']);
+    assume(Object.keys(first.m)).eql(['c', 'b']);
+    assume(JSON.stringify(first)).eql(JSON.stringify(jsone(template, {x: 2})));
+  });
+
+  test('renderEach streams the elements of a $map', function() {
+    let template = {$map: {$eval: 'items'}, 'each(x,i)': {$if: 'i % 2 == 0', then: {$eval: 'x * 2'}}};
+    let items = [...Array(10000).keys()];
+    let count = 0, first, last;
+
+    jsone.renderEach(template, {items}, value => {
+      if (count === 0) {
+        first = value;
+      }
+      last = value;
+      count++;
+    });
+    assume(count).eql(5000);
+    assume(first).eql(0);
+    assume(last).eql(19996);
+  });
+
+  test('renderEach streams the elements of an array template', function() {
+    let values = [];
+    jsone.renderEach([{$eval: 'a'}, {$if: 'false', then: 1}, 'x${a}'], {a: 1}, v => values.push(v));
+    assume(values).eql([1, 'x1']);
+  });
+
+  test('renderEach requires an array result', function() {
+    assume(() => jsone.renderEach({a: 1}, {}, () => {}))
+      .throws(/renderEach requires a template that renders to an array/);
+    assume(() => jsone.renderEach({$map: {a: 1}, 'each(x)': {b: 2}}, {}, () => {}))
+      .throws(/renderEach requires a template that renders to an array/);
+  });
+
+  test('renderEach respects maxElements', function() {
+    let template = {$map: {$eval: 'items'}, 'each(x)': {$eval: 'x'}};
+    let items = [...Array(100).keys()];
+    let count = 0;
+
+    assume(() => jsone.renderEach(template, {items}, () => count++, {maxElements: 10}))
+      .throws(/output exceeds the maximum of 10 elements/);
+    assume(count).eql(10);
+  });
+
+  test('rendering does not modify the context', function() {
+    let context = {items: [3, 1, 2], objs: [{a: [1]}, {a: [2]}], o: {x: 1}};
+    let template = {
+      $let: {y: {$eval: 'o.x'}},
+      in: {
+        m: {$map: {$eval: 'items'}, 'each(x,i)': {$eval: 'x + y + i'}},
+        s: {$sort: {$eval: 'items'}},
+        r: {$reverse: {$eval: 'items'}},
+        d: {$mergeDeep: {$eval: 'objs'}},
+        f: {$map: {$eval: 'o'}, 'each(v,k)': {'${k}': {$eval: 'v'}}},
+      },
+    };
+    let before = JSON.stringify(context);
+
+    jsone(template, context);
+    assume(JSON.stringify(context)).eql(before);
+    assume(Object.keys(context)).eql(['items', 'objs', 'o']);
+  });
+
+  test('scopes of a large context are not copied', function() {
+    let context = {items: [...Array(10000).keys()]};
+    for (let i = 0; i < 10000; i++) {
+      context[`v${i}`] = i;
+    }
+    let template = {$map: {$eval: 'items'}, 'each(x)': {$let: {y: {$eval: 'x + v1'}}, in: {$eval: 'y + v9999'}}};
+    let result = jsone(template, context);
+
+    assume(result.length).eql(10000);
+    assume(result[0]).eql(10000);
+    assume(result[9999]).eql(19999);
+  });
+
+  // Call fn, counting the expressions it parses, returning its result and the count
+  let countParses = fn => {
+    let {Parser} = require('../src/parser');
+    let parse = Parser.prototype.parse;
+    let parses = 0, depth = 0;
+    Parser.prototype.parse = function(...args) {
+      parses += depth === 0 ? 1 : 0;
+      depth++;
+      try {
+        return parse.apply(this, args);
+      } finally {
+        depth--;
+      }
+    };
+    try {
+      return {result: fn(), parses};
+    } finally {
+      Parser.prototype.parse = parse;
+    }
+  };
+
+  // items, x * n, and the two interpolations
+  let parsedTemplate = {$map: {$eval: 'items'}, 'each(x)': {a: {$eval: 'x * n'}, b: '${x}-${x * n}'}};
+
+  test('expressions are parsed once per render', function() {
+    let {result, parses} = countParses(() => jsone(parsedTemplate, {items: [1, 2, 3], n: 2}));
+    assume(result).eql([{a: 2, b: '1-2'}, {a: 4, b: '2-4'}, {a: 6, b: '3-6'}]);
+    assume(parses).eql(4);
+  });
+
+  test('compiled templates parse expressions once, when compiled', function() {
+    let {result: template, parses} = countParses(() => jsone.compile(parsedTemplate));
+    assume(parses).eql(4);
+    let renders = countParses(() => [1, 2, 3].map(n => template({items: [1, 2], n})));
+    assume(renders.result).eql([
+      [{a: 1, b: '1-1'}, {a: 2, b: '2-2'}],
+      [{a: 2, b: '1-2'}, {a: 4, b: '2-4'}],
+      [{a: 3, b: '1-3'}, {a: 6, b: '2-6'}],
+    ]);
+    assume(renders.parses).eql(0);
+    // options apply to each render
+    assume(() => template({items: [1, 2], n: 1}, {maxSteps: 3})).throws('evaluation budget exceeded');
+    assume(template({items: [], n: 1}, {maxSteps: 3})).eql([]);
+  });
+
+  test('compile reports problems in the structure of the template', function() {
+    assume(() => jsone.compile({a: {$if: 'x', then: 1, els: 2}})).throws('$if has undefined properties: els');
+  });
+
+  test('compile reports syntax errors in expressions that are not rendered', function() {
+    assume(() => jsone.compile({$eval: '1 +'})).throws('Unexpected end of input');
+    assume(() => jsone.compile({$if: 'true', then: 1, else: '${x +}'})).throws('Found }, expected');
+    assume(() => jsone.compile({$switch: {'x ==': 1}})).throws('Unexpected end of input');
+    jsone.compile({a: '$${x +} ${x}'});
+  });
+
+  test('very long tokens are handled promptly', function() {
+    let name = 'x'.repeat(1000000);
+    assume(jsone({$eval: `${name} + 1`}, {[name]: 1})).eql(2);
+
+    let start = Date.now();
+    let message;
+    try {
+      jsone({$eval: `'${name}`}, {});
+    } catch (err) {
+      message = err.toString();
+    }
+    assume(message).includes(`SyntaxError: Unexpected input for ''${'x'.repeat(49)}...' at`);
+    assume(message.length).lessThan(200);
+    assume(Date.now() - start).lessThan(1000);
+  });
+
+  test('syntax errors give the offsets of the problem in the expression', function() {
+    let offsets = template => {
+      try {
+        jsone(template, {});
+      } catch (err) {
+        return [err.name, err.start, err.end, err.location.join('')];
+      }
+    };
+    assume(offsets({a: [{$eval: '1 2'}]})).eql(['SyntaxError', 2, 3, '.a[0]']);
+    assume(offsets({$eval: 'a # b'})).eql(['SyntaxError', 2, 5, '']);
+    assume(offsets({$eval: '1 +'})).eql(['SyntaxError', 3, 3, '']);
+    assume(offsets({a: 'x ${1 + }'})).eql(['SyntaxError', 4, 5, '.a']);
+  });
+
+  test('validate reports every structural problem', function() {
+    let template = {
+      a: {$if: 'x', then: 1, els: 2},
+      b: [{$map: [1, 2], 'each(1x)': 3}],
+      c: {$foo: 1},
+      d: {$sort: [1], 'by(x)': 'x', 'by(y)': 'y'},
+    };
+    let errors = jsone.validate(template).map(err => err.toString());
+
+    assume(errors).eql([
+      'TemplateError at template.a: $if has undefined properties: els',
+      'TemplateError at template.b[0]: $map has undefined properties: each(1x)',
+      'TemplateError at template.c: $foo is reserved; use $$foo',
+      'TemplateError at template.d: only one by(..) is allowed',
+    ]);
+  });
+
+  test('validate accepts a valid template', function() {
+    let template = {
+      $let: {x: 1},
+      in: {a: {$map: [1, 2], 'each(y)': {$switch: {'y == 1': 'one', $default: 'many'}}}, $$b: 2,
+        c: {$$: {$frobnicate: 1, $if: 'x', else: 2}}},
+    };
+
+    assume(jsone.validate(template)).eql([]);
+  });
+
+  test('staticResultType gives the type of literal expressions', function() {
+    assume(jsone.staticResultType('1 + 2')).equals('number');
+    assume(jsone.staticResultType('"a" + \'b\'')).equals('string');
+    assume(jsone.staticResultType('x == 1 || !y')).equals('boolean');
+    assume(jsone.staticResultType('null')).equals('null');
+    assume(jsone.staticResultType('[x, y][1:]')).equals('array');
+    assume(jsone.staticResultType('{a: x}')).equals('object');
+    assume(jsone.staticResultType('x ? 1 : 2 * y')).equals('number');
+    // these depend on the context
+    assume(jsone.staticResultType('x')).equals(null);
+    assume(jsone.staticResultType('x.y')).equals(null);
+    assume(jsone.staticResultType('len(x)')).equals(null);
+    assume(jsone.staticResultType('x ? 1 : "a"')).equals(null);
+    assume(() => jsone.staticResultType('1 +')).throws(/Unexpected end of input/);
+  });
+
+  test('freeVariables gives the context values an expression refers to', function() {
+    assume(jsone.freeVariables('a.b + len(c[d:]) > 0 ? {k: e, m: e} : [f?.g, "x"]'))
+      .eql(['a', 'c', 'd', 'e', 'f', 'len']);
+    assume(jsone.freeVariables('1 + 2')).eql([]);
+    assume(jsone.parseExpression('x + 1').constructorName).equals('BinOp');
+    assume(() => jsone.freeVariables('1 +')).throws(/Unexpected end of input/);
+  });
+
+  test('tokenize gives the tokens of an expression', function() {
+    assume(jsone.tokenize('1 + foo * \'x\'')).eql([
+      {kind: 'number', value: '1', start: 0, end: 1},
+      {kind: '+', value: '+', start: 2, end: 3},
+      {kind: 'identifier', value: 'foo', start: 4, end: 7},
+      {kind: '*', value: '*', start: 8, end: 9},
+      {kind: 'string', value: '\'x\'', start: 10, end: 13},
+    ]);
+    assume(jsone.tokenize('2**3').map(t => t.kind)).eql(['number', '**', 'number']);
+    assume(jsone.tokenize('2* *3').map(t => t.kind)).eql(['number', '*', '*', 'number']);
+    assume(() => jsone.tokenize('1 # 2')).throws(/Unexpected input/);
+  });
+
+  test('builtinNames lists the built-in functions', function() {
+    let names = jsone.builtinNames();
+    let builtins = require('../src/builtins')({});
+
+    assume(names).includes('min');
+    assume(names).includes('fromNow');
+    assume(names).includes('uppercase');
+    assume(names).not.includes('now');
+    assume(names.length).eql(Object.keys(builtins).length);
+    assume(names).eql([...names].sort());
+  });
+
   test('time doesn\'t change mid-evaluation (operator)', function() {
     let template = [...Array(1000).keys()].map(() => ({$fromNow: ''}));
     let result = new Set(jsone(template, {}));
@@ -82,6 +82,57 @@ def make_strategies():
         context=dictionaries(prefixed_identifiers(), json_strategy()))
 
 
+# invariants of the array operators, over arrays that $sort accepts
+sortable_arrays = lambda: (
+    lists(integers(min_value=-1000, max_value=1000)) |
+    lists(floats(min_value=-1000, max_value=1000, allow_nan=False, allow_infinity=False)) |
+    lists(text(max_size=5)))
+
+
+@given(sortable_arrays())
+def test_reverse_of_reverse_is_identity(a):
+    eq_(jsone.render({'$reverse': {'$reverse': {'$eval': 'a'}}}, {'a': a}), a)
+
+
+@given(sortable_arrays())
+def test_sort_is_an_ordered_permutation(a):
+    result = jsone.render({'$sort': {'$eval': 'a'}}, {'a': a})
+    eq_(sorted(result), sorted(a))
+    assert all(x <= y for x, y in zip(result, result[1:])), result
+
+
+@given(sortable_arrays())
+def test_sort_is_idempotent(a):
+    once = jsone.render({'$sort': {'$eval': 'a'}}, {'a': a})
+    eq_(jsone.render({'$sort': {'$sort': {'$eval': 'a'}}}, {'a': a}), once)
+
+
+@given(lists(integers(min_value=-1000, max_value=1000)))
+def test_sort_by_negation_is_reversed_sort(a):
+    descending = jsone.render({'$sort': {'$eval': 'a'}, 'by(x)': '-x'}, {'a': a})
+    eq_(descending, jsone.render({'$reverse': {'$sort': {'$eval': 'a'}}}, {'a': a}))
+
+
+@given(lists(integers(min_value=0, max_value=5)))
+def test_sort_by_is_stable(keys):
+    a = [{'k': k, 'i': i} for i, k in enumerate(keys)]
+    result = jsone.render({'$sort': {'$eval': 'a'}, 'by(x)': 'x.k'}, {'a': a})
+    eq_(result, sorted(a, key=lambda e: e['k']))
+
+
+@given(sortable_arrays())
+def test_map_commutes_with_reverse(a):
+    double = lambda t: {'$map': t, 'each(x)': [{'$eval': 'x'}, {'$eval': 'x'}]}
+    eq_(jsone.render(double({'$reverse': {'$eval': 'a'}}), {'a': a}),
+        jsone.render({'$reverse': double({'$eval': 'a'})}, {'a': a}))
+
+
+@given(sortable_arrays())
+def test_map_with_index_preserves_length_and_order(a):
+    result = jsone.render({'$map': {'$eval': 'a'}, 'each(x, i)': {'$eval': 'i'}}, {'a': a})
+    eq_(result, list(range(len(a))))
+
+
 if os.environ.get('RUN_PROP_TESTS'):
     @settings(max_examples=1000000, timeout=3600)
     @given(**make_strategies())
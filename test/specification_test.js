@@ -19,6 +19,10 @@ suite('json-e', () => {
       assert(!spec[c.section], `section ${c.section} is defined twice`);
       spec[c.section] = section = [];
     } else {
+      // every case is reported by title, so a repeated one would be hidden
+      assert(!section.some(other => other.title === c.title), `${c.title} is defined twice in its section`);
+      assert('template' in c, `${c.title} has no template`);
+      assert(('result' in c) !== ('error' in c), `${c.title} must have one of result and error`);
       section.push(c);
     }
   });
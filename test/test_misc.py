@@ -1,10 +1,19 @@
 from __future__ import absolute_import, print_function, unicode_literals
 
-import math
+import copy
 import datetime
-from nose.tools import eq_
+import io
+import json
+import math
+import os
+import shutil
+import sys
+import tempfile
+from nose.tools import eq_, assert_raises
 from jsone.shared import string, stringDate
-from jsone import render, JSONTemplateError
+from jsone import (builtins, builtin_names, compile, free_variables, parse_expression, render, render_each,
+                   static_result_type, tokenize, validate, JSONTemplateError, LimitExceededError, TemplateError)
+from jsone.__main__ import main
 
 
 def test_custom_builtin():
@@ -12,6 +21,443 @@ def test_custom_builtin():
         return math.sqrt(x ** 2 + y ** 2)
     eq_(render({'$eval': 'my_builtin(3, 4)'}, {'my_builtin': my_builtin}), 5)
 
+def test_custom_function_in_expression():
+    def double(x):
+        return 2 * x
+    eq_(render({'$eval': 'double(x) + 1'}, {'double': double, 'x': 4}), 9)
+
+def test_custom_functions_in_namespace():
+    context = {'math': {'sqrt': math.sqrt, 'abs': abs}}
+    eq_(render({'$eval': 'math.sqrt(16) + math.abs(-1)'}, context), 5)
+
+def test_custom_function_overrides_builtin():
+    def min(x, y):
+        return x + y
+    eq_(render({'$eval': 'min(3, 4)'}, {'min': min}), 7)
+
+def test_custom_function_errors_propagate():
+    secrets = {'my/path': 's3cret'}
+
+    def secret(path):
+        if path not in secrets:
+            raise KeyError(path)
+        return secrets[path]
+    eq_(render({'token': '${secret("my/" + "path")}'}, {'secret': secret}), {'token': 's3cret'})
+    with assert_raises(KeyError):
+        render({'$eval': 'secret("other")'}, {'secret': secret})
+
+def test_untaken_branches_not_called():
+    calls = []
+
+    def expensive():
+        calls.append(None)
+        return len(calls)
+    template = {
+        'and': {'$eval': 'false && expensive()'},
+        'or': {'$eval': 'true || expensive()'},
+        'ternary': {'$eval': 'true ? 0 : expensive()'},
+        'if': {'$if': 'false', 'then': {'$eval': 'expensive()'}, 'else': '${expensive()}'},
+    }
+    eq_(render(template, {'expensive': expensive}), {'and': False, 'or': True, 'ternary': 0, 'if': '1'})
+    eq_(len(calls), 1)
+
+def test_max_elements():
+    template = {'$map': {'$eval': 'items'}, 'each(x)': {'$map': {'$eval': 'items'}, 'each(y)': {'$eval': 'x * y'}}}
+    context = {'items': list(range(100))}
+    eq_(len(render(template, context)), 100)
+    eq_(len(render(template, context, max_elements=100 * 100 + 100)), 100)
+    with assert_raises(TemplateError) as cm:
+        render(template, context, max_elements=1000)
+    eq_(str(cm.exception), 'TemplateError: output exceeds the maximum of 1000 elements')
+
+def test_max_steps():
+    template = {'$map': {'$eval': 'items'}, 'each(x)': {'$map': {'$eval': 'items'}, 'each(y)': {'$eval': 'x * y'}}}
+    context = {'items': list(range(100))}
+    eq_(len(render(template, context, max_steps=100000)), 100)
+    with assert_raises(TemplateError) as cm:
+        render(template, context, max_steps=10000)
+    eq_(str(cm.exception), 'TemplateError: evaluation budget exceeded')
+    # each node of an expression is a step
+    eq_(render({'$eval': '1 + 2 * 3'}, {}, max_steps=5), 7)
+    with assert_raises(TemplateError):
+        render('${1 + 2 * 3}', {}, max_steps=4)
+
+def test_max_depth():
+    # an object, an array, and an operator
+    template = {'a': [{'$eval': '1'}]}
+    eq_(render(template, {}, max_depth=3), {'a': [1]})
+    with assert_raises(TemplateError) as cm:
+        render(template, {}, max_depth=2)
+    eq_(str(cm.exception), 'TemplateError at template.a[0]: template nesting exceeds the maximum depth of 2')
+
+    deep = 1
+    for _ in range(100000):
+        deep = [deep]
+    with assert_raises(TemplateError):
+        render(deep, {}, max_depth=100)
+
+def test_deeply_nested_expressions():
+    template = {'$eval': '(' * 5000 + '1' + ')' * 5000}
+    with assert_raises(LimitExceededError) as cm:
+        render(template, {}, max_depth=10, max_steps=100)
+    eq_(str(cm.exception), 'TemplateError: expression is nested more than 64 levels deep')
+
+def test_max_expression_length():
+    eq_(render({'a': {'$eval': 'x + 1'}, 'b': '${x + 1}'}, {'x': 1}, max_expression_length=5), {'a': 2, 'b': '2'})
+    for template in [{'$eval': 'x + 10'}, '${x + 10}']:
+        with assert_raises(LimitExceededError) as cm:
+            render(template, {'x': 1}, max_expression_length=5)
+        eq_(str(cm.exception), 'TemplateError: expression exceeds the maximum length of 5')
+
+def test_max_interpolation_length():
+    eq_(render('<${x}${x}>', {'x': 'abcd'}, max_interpolation_length=10), '<abcdabcd>')
+    for template in ['<${x}${x}>!', '${x}${x}${x}']:
+        with assert_raises(LimitExceededError) as cm:
+            render(template, {'x': 'abcd'}, max_interpolation_length=10)
+        eq_(str(cm.exception), 'TemplateError: interpolation exceeds the maximum length of 10')
+
+def test_timeout():
+    template = {'$map': {'$eval': 'items'}, 'each(x)': {'$eval': 'x * 2'}}
+    context = {'items': list(range(10))}
+    eq_(render(template, context, timeout=10), [x * 2 for x in range(10)])
+    with assert_raises(LimitExceededError) as cm:
+        render(template, context, timeout=-1)
+    eq_(str(cm.exception), 'TemplateError: render exceeds the timeout of -1 seconds')
+
+def test_sort_keys():
+    template = {
+        'z': 1,
+        'm': {'$merge': [{'y': 1, 'a': 1}, {'b': 1}]},
+        'a': {'$mergeDeep': [{'d': {'c': 1, 'b': 1}}, {'d': {'a': 1}}]},
+    }
+    result = render(template, {}, sort_keys=True)
+    eq_(result, {'z': 1, 'm': {'y': 1, 'a': 1, 'b': 1}, 'a': {'d': {'c': 1, 'b': 1, 'a': 1}}})
+    eq_(list(result), ['a', 'm', 'z'])
+    eq_(list(result['m']), ['a', 'b', 'y'])
+    eq_(list(result['a']['d']), ['a', 'b', 'c'])
+    # by code point, so characters above the base plane come last
+    eq_(list(render({'\U00010000': 2, '\uffff': 1}, {}, sort_keys=True)), ['\uffff', '\U00010000'])
+    # dicts only keep the order of their keys from Python 3.7
+    if sys.version_info >= (3, 7):
+        eq_(list(render(template, {})), ['z', 'm', 'a'])
+        eq_(list(render(template, {})['m']), ['y', 'a', 'b'])
+
+def test_partial():
+    template = {
+        'greeting': 'hello ${name}',
+        'count': {'$eval': 'x + 1'},
+        'items': ['${y}', {'$if': 'x > 0', 'then': 'positive'}],
+        '${key}': 'keyed',
+        'escaped': {'$eval': 'dollar'},
+        'scoped': {'$let': {'a': 1}, 'in': '${a}${name}'},
+    }
+    first = render(template, {'x': 1, 'dollar': '${y}'}, partial=True)
+    eq_(first, {
+        'greeting': 'hello ${name}',
+        'count': 2,
+        'items': ['${y}', 'positive'],
+        '${key}': 'keyed',
+        'escaped': {'$$': '${y}'},
+        'scoped': {'$let': {'a': 1}, 'in': '${a}${name}'},
+    })
+    eq_(render(first, {'name': 'pat', 'y': 'why', 'key': 'k'}), {
+        'greeting': 'hello pat',
+        'count': 2,
+        'items': ['why', 'positive'],
+        'k': 'keyed',
+        'escaped': '${y}',
+        'scoped': '1pat',
+    })
+    # other errors are still errors, and without partial so are undefined variables
+    with assert_raises(JSONTemplateError):
+        render({'a': '${1 + true}'}, {}, partial=True)
+    with assert_raises(JSONTemplateError):
+        render('${x}', {})
+
+def test_expect_type():
+    eq_(render({'a': {'$eval': 'x'}}, {'x': 1}, expect_type='object'), {'a': 1})
+    eq_(render({'$eval': '[x]'}, {'x': 1}, expect_type='array'), [1])
+    eq_(render({'$if': 'false', 'then': 1}, {}, expect_type='null'), None)
+    with assert_raises(TemplateError) as cm:
+        render('${x}', {'x': 1}, expect_type='object')
+    eq_(str(cm.exception), 'TemplateError: rendered output is of type string, expected object')
+    with assert_raises(TemplateError) as cm:
+        render({'$eval': 'x > 0'}, {'x': 1}, expect_type='number')
+    eq_(str(cm.exception), 'TemplateError: rendered output is of type boolean, expected number')
+
+def test_large_integers():
+    template = json.loads('{"big": 123456789012345678901234567890, "let": {"$let": {"x": 9007199254740993}, "in": ["${x}", {"$eval": "x"}]}}')
+    eq_(json.dumps(render(template, {}), sort_keys=True),
+        '{"big": 123456789012345678901234567890, "let": ["9007199254740993", 9007199254740993]}')
+
+def test_integral_math_results():
+    # these render as integers, not as floats such as 4.0
+    eq_(json.dumps(render({'$eval': '[abs(-4), sign(-3), sign(0), sign(2.5), ceil(0.5)]'}, {})),
+        '[4, -1, 0, 1, 1]')
+    eq_(json.dumps(render({'$eval': '[6 / 2, -6 / 3, 0 / 5, 7 / 2, 6.0 / 2]'}, {})), '[3, -2, 0, 3.5, 3]')
+    eq_(json.dumps(render({'$eval': '[1.5 * 2, 0.5 + 0.5, 3.5 - 0.5, 4 ** 0.5, 7.5 % 2.5, 2 ** 60 / 2]'}, {})),
+        '[3, 1, 3, 2, 0, 5.764607523034235e+17]')
+
+def test_object_key_order():
+    template = {'z': 1, 'a': {'$eval': 'x'}, 'm': {'c': 3, 'b': 2}}
+    first = render(template, {'x': 2})
+    # dicts only keep insertion order from Python 3.7
+    if sys.version_info >= (3, 7):
+        eq_(list(first), ['z', 'a', 'm'])
+        eq_(list(first['m']), ['c', 'b'])
+    eq_(json.dumps(first), json.dumps(render(template, {'x': 2})))
+
+def test_render_each_map():
+    template = {'$map': {'$eval': 'items'}, 'each(x,i)': {'$if': 'i % 2 == 0', 'then': {'$eval': 'x * 2'}}}
+    count, first, last = 0, None, None
+    for value in render_each(template, {'items': list(range(10000))}):
+        if count == 0:
+            first = value
+        last = value
+        count += 1
+    eq_(count, 5000)
+    eq_(first, 0)
+    eq_(last, 19996)
+
+def test_render_each_array():
+    template = [{'$eval': 'a'}, {'$if': 'false', 'then': 1}, 'x${a}']
+    eq_(list(render_each(template, {'a': 1})), [1, 'x1'])
+
+def test_render_each_requires_array():
+    with assert_raises(TemplateError) as cm:
+        list(render_each({'a': 1}, {}))
+    eq_(str(cm.exception), 'TemplateError: render_each requires a template that renders to an array')
+    with assert_raises(TemplateError):
+        list(render_each({'$map': {'a': 1}, 'each(x)': {'b': 2}}, {}))
+
+def test_render_each_max_elements():
+    template = {'$map': {'$eval': 'items'}, 'each(x)': {'$eval': 'x'}}
+    values = []
+    with assert_raises(TemplateError) as cm:
+        for value in render_each(template, {'items': list(range(100))}, max_elements=10):
+            values.append(value)
+    eq_(str(cm.exception), 'TemplateError: output exceeds the maximum of 10 elements')
+    eq_(len(values), 10)
+
+def test_context_not_modified():
+    context = {'items': [3, 1, 2], 'objs': [{'a': [1]}, {'a': [2]}], 'o': {'x': 1}}
+    template = {
+        '$let': {'y': {'$eval': 'o.x'}},
+        'in': {
+            'm': {'$map': {'$eval': 'items'}, 'each(x,i)': {'$eval': 'x + y + i'}},
+            's': {'$sort': {'$eval': 'items'}},
+            'r': {'$reverse': {'$eval': 'items'}},
+            'd': {'$mergeDeep': {'$eval': 'objs'}},
+            'f': {'$map': {'$eval': 'o'}, 'each(v,k)': {'${k}': {'$eval': 'v'}}},
+        },
+    }
+    before = copy.deepcopy(context)
+    render(template, context)
+    eq_(context, before)
+
+def test_scopes_of_large_context():
+    context = dict(('v{}'.format(i), i) for i in range(10000))
+    context['items'] = list(range(10000))
+    template = {'$map': {'$eval': 'items'}, 'each(x)': {'$let': {'y': {'$eval': 'x + v1'}}, 'in': {'$eval': 'y + v9999'}}}
+    result = render(template, context)
+    eq_(len(result), 10000)
+    eq_(result[0], 10000)
+    eq_(result[9999], 19999)
+
+def count_parses(fn):
+    """Call fn, counting the expressions it parses, returning its result and the count"""
+    # the name jsone.render is the function, so find the module itself
+    render_module = sys.modules['jsone.render']
+    parsers = []
+
+    class CountingParser(render_module.Parser):
+        def __init__(self, *args):
+            parsers.append(self)
+            super(CountingParser, self).__init__(*args)
+
+    original, render_module.Parser = render_module.Parser, CountingParser
+    try:
+        return fn(), len(parsers)
+    finally:
+        render_module.Parser = original
+
+# items, x * n, and the two interpolations
+parsed_template = {'$map': {'$eval': 'items'}, 'each(x)': {'a': {'$eval': 'x * n'}, 'b': '${x}-${x * n}'}}
+
+def test_expressions_parsed_once():
+    result, parses = count_parses(lambda: render(parsed_template, {'items': [1, 2, 3], 'n': 2}))
+    eq_(result, [{'a': 2, 'b': '1-2'}, {'a': 4, 'b': '2-4'}, {'a': 6, 'b': '3-6'}])
+    eq_(parses, 4)
+
+def test_compiled_templates_parse_once():
+    template, parses = count_parses(lambda: compile(parsed_template))
+    eq_(parses, 4)
+    results, parses = count_parses(lambda: [template({'items': [1, 2], 'n': n}) for n in (1, 2, 3)])
+    eq_(results, [
+        [{'a': 1, 'b': '1-1'}, {'a': 2, 'b': '2-2'}],
+        [{'a': 2, 'b': '1-2'}, {'a': 4, 'b': '2-4'}],
+        [{'a': 3, 'b': '1-3'}, {'a': 6, 'b': '2-6'}],
+    ])
+    eq_(parses, 0)
+    # options apply to each render
+    with assert_raises(TemplateError):
+        template({'items': [1, 2], 'n': 1}, max_steps=3)
+    eq_(template({'items': [], 'n': 1}, max_steps=3), [])
+
+def test_compile_reports_problems():
+    with assert_raises(TemplateError) as cm:
+        compile({'a': {'$if': 'x', 'then': 1, 'els': 2}})
+    assert 'els' in str(cm.exception), str(cm.exception)
+
+def test_compile_reports_syntax_errors():
+    for template in [{'$eval': '1 +'}, {'$if': 'true', 'then': 1, 'else': '${x +}'}, {'$switch': {'x ==': 1}}]:
+        with assert_raises(JSONTemplateError):
+            compile(template)
+    compile({'a': '$${x +} ${x}'})
+
+def test_long_tokens():
+    name = 'x' * 1000000
+    eq_(render({'$eval': name + ' + 1'}, {name: 1}), 2)
+
+    start = datetime.datetime.now()
+    with assert_raises(JSONTemplateError) as cm:
+        render({'$eval': "'" + name}, {})
+    message = str(cm.exception)
+    assert message.startswith("SyntaxError: Unexpected input for ''" + 'x' * 49 + "...' at"), message
+    assert len(message) < 200
+    assert datetime.datetime.now() - start < datetime.timedelta(seconds=1)
+
+def test_syntax_error_offsets():
+    def offsets(template):
+        with assert_raises(JSONTemplateError) as cm:
+            render(template, {})
+        e = cm.exception
+        return type(e).__name__, e.start, e.end, ''.join(e.location)
+    eq_(offsets({'a': [{'$eval': '1 2'}]}), ('SyntaxError', 2, 3, '.a[0]'))
+    eq_(offsets({'$eval': 'a # b'}), ('SyntaxError', 2, 5, ''))
+    eq_(offsets({'$eval': '1 +'}), ('SyntaxError', 3, 3, ''))
+    eq_(offsets({'a': 'x ${1 + }'}), ('SyntaxError', 4, 5, '.a'))
+
+
+def test_validate_reports_every_problem():
+    template = {
+        'a': {'$if': 'x', 'then': 1, 'els': 2},
+        'b': [{'$map': [1, 2], 'each(1x)': 3}],
+        'c': {'$foo': 1},
+        'd': {'$sort': [1], 'by(x)': 'x', 'by(y)': 'y'},
+    }
+    eq_([str(e) for e in validate(template)], [
+        'TemplateError at template.a: $if has undefined properties: els',
+        'TemplateError at template.b[0]: $map has undefined properties: each(1x)',
+        'TemplateError at template.c: $foo is reserved; use $$foo',
+        'TemplateError at template.d: only one by(..) is allowed',
+    ])
+
+def test_validate_valid_template():
+    template = {
+        '$let': {'x': 1},
+        'in': {'a': {'$map': [1, 2], 'each(y)': {'$switch': {'y == 1': 'one', '$default': 'many'}}}, '$$b': 2,
+               'c': {'$$': {'$frobnicate': 1, '$if': 'x', 'else': 2}}},
+    }
+    eq_(validate(template), [])
+
+def test_builtin_names():
+    names = builtin_names()
+    for name in ('min', 'fromNow', 'uppercase'):
+        assert name in names
+    assert 'now' not in names
+    eq_(len(names), len(builtins.build()))
+    eq_(names, sorted(names))
+
+def test_static_result_type():
+    eq_(static_result_type('1 + 2'), 'number')
+    eq_(static_result_type('"a" + \'b\''), 'string')
+    eq_(static_result_type('x == 1 || !y'), 'boolean')
+    eq_(static_result_type('null'), 'null')
+    eq_(static_result_type('[x, y][1:]'), 'array')
+    eq_(static_result_type('{a: x}'), 'object')
+    eq_(static_result_type('x ? 1 : 2 * y'), 'number')
+    # these depend on the context
+    for expression in ('x', 'x.y', 'len(x)', 'x ? 1 : "a"'):
+        eq_(static_result_type(expression), None)
+    with assert_raises(TemplateError):
+        static_result_type('1 +')
+
+def test_free_variables():
+    eq_(free_variables('a.b + len(c[d:]) > 0 ? {k: e, m: e} : [f?.g, "x"]'), ['a', 'c', 'd', 'e', 'f', 'len'])
+    eq_(free_variables('1 + 2'), [])
+    eq_(type(parse_expression('x + 1')).__name__, 'BinOp')
+    with assert_raises(TemplateError):
+        free_variables('1 +')
+
+def test_tokenize():
+    eq_(tokenize('1 + foo * \'x\''), [
+        ('number', '1', 0, 1),
+        ('+', '+', 2, 3),
+        ('identifier', 'foo', 4, 7),
+        ('*', '*', 8, 9),
+        ('string', "'x'", 10, 13),
+    ])
+    eq_([t.kind for t in tokenize('2**3')], ['number', '**', 'number'])
+    eq_([t.kind for t in tokenize('2* *3')], ['number', '*', '*', 'number'])
+    with assert_raises(TemplateError):
+        tokenize('1 # 2')
+
+def test_command_line():
+    tmp = tempfile.mkdtemp()
+
+    def write(name, value):
+        path = os.path.join(tmp, name)
+        with io.open(path, 'w', encoding='utf-8') as f:
+            f.write('{}'.format(json.dumps(value)))
+        return path
+
+    def run(*args, **kwargs):
+        stdout, stderr = io.StringIO(), io.StringIO()
+        status = main(list(args), io.StringIO(kwargs.get('stdin', '')), stdout, stderr)
+        return status, stdout.getvalue(), stderr.getvalue()
+
+    try:
+        template = write('template.json', {'a': {'$eval': 'x + y'}, 'b': '${z}'})
+        first = write('first.json', {'x': 1, 'y': 2, 'z': 'first'})
+        second = write('second.json', {'y': 10})
+        eq_(run(template, '--context', first), (0, '{"a":3,"b":"first"}\n', ''))
+        # later context files override earlier ones
+        eq_(run(template, '--context', first, '--context', second), (0, '{"a":11,"b":"first"}\n', ''))
+        eq_(run('--pretty', stdin='[1, {"$eval": "2"}]'), (0, '[\n  1,\n  2\n]\n', ''))
+        # integral floats are written as integers, as JavaScript would
+        floats = write('floats.json', {'x': 2.0})
+        eq_(run('--context', floats, stdin='[3.0, {"$eval": "x"}, 0.5]'), (0, '[3,2,0.5]\n', ''))
+
+        status, stdout, stderr = run(template)
+        eq_((status, stdout), (1, ''))
+        eq_(stderr, 'json-e: InterpreterError at template.a: unknown context value x\n')
+        status, _, stderr = run(template, '--context', write('array.json', [1]))
+        eq_(status, 1)
+        assert 'array.json does not hold a JSON object' in stderr, stderr
+        eq_(run(os.path.join(tmp, 'missing.json'))[0], 1)
+        eq_(run(stdin='{')[0], 1)
+
+        # files named .yml or .yaml are YAML
+        yaml_template = os.path.join(tmp, 'template.yml')
+        with io.open(yaml_template, 'w', encoding='utf-8') as f:
+            f.write('a: {$eval: x}\nb: [1, 2]\n')
+        yaml_context = os.path.join(tmp, 'context.yaml')
+        with io.open(yaml_context, 'w', encoding='utf-8') as f:
+            f.write('x: from yaml\n')
+        eq_(run(yaml_template, '--context', yaml_context), (0, '{"a":"from yaml","b":[1,2]}\n', ''))
+        # --yaml reads every file and stdin as YAML, and writes YAML
+        eq_(run('--yaml', '--context', yaml_context, stdin='a: {$eval: x}\nb: [1, 2]\n'),
+            (0, '{a: from yaml, b: [1, 2]}\n', ''))
+        eq_(run('--yaml', '--pretty', yaml_template, '--context', yaml_context),
+            (0, 'a: from yaml\nb:\n- 1\n- 2\n', ''))
+        eq_(run('--yaml', stdin='{$eval: "1 + 2"}'), (0, '3\n', ''))
+        with io.open(yaml_context, 'w', encoding='utf-8') as f:
+            f.write('x: [\n')
+        eq_(run(yaml_template, '--context', yaml_context)[0], 1)
+    finally:
+        shutil.rmtree(tmp)
+
 def test_same_time_within_evaluation_operator():
     template = [{'$fromNow': ''} for _ in range(1000)]
     result = render(template, {})
@@ -22,6 +22,14 @@ simple_tokenizer = Tokenizer(
     ['number', '+', '-', '*', '(', ')'],
 )
 
+power_tokenizer = Tokenizer(
+    r' +',
+    {
+        'number': r'[0-9]+',
+    },
+    ['**', '*', 'number'],
+)
+
 
 def test_tokenizer():
     tests = {
@@ -39,6 +47,17 @@ def test_tokenizer():
             Token('symbol', 'ABCD', 5, 9),
         ]),
         'invalid': (simple_tokenizer, 'xxx', JSONTemplateError),
+        'longest-match': (power_tokenizer, '2**3', [
+            Token('number', '2', 0, 1),
+            Token('**', '**', 1, 3),
+            Token('number', '3', 3, 4),
+        ]),
+        'separated-stars': (power_tokenizer, '2 * *3', [
+            Token('number', '2', 0, 1),
+            Token('*', '*', 2, 3),
+            Token('*', '*', 4, 5),
+            Token('number', '3', 5, 6),
+        ]),
     }
 
     def t(name):